@@ -0,0 +1,2 @@
+ALTER TABLE organizations
+  ADD COLUMN llm_settings jsonb;
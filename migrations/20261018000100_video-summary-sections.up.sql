@@ -0,0 +1,2 @@
+ALTER TABLE videos
+  ADD COLUMN summary_sections jsonb;
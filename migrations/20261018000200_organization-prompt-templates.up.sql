@@ -0,0 +1,2 @@
+ALTER TABLE organizations
+  ADD COLUMN prompt_templates jsonb;
@@ -0,0 +1,4 @@
+ALTER TABLE videos
+  DROP COLUMN collection_id;
+
+DROP TABLE collections;
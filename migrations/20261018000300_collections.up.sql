@@ -0,0 +1,15 @@
+CREATE TABLE collections (
+  id uuid NOT NULL PRIMARY KEY,
+  organization_id uuid NOT NULL REFERENCES organizations (id) ON DELETE CASCADE,
+  updated_at timestamptz NOT NULL DEFAULT now(),
+  created_at timestamptz NOT NULL DEFAULT now(),
+  name text,
+  url text
+);
+
+CREATE INDEX collections_organization_id ON collections (organization_id, created_at DESC);
+
+ALTER TABLE videos
+  ADD COLUMN collection_id uuid REFERENCES collections (id) ON DELETE SET NULL;
+
+CREATE INDEX videos_collection_id ON videos (collection_id);
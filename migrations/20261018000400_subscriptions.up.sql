@@ -0,0 +1,14 @@
+CREATE TABLE subscriptions (
+  id uuid NOT NULL PRIMARY KEY,
+  organization_id uuid NOT NULL REFERENCES organizations (id) ON DELETE CASCADE,
+  updated_at timestamptz NOT NULL DEFAULT now(),
+  created_at timestamptz NOT NULL DEFAULT now(),
+  url text NOT NULL,
+  name text,
+  filters jsonb NOT NULL DEFAULT '{}'::jsonb,
+  last_seen_url text,
+  last_checked_at timestamptz,
+  last_error text
+);
+
+CREATE INDEX subscriptions_organization_id ON subscriptions (organization_id);
@@ -0,0 +1,5 @@
+DROP INDEX videos_url;
+
+ALTER TABLE videos
+  DROP COLUMN extractor,
+  DROP COLUMN extractor_id;
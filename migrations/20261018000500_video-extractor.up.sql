@@ -0,0 +1,7 @@
+ALTER TABLE videos
+  ADD COLUMN extractor text,
+  ADD COLUMN extractor_id text;
+
+CREATE INDEX videos_extractor_id ON videos (organization_id, extractor, extractor_id);
+
+CREATE INDEX videos_url ON videos (organization_id, url);
@@ -0,0 +1,2 @@
+-- The backfilled values are the same as what newly added videos get, so they are left in place.
+SELECT 1;
@@ -0,0 +1,20 @@
+-- Identify the YouTube videos that were added before the extractor columns existed, in the same
+-- way as VideoSource::from_url, so that adding them again finds the existing video.
+WITH youtube AS (
+  SELECT
+    id,
+    COALESCE(
+      substring(url FROM '^https?://(?:www\.)?youtu\.be/([A-Za-z0-9_-]{11})(?:[/?#]|$)'),
+      substring(url FROM '^https?://(?:www\.)?(?:(?:m\.|music\.)?youtube\.com|youtube-nocookie\.com)/watch\?(?:[^#]*&)?v=([A-Za-z0-9_-]{11})(?:[&#]|$)'),
+      substring(url FROM '^https?://(?:www\.)?(?:(?:m\.|music\.)?youtube\.com|youtube-nocookie\.com)/(?:shorts|live|embed|v)/([A-Za-z0-9_-]{11})(?:[/?#]|$)')
+    ) AS video_id
+  FROM videos
+  WHERE extractor IS NULL AND url IS NOT NULL
+)
+UPDATE videos
+SET
+  extractor = 'Youtube',
+  extractor_id = youtube.video_id,
+  url = 'https://www.youtube.com/watch?v=' || youtube.video_id
+FROM youtube
+WHERE videos.id = youtube.id AND youtube.video_id IS NOT NULL;
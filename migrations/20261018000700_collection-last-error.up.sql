@@ -0,0 +1,2 @@
+ALTER TABLE collections
+  ADD COLUMN last_error text;
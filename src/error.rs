@@ -61,6 +61,18 @@ pub enum Error {
     InvalidHostHeader,
     #[error("Type Export Error")]
     TypeExport,
+    /// Organization settings failed validation
+    #[error("Invalid settings: {0}")]
+    InvalidSettings(String),
+    /// An uploaded file or its details were missing or invalid
+    #[error("Invalid upload: {0}")]
+    InvalidUpload(&'static str),
+    /// The video isn't in a state where the action makes sense
+    #[error("Video is not {0}")]
+    WrongProcessingState(&'static str),
+
+    #[error("Stage can not run: {0}")]
+    StageCanNotRun(&'static str),
 }
 
 impl From<Report<Error>> for Error {
@@ -149,6 +161,10 @@ impl HttpError for Error {
             // These aren't ever returned, we just need some value to fill out the match
             Error::Config => "config",
             Error::TypeExport => "cli",
+            Error::InvalidSettings(_) => ErrorKind::InvalidSettings.as_str(),
+            Error::InvalidUpload(_) => ErrorKind::InvalidUpload.as_str(),
+            Error::WrongProcessingState(_) => ErrorKind::WrongProcessingState.as_str(),
+            Error::StageCanNotRun(_) => ErrorKind::StageCanNotRun.as_str(),
         }
     }
 
@@ -191,6 +207,10 @@ impl HttpError for Error {
             Error::Storage => StatusCode::INTERNAL_SERVER_ERROR,
             Error::Config => StatusCode::INTERNAL_SERVER_ERROR,
             Error::TypeExport => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::InvalidSettings(_) => StatusCode::BAD_REQUEST,
+            Error::InvalidUpload(_) => StatusCode::BAD_REQUEST,
+            Error::WrongProcessingState(_) => StatusCode::CONFLICT,
+            Error::StageCanNotRun(_) => StatusCode::BAD_REQUEST,
         }
     }
 
@@ -214,6 +234,10 @@ pub enum ErrorKind {
     Filter,
     AuthSubsystem,
     Login,
+    InvalidSettings,
+    InvalidUpload,
+    WrongProcessingState,
+    StageCanNotRun,
 }
 
 impl ErrorKind {
@@ -224,6 +248,10 @@ impl ErrorKind {
             ErrorKind::Filter => "invalid_filter",
             ErrorKind::AuthSubsystem => "auth",
             ErrorKind::Login => "auth",
+            ErrorKind::InvalidSettings => "invalid_settings",
+            ErrorKind::InvalidUpload => "invalid_upload",
+            ErrorKind::WrongProcessingState => "wrong_processing_state",
+            ErrorKind::StageCanNotRun => "stage_can_not_run",
         }
     }
 }
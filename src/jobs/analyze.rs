@@ -1,27 +1,57 @@
 //! analyze background job
 #![allow(unused_imports, unused_variables, dead_code)]
 
-use std::{io::Write, path::Path};
+use std::{collections::BTreeMap, sync::Arc};
 
 use bytes::Bytes;
 use effectum::{JobBuilder, JobRunner, Queue, RecurringJobSchedule, RunningJob};
 use error_stack::{Report, ResultExt};
-use futures::{
-    future::{try_join, try_join_all},
-    TryStreamExt,
-};
+use futures::{future::try_join_all, StreamExt, TryStreamExt};
 use image::{DynamicImage, ImageBuffer};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use temp_dir::TempDir;
-use tokio_stream::StreamExt;
 
-use super::JobError;
+use super::{pipeline::Stage, JobError};
 use crate::{
-    models::video::{image_filename, VideoId},
+    models::video::{image_filename, VideoId, VideoImages},
     server::ServerState,
 };
 
+/// How to find images that are similar enough to remove
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupAlgorithm {
+    /// Compare each image to the one before it with SSIM
+    Ssim,
+    /// Compare perceptual hashes of the images, which also catches repeats of images from
+    /// anywhere earlier in the video.
+    Hash,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct AnalyzeConfig {
+    /// How to find similar images to remove
+    #[clap(long = "image-dedup", env = "IMAGE_DEDUP", value_enum, default_value_t = DedupAlgorithm::Ssim)]
+    pub dedup: DedupAlgorithm,
+
+    /// With hash deduplication, the number of bits that can differ between two image hashes for
+    /// them to be considered the same image, out of 64.
+    #[clap(
+        long = "image-hash-distance",
+        env = "IMAGE_HASH_DISTANCE",
+        default_value_t = 5
+    )]
+    pub hash_distance: u32,
+}
+
+impl Default for AnalyzeConfig {
+    fn default() -> Self {
+        Self {
+            dedup: DedupAlgorithm::Ssim,
+            hash_distance: 5,
+        }
+    }
+}
+
 /// The payload data for the analyze background job
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AnalyzeJobPayload {
@@ -30,159 +60,282 @@ pub struct AnalyzeJobPayload {
     pub max_index: usize,
 }
 
-const THUMBNAIL_SIZES: &[u32] = &[720, 1280, 1920];
+pub(super) const THUMBNAIL_SIZES: &[u32] = &[720, 1280, 1920];
+
+/// The number of images to download and process at once. Images are processed in order, so this
+/// also limits how many decoded images are held in memory.
+const FRAME_CONCURRENCY: usize = 8;
 
-/// Compare image similarity to see which ones we can remove
+/// An image after it has been downloaded, decoded, and had its thumbnails generated
+enum ProcessedFrame {
+    /// The decoded image, for SSIM comparison against the next image
+    Image(image::RgbImage),
+    /// The perceptual hash of the image
+    Hash(u64),
+}
+
+/// Compare image similarity to see which ones we can remove, and generate thumbnails
 async fn run(job: RunningJob, state: ServerState) -> Result<(), error_stack::Report<JobError>> {
     let payload: AnalyzeJobPayload = job.json_payload().change_context(JobError::Payload)?;
+    let dedup = state.analyze.dedup;
 
-    let tempdir = TempDir::new().change_context(JobError::TempDir)?;
-    let dir = tempdir.path();
+    // Each image is fetched and decoded once, and then used for both the thumbnails and the
+    // deduplication. `buffered` returns the images in order while letting the next few download
+    // and generate their thumbnails in the meantime.
+    let frames = futures::stream::iter(1..=payload.max_index)
+        .map(|index| process_frame(&state, &payload, dedup, index))
+        .buffered(FRAME_CONCURRENCY);
+    futures::pin_mut!(frames);
 
-    //  We could pipeline this better but it's simpler to just download all the images at once and
-    //  then process them after.
-    futures::stream::iter(1..=payload.max_index)
-        .map(|index| {
-            let filename = image_filename(index, None);
-            let storage_path = format!("{}/{}", payload.storage_prefix, filename);
-            Ok((index, filename, storage_path, state.clone()))
-        })
-        .try_for_each_concurrent(16, |(index, filename, storage_path, state)| async move {
-            state
-                .storage
-                .images
-                .stream_to_disk(&storage_path, dir.join(&filename))
-                .await
-                .change_context(JobError::StorageDownload)
-                .attach_printable(filename)?;
-            Ok::<(), Report<JobError>>(())
-        })
-        .await?;
+    let mut ssim = SsimDedup::new(state.ssim_threshold);
+    let mut hashes = Vec::new();
+    while let Some(frame) = frames.try_next().await? {
+        match frame {
+            ProcessedFrame::Image(image) => ssim.add(image).await?,
+            ProcessedFrame::Hash(hash) => hashes.push(hash),
+        }
+    }
 
-    // Read the images and do structural similarity comparison on them
-    let (removed, _) = try_join(
-        ssim_pipeline(&payload, state.ssim_threshold, dir),
-        thumbnail_pipeline(&state, &payload, dir),
-    )
-    .await?;
+    let update = match dedup {
+        DedupAlgorithm::Ssim => {
+            json!({
+                "thumbnail_widths": THUMBNAIL_SIZES,
+                "removed": ssim.removed,
+                "similarity": ssim.similarity,
+                "similarity_threshold": state.ssim_threshold,
+                "hashes": [],
+                "duplicate_of": {},
+            })
+        }
+        DedupAlgorithm::Hash => {
+            let duplicate_of = find_duplicates(&hashes, state.analyze.hash_distance);
+
+            json!({
+                "thumbnail_widths": THUMBNAIL_SIZES,
+                "removed": duplicate_of.keys().collect::<Vec<_>>(),
+                "similarity": [],
+                "similarity_threshold": null,
+                "hashes": hashes,
+                "duplicate_of": duplicate_of,
+            })
+        }
+    };
 
     sqlx::query!(
         "UPDATE videos
         SET images = images || $2
         WHERE id=$1",
         payload.id.as_uuid(),
-        json!({
-            "thumbnail_widths": THUMBNAIL_SIZES,
-            "removed": removed,
-        })
+        update
     )
     .execute(&state.db)
     .await
     .change_context(JobError::Db)?;
 
+    super::pipeline::stage_finished(&state, payload.id, Stage::Analyze, &[]).await?;
+
     Ok(())
 }
 
-async fn ssim_pipeline(
+/// Download an image, upload its thumbnails, and prepare it for deduplication.
+async fn process_frame(
+    state: &ServerState,
     payload: &AnalyzeJobPayload,
-    threshold: f64,
-    dir: &Path,
-) -> Result<Vec<u32>, error_stack::Report<JobError>> {
-    let mut removed = Vec::new();
-
-    let filename = image_filename(payload.max_index, None);
-    let mut last_image = image::open(dir.join(&filename))
-        .change_context(JobError::ExtractingImages)
-        .attach_printable(filename)?
-        .into_rgb8();
-
-    for i in 1..=payload.max_index {
-        let this_filename = image_filename(i, None);
-        let this_image = image::open(dir.join(&this_filename))
+    dedup: DedupAlgorithm,
+    index: usize,
+) -> Result<ProcessedFrame, Report<JobError>> {
+    let filename = image_filename(index, None);
+    let storage_path = format!("{}/{}", payload.storage_prefix, filename);
+    let data = state
+        .storage
+        .images
+        .get(&storage_path)
+        .await
+        .change_context(JobError::StorageDownload)
+        .attach_printable_lazy(|| storage_path.clone())?
+        .bytes()
+        .await
+        .change_context(JobError::StorageDownload)
+        .attach_printable_lazy(|| storage_path.clone())?;
+
+    let (frame, thumbnails) = tokio::task::spawn_blocking(move || {
+        let image = image::load_from_memory(&data)
             .change_context(JobError::ReadImage)
-            .attach_printable(this_filename)?
-            .into_rgb8();
-
-        let ssim = image_compare::rgb_hybrid_compare(&last_image, &this_image)
-            .change_context(JobError::CalculatingSimilarity)?;
-        if ssim.score >= threshold {
-            // If this image is too similar to the previous kept image, then remove it.
-            removed.push(i as u32);
-        } else {
-            // If it is different enough, then we keep it and this becomes the new base image.
-            last_image = this_image;
+            .attach_printable(filename)?;
+        let thumbnails = generate_thumbnails(&image, index)?;
+        let frame = match dedup {
+            DedupAlgorithm::Ssim => ProcessedFrame::Image(image.into_rgb8()),
+            DedupAlgorithm::Hash => ProcessedFrame::Hash(dhash(&image)),
+        };
+        Ok::<_, Report<JobError>>((frame, thumbnails))
+    })
+    .await
+    .unwrap()?;
+
+    let uploads = thumbnails
+        .into_iter()
+        .map(|(filename, data)| async move {
+            let b = Bytes::from(data);
+            let storage_path = format!("{}/{}", payload.storage_prefix, filename);
+            state
+                .storage
+                .images
+                .put(&storage_path, b)
+                .await
+                .change_context(JobError::StorageUpload)
+                .attach_printable_lazy(|| format!("Thumbnail {storage_path}"))
+        })
+        .collect::<Vec<_>>();
+    try_join_all(uploads).await?;
+
+    Ok(frame)
+}
+
+/// Compare an image to the one before it. The scores are saved so that the threshold for
+/// removing similar images can be changed without doing this again.
+/// SSIM deduplication of a video's images, which are added in order. An image is removed when it
+/// is at least `threshold` similar to the last image that was kept. The similarity of each image
+/// to the one right before it is also recorded, so that [VideoImages::removed_images] can
+/// recalculate the removed images for a different threshold later.
+struct SsimDedup {
+    threshold: f64,
+    previous: Option<Arc<image::RgbImage>>,
+    last_kept: Option<Arc<image::RgbImage>>,
+    similarity: Vec<f64>,
+    removed: Vec<u32>,
+}
+
+impl SsimDedup {
+    fn new(threshold: f64) -> Self {
+        Self {
+            threshold,
+            previous: None,
+            last_kept: None,
+            similarity: Vec::new(),
+            removed: Vec::new(),
         }
     }
 
-    Ok(removed)
-}
+    async fn add(&mut self, image: image::RgbImage) -> Result<(), Report<JobError>> {
+        let image = Arc::new(image);
+        let score = compare_images(self.previous.clone(), image.clone()).await?;
+        self.similarity.push(score);
+        let index = self.similarity.len() as u32;
 
-async fn thumbnail_pipeline(
-    state: &ServerState,
-    payload: &AnalyzeJobPayload,
-    dir: &Path,
-) -> Result<(), Report<JobError>> {
-    futures::stream::iter(1..=payload.max_index)
-        .map(|index| Ok((index, dir.join(&image_filename(index, None)))))
-        .try_for_each_concurrent(8, |(index, disk_path)| async move {
-            // Read the image
-            let image = image::open(&disk_path)
-                .change_context(JobError::ReadImage)
-                .attach_printable_lazy(|| disk_path.display().to_string())?;
-            let thumbnails = generate_thumbnails(image, &payload.storage_prefix, index).await?;
-            let uploads = thumbnails
-                .into_iter()
-                .map(|(filename, data)| async move {
-                    let b = Bytes::from(data);
-                    let storage_path = format!("{}/{}", payload.storage_prefix, filename);
-                    state
-                        .storage
-                        .images
-                        .put(&storage_path, b)
-                        .await
-                        .change_context(JobError::StorageUpload)
-                        .attach_printable_lazy(|| format!("Thumbnail {storage_path}"))
-                })
-                .collect::<Vec<_>>();
-            try_join_all(uploads).await?;
-            Ok(())
-        })
-        .await
+        // When the previous image was kept, it is also the one to compare against.
+        let previous_kept = match (&self.previous, &self.last_kept) {
+            (Some(previous), Some(kept)) => Arc::ptr_eq(previous, kept),
+            _ => true,
+        };
+        let kept_score = if previous_kept {
+            score
+        } else {
+            compare_images(self.last_kept.clone(), image.clone()).await?
+        };
+
+        if kept_score >= self.threshold {
+            self.removed.push(index);
+        } else {
+            self.last_kept = Some(image.clone());
+        }
+        self.previous = Some(image);
+
+        Ok(())
+    }
 }
 
-async fn generate_thumbnails(
-    image: DynamicImage,
-    storage_prefix: &str,
-    index: usize,
-) -> Result<Vec<(String, Vec<u8>)>, error_stack::Report<JobError>> {
+/// The similarity of two images from 0 to 1. The first image has nothing to be similar to, so
+/// it gets 0.
+async fn compare_images(
+    a: Option<Arc<image::RgbImage>>,
+    b: Arc<image::RgbImage>,
+) -> Result<f64, Report<JobError>> {
+    let Some(a) = a else {
+        return Ok(0.0);
+    };
+
     tokio::task::spawn_blocking(move || {
-        THUMBNAIL_SIZES
-            .iter()
-            .filter(|&size| image.width() > *size)
-            .map(|&size| {
-                let thumbnail = image.resize(size, size, image::imageops::FilterType::Lanczos3);
-
-                let encoded = webp::Encoder::from_image(&thumbnail)
-                    .map_err(|msg| JobError::WebPEncoder(msg.to_string()))
-                    .change_context(JobError::Thumbnail)
-                    .attach_printable_lazy(|| {
-                        format!(
-                            "input file {}, thumbnail size: {size}",
-                            image_filename(index, None)
-                        )
-                    })?
-                    .encode(70.0);
-
-                let output = Vec::from(&*encoded);
-                let filename = image_filename(index, Some(size as usize));
-                Ok::<_, Report<JobError>>((filename, output))
-            })
-            .collect::<Result<Vec<_>, _>>()
+        let score = image_compare::rgb_hybrid_compare(&a, &b)
+            .change_context(JobError::CalculatingSimilarity)?
+            .score;
+        Ok(score)
     })
     .await
     .unwrap()
 }
 
+/// Calculate the difference hash of an image. Each bit records whether a pixel is brighter than
+/// the one to its right in a 9x8 grayscale version of the image, so images that look the same
+/// have hashes that differ in only a few bits, regardless of their size.
+fn dhash(image: &DynamicImage) -> u64 {
+    let small = image
+        .resize_exact(9, 8, image::imageops::FilterType::Triangle)
+        .into_luma8();
+
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let bright = small.get_pixel(x, y).0[0] > small.get_pixel(x + 1, y).0[0];
+            hash = (hash << 1) | bright as u64;
+        }
+    }
+
+    hash
+}
+
+/// Find images that repeat an earlier image, mapping the index of each repeated image to the
+/// index of the kept image that it duplicates.
+fn find_duplicates(hashes: &[u64], max_distance: u32) -> BTreeMap<u32, u32> {
+    let mut kept: Vec<(u32, u64)> = Vec::new();
+    let mut duplicate_of = BTreeMap::new();
+
+    for (i, &hash) in hashes.iter().enumerate() {
+        let index = i as u32 + 1;
+        // Check the most recent images first, since those are the most likely to match.
+        let original = kept
+            .iter()
+            .rev()
+            .find(|(_, kept_hash)| (hash ^ kept_hash).count_ones() <= max_distance);
+
+        match original {
+            Some((original, _)) => {
+                duplicate_of.insert(index, *original);
+            }
+            None => kept.push((index, hash)),
+        }
+    }
+
+    duplicate_of
+}
+
+fn generate_thumbnails(
+    image: &DynamicImage,
+    index: usize,
+) -> Result<Vec<(String, Vec<u8>)>, error_stack::Report<JobError>> {
+    THUMBNAIL_SIZES
+        .iter()
+        .filter(|&size| image.width() > *size)
+        .map(|&size| {
+            let thumbnail = image.resize(size, size, image::imageops::FilterType::Lanczos3);
+
+            let encoded = webp::Encoder::from_image(&thumbnail)
+                .map_err(|msg| JobError::WebPEncoder(msg.to_string()))
+                .change_context(JobError::Thumbnail)
+                .attach_printable_lazy(|| {
+                    format!(
+                        "input file {}, thumbnail size: {size}",
+                        image_filename(index, None)
+                    )
+                })?
+                .encode(70.0);
+
+            let output = Vec::from(&*encoded);
+            let filename = image_filename(index, Some(size as usize));
+            Ok::<_, Report<JobError>>((filename, output))
+        })
+        .collect::<Result<Vec<_>, _>>()
+}
+
 /// Enqueue the analyze job to run immediately
 pub async fn enqueue(
     state: &ServerState,
@@ -221,10 +374,12 @@ pub async fn register(
     queue: &Queue,
     init_recurring_jobs: bool,
 ) -> Result<JobRunner<ServerState>, effectum::Error> {
-    let runner = JobRunner::builder("analyze", run)
-        .autoheartbeat(false)
-        .format_failures_with_debug(true)
-        .build();
+    let runner = JobRunner::builder("analyze", |job, state| {
+        super::track_failure(Stage::Analyze, job, state, run)
+    })
+    .autoheartbeat(false)
+    .format_failures_with_debug(true)
+    .build();
 
     Ok(runner)
 }
@@ -232,3 +387,57 @@ pub async fn register(
 fn create_job_builder() -> JobBuilder {
     JobBuilder::new("analyze").priority(1).weight(1)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dhash_ignores_size() {
+        let gradient = |width: u32, height: u32| {
+            DynamicImage::ImageLuma8(ImageBuffer::from_fn(width, height, |x, y| {
+                image::Luma([((x * 255 / width) ^ (y * 255 / height)) as u8])
+            }))
+        };
+
+        let small = dhash(&gradient(320, 180));
+        let large = dhash(&gradient(1920, 1080));
+        assert!((small ^ large).count_ones() <= 2);
+    }
+
+    #[test]
+    fn duplicates_of_earlier_images() {
+        let slide = 0xF0F0_F0F0_0F0F_0F0F;
+        let camera = 0x1234_5678_9ABC_DEF0;
+        let other_slide = 0xFFFF_0000_FFFF_0000;
+
+        let hashes = [slide, camera, slide ^ 0b101, other_slide, camera ^ 1, slide];
+        let duplicates = find_duplicates(&hashes, 3);
+
+        assert_eq!(duplicates, BTreeMap::from([(3, 1), (5, 2), (6, 1)]));
+    }
+
+    #[tokio::test]
+    async fn ssim_keeps_slide_built_up_slowly() {
+        // A slide that has one more line of text in each image. Each image is very similar to
+        // the one before it, but by the end it is quite different from the first one.
+        let slide = |lines: u32| {
+            ImageBuffer::from_fn(160, 90, |_, y| {
+                if y / 8 < lines && y % 8 < 4 {
+                    image::Rgb([0, 0, 0])
+                } else {
+                    image::Rgb([255, 255, 255])
+                }
+            })
+        };
+
+        let mut dedup = SsimDedup::new(0.9);
+        for lines in 0..=10 {
+            dedup.add(slide(lines)).await.unwrap();
+        }
+
+        assert!(dedup.similarity[1..].iter().all(|score| *score >= 0.9));
+        assert!(!dedup.removed.is_empty());
+        assert!(dedup.removed.len() < 10, "removed {:?}", dedup.removed);
+    }
+}
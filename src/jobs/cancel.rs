@@ -0,0 +1,105 @@
+//! Cancelling the processing of a video
+//!
+//! Jobs that haven't started yet are cancelled in the queue. Each running stage job registers
+//! itself in [RunningJobs], and stops when it is notified. Stopping a job drops its future, which
+//! kills any child processes it started and removes its temporary directory.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use effectum::JobState;
+use error_stack::{Report, ResultExt};
+use tokio::sync::Notify;
+use tracing::{event, Level};
+use uuid::Uuid;
+
+use crate::{models::video::VideoId, server::ServerState, Error};
+
+/// The video processing jobs running in this process
+#[derive(Default)]
+pub struct RunningJobs {
+    jobs: Mutex<HashMap<Uuid, (VideoId, Arc<Notify>)>>,
+}
+
+impl RunningJobs {
+    /// Register a running job. The job is removed from the list when the returned guard is
+    /// dropped.
+    pub(super) fn start(&self, job_id: Uuid, video_id: VideoId) -> RunningJobGuard<'_> {
+        let notify = Arc::new(Notify::new());
+        self.jobs
+            .lock()
+            .unwrap()
+            .insert(job_id, (video_id, notify.clone()));
+
+        RunningJobGuard {
+            jobs: self,
+            job_id,
+            notify,
+        }
+    }
+
+    /// Tell every running job for the video to stop, and return how many there were.
+    fn cancel(&self, video_id: VideoId) -> usize {
+        let jobs = self.jobs.lock().unwrap();
+        let mut count = 0;
+        for (id, notify) in jobs.values() {
+            if *id == video_id {
+                // This stores a permit if the job isn't waiting yet, so it won't miss the
+                // notification.
+                notify.notify_one();
+                count += 1;
+            }
+        }
+
+        count
+    }
+}
+
+pub(super) struct RunningJobGuard<'a> {
+    jobs: &'a RunningJobs,
+    job_id: Uuid,
+    notify: Arc<Notify>,
+}
+
+impl<'a> RunningJobGuard<'a> {
+    /// Wait until the job is cancelled
+    pub async fn cancelled(&self) {
+        self.notify.notified().await
+    }
+}
+
+impl<'a> Drop for RunningJobGuard<'a> {
+    fn drop(&mut self) {
+        self.jobs.jobs.lock().unwrap().remove(&self.job_id);
+    }
+}
+
+/// The most jobs to look at when finding the pending jobs for a video. A video only has a few
+/// jobs for each time it is processed.
+const MAX_VIDEO_JOBS: usize = 100;
+
+/// Cancel the pending jobs for a video and stop the ones that are running. The video should
+/// already be in the cancelled state, so that jobs which start while this runs stop right away.
+pub async fn cancel_video_jobs(state: &ServerState, id: VideoId) -> Result<(), Report<Error>> {
+    // Video jobs are named after the video they process.
+    let jobs = state
+        .queue
+        .get_jobs_by_name(id.to_string(), MAX_VIDEO_JOBS)
+        .await
+        .change_context(Error::TaskQueue)?;
+
+    for job in jobs.iter().filter(|j| j.state == JobState::Pending) {
+        // The job may have started since it was listed, in which case it can't be cancelled in
+        // the queue but will be stopped below.
+        if let Err(e) = state.queue.cancel_job(job.id).await {
+            event!(Level::WARN, %id, job_id = %job.id, "Failed to cancel job: {e:?}");
+        }
+    }
+
+    let stopped = state.running_jobs.cancel(id);
+    event!(Level::INFO, %id, stopped, "Cancelled processing");
+
+    Ok(())
+}
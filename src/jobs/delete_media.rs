@@ -0,0 +1,313 @@
+//! delete_media background job
+#![allow(unused_imports, unused_variables, dead_code)]
+
+use effectum::{JobBuilder, JobRunner, Queue, RecurringJobSchedule, RunningJob};
+use error_stack::{Report, ResultExt};
+use filigree::storage::{Storage, StorageError};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+
+use super::JobError;
+use crate::{
+    models::video::{image_filename, VideoId, VideoImages, VideoMetadata, THUMBNAIL_FILENAME},
+    server::ServerState,
+};
+
+/// The number of objects to delete at once
+const DELETE_CONCURRENCY: usize = 16;
+
+/// The extensions that a downloaded video can have. The download job stores the file before it
+/// records the filename, so each of these is tried in case it failed in between.
+const VIDEO_EXTENSIONS: &[&str] = &[
+    "mp4", "webm", "mkv", "mov", "flv", "3gp", "m4a", "mp3", "opus", "ogg", "wav", "flac",
+];
+
+/// The payload data for the delete_media background job. The video has already been deleted
+/// from the database, so this describes everything that was stored for it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteMediaJobPayload {
+    pub id: VideoId,
+    pub storage_prefix: String,
+    /// The downloaded or uploaded media file
+    pub video_filename: Option<String>,
+    /// The number of images extracted from the video
+    pub max_index: usize,
+    pub thumbnail_widths: Vec<u32>,
+}
+
+impl DeleteMediaJobPayload {
+    /// Describe the stored media for a video, from the information in its database row.
+    pub fn new(
+        id: VideoId,
+        metadata: Option<&VideoMetadata>,
+        images: Option<&VideoImages>,
+    ) -> Self {
+        let mut thumbnail_widths = super::analyze::THUMBNAIL_SIZES.to_vec();
+        for width in images
+            .map(|i| i.thumbnail_widths.as_slice())
+            .unwrap_or_default()
+        {
+            if !thumbnail_widths.contains(width) {
+                thumbnail_widths.push(*width);
+            }
+        }
+
+        Self {
+            id,
+            storage_prefix: id.to_string(),
+            video_filename: metadata
+                .and_then(|m| m.download.as_ref())
+                .and_then(|d| d.filename.clone()),
+            max_index: images.map(|i| i.max_index).unwrap_or_default(),
+            thumbnail_widths,
+        }
+    }
+
+    /// The objects in the uploads bucket
+    fn uploads(&self) -> Vec<String> {
+        let files = vec![
+            "video.info.json",
+            "audio.mp4",
+            "transcript_raw.json",
+            "subtitles.vtt",
+            "subtitles.srt",
+        ];
+        let mut files = files.into_iter().map(String::from).collect::<Vec<_>>();
+        files.extend(VIDEO_EXTENSIONS.iter().map(|ext| format!("video.{ext}")));
+        if let Some(video_filename) = &self.video_filename {
+            if !files.contains(video_filename) {
+                files.push(video_filename.clone());
+            }
+        }
+
+        files
+            .into_iter()
+            .map(|file| format!("{}/{file}", self.storage_prefix))
+            .collect()
+    }
+
+    /// The objects in the images bucket
+    fn images(&self) -> Vec<String> {
+        let mut files = vec![format!("{}/{THUMBNAIL_FILENAME}", self.storage_prefix)];
+        for index in 1..=self.max_index {
+            files.extend(self.image_files(index));
+        }
+
+        files
+    }
+
+    /// The image at `index` and its thumbnails
+    fn image_files(&self, index: usize) -> Vec<String> {
+        let mut files = vec![image_filename(index, None)];
+        for width in &self.thumbnail_widths {
+            files.push(image_filename(index, Some(*width as usize)));
+        }
+
+        files
+            .into_iter()
+            .map(|file| format!("{}/{file}", self.storage_prefix))
+            .collect()
+    }
+}
+
+/// Delete the stored files for a video that was deleted
+async fn run(job: RunningJob, state: ServerState) -> Result<(), error_stack::Report<JobError>> {
+    let payload: DeleteMediaJobPayload = job.json_payload().change_context(JobError::Payload)?;
+
+    let failed_uploads = delete_objects(&state.storage.uploads, payload.uploads()).await;
+    let failed_images = delete_objects(&state.storage.images, payload.images()).await
+        + delete_unrecorded_images(&state.storage.images, &payload).await;
+
+    let failed = failed_uploads + failed_images;
+    if failed > 0 {
+        // Failing the job retries it, and the objects that were already deleted are skipped.
+        return Err(Report::new(JobError::StorageDelete))
+            .attach_printable(format!("{failed} objects could not be deleted"));
+    }
+
+    event!(Level::INFO, id = %payload.id, "Deleted stored media");
+    Ok(())
+}
+
+/// Delete the images past `max_index`, which exist when the extract job stored images but failed
+/// before recording how many there were. Images are numbered without gaps, so this stops at the
+/// first one that doesn't exist. Returns how many objects could not be deleted.
+async fn delete_unrecorded_images(storage: &Storage, payload: &DeleteMediaJobPayload) -> usize {
+    let mut failed = 0;
+    for index in payload.max_index + 1.. {
+        let path = format!("{}/{}", payload.storage_prefix, image_filename(index, None));
+        match storage.get(&path).await.map_err(StorageError::from) {
+            Ok(_) => {}
+            Err(StorageError::NotFound(_)) => break,
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    bucket = storage.bucket,
+                    path,
+                    "Failed to check for object: {e:?}"
+                );
+                return failed + 1;
+            }
+        }
+
+        failed += delete_objects(storage, payload.image_files(index)).await;
+    }
+
+    failed
+}
+
+/// Delete the objects from storage, and return how many of them could not be deleted. Objects
+/// that don't exist are skipped, since a video may not have reached the stage that creates them.
+async fn delete_objects(storage: &Storage, paths: Vec<String>) -> usize {
+    futures::stream::iter(paths)
+        .map(|path| async move {
+            match storage.delete(&path).await.map_err(StorageError::from) {
+                Ok(()) | Err(StorageError::NotFound(_)) => true,
+                Err(e) => {
+                    event!(
+                        Level::WARN,
+                        bucket = storage.bucket,
+                        path,
+                        "Failed to delete object: {e:?}"
+                    );
+                    false
+                }
+            }
+        })
+        .buffer_unordered(DELETE_CONCURRENCY)
+        .filter(|deleted| futures::future::ready(!deleted))
+        .count()
+        .await
+}
+
+/// Enqueue the delete_media job to run immediately
+pub async fn enqueue(
+    state: &ServerState,
+    name: impl ToString,
+    payload: &DeleteMediaJobPayload,
+) -> Result<uuid::Uuid, effectum::Error> {
+    create_job_builder()
+        .name(name)
+        .json_payload(payload)?
+        .add_to(&state.queue)
+        .await
+}
+
+/// Enqueue the delete_media job to run at a specific time
+pub async fn enqueue_at(
+    state: &ServerState,
+    name: impl ToString,
+    at: chrono::DateTime<chrono::Utc>,
+    payload: &DeleteMediaJobPayload,
+) -> Result<uuid::Uuid, effectum::Error> {
+    // convert to time crate
+    let timestamp = at.timestamp();
+    let t = time::OffsetDateTime::from_unix_timestamp(timestamp)
+        .map_err(|_| effectum::Error::TimestampOutOfRange("at"))?;
+
+    create_job_builder()
+        .name(name)
+        .json_payload(payload)?
+        .run_at(t)
+        .add_to(&state.queue)
+        .await
+}
+
+/// Register this job with the queue and initialize any recurring jobs.
+pub async fn register(
+    queue: &Queue,
+    init_recurring_jobs: bool,
+) -> Result<JobRunner<ServerState>, effectum::Error> {
+    let runner = JobRunner::builder("delete_media", run)
+        .autoheartbeat(false)
+        .format_failures_with_debug(true)
+        .build();
+
+    Ok(runner)
+}
+
+fn create_job_builder() -> JobBuilder {
+    // Storage outages can last a while, so keep trying for longer than the other jobs.
+    JobBuilder::new("delete_media")
+        .priority(0)
+        .weight(1)
+        .max_retries(8)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stored_objects() {
+        let payload = DeleteMediaJobPayload {
+            id: VideoId::new(),
+            storage_prefix: "vid".to_string(),
+            video_filename: Some("video.webm".to_string()),
+            max_index: 2,
+            thumbnail_widths: vec![720],
+        };
+
+        assert_eq!(
+            payload.uploads(),
+            vec![
+                "vid/video.info.json",
+                "vid/audio.mp4",
+                "vid/transcript_raw.json",
+                "vid/subtitles.vtt",
+                "vid/subtitles.srt",
+                "vid/video.mp4",
+                "vid/video.webm",
+                "vid/video.mkv",
+                "vid/video.mov",
+                "vid/video.flv",
+                "vid/video.3gp",
+                "vid/video.m4a",
+                "vid/video.mp3",
+                "vid/video.opus",
+                "vid/video.ogg",
+                "vid/video.wav",
+                "vid/video.flac",
+            ]
+        );
+        assert_eq!(
+            payload.images(),
+            vec![
+                "vid/thumbnail.webp",
+                "vid/image-00001.webp",
+                "vid/image-00001-720w.webp",
+                "vid/image-00002.webp",
+                "vid/image-00002-720w.webp",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn unrecorded_images() {
+        let storage = Storage::new_memory();
+        let payload = DeleteMediaJobPayload {
+            id: VideoId::new(),
+            storage_prefix: "vid".to_string(),
+            video_filename: None,
+            max_index: 1,
+            thumbnail_widths: vec![720],
+        };
+
+        for index in 1..=3 {
+            for path in payload.image_files(index) {
+                storage.put(&path, bytes::Bytes::new()).await.unwrap();
+            }
+        }
+
+        assert_eq!(delete_unrecorded_images(&storage, &payload).await, 0);
+        assert!(storage.get("vid/image-00001.webp").await.is_ok());
+        for path in payload
+            .image_files(2)
+            .into_iter()
+            .chain(payload.image_files(3))
+        {
+            assert!(storage.get(&path).await.is_err(), "{path}");
+        }
+    }
+}
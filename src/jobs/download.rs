@@ -1,7 +1,10 @@
 //! download background job
 #![allow(unused_imports, unused_variables, dead_code)]
 
-use std::path::{Path, PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
 use bytes::Bytes;
 use effectum::{JobBuilder, JobRunner, Queue, RecurringJobSchedule, RunningJob};
@@ -10,33 +13,155 @@ use serde::{Deserialize, Serialize};
 use serde_json::json;
 use temp_dir::TempDir;
 use tokio::io::AsyncWriteExt;
+use tracing::{event, Level};
 
-use super::{check_command_result, JobError};
+use super::{
+    check_command_result,
+    pipeline::Stage,
+    progress::{
+        parse_yt_dlp_progress, run_with_progress, ProgressReporter, YT_DLP_PROGRESS_TEMPLATE,
+    },
+    JobError,
+};
 use crate::{
-    models::video::{VideoChapter, VideoId, VideoProcessingState, THUMBNAIL_FILENAME},
+    models::video::{
+        source::VideoSource, DownloadOptions, SubtitleSource, VideoChapter, VideoContainer,
+        VideoId, VideoProcessingState, VideoTranscript, THUMBNAIL_FILENAME,
+    },
     server::ServerState,
 };
 
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct DownloadConfig {
+    /// The largest video height to download, such as 720. Images are only sampled from the
+    /// video, so a smaller size saves bandwidth and storage without losing much.
+    #[clap(long = "download-max-height", env = "DOWNLOAD_MAX_HEIGHT")]
+    pub max_height: Option<u32>,
+
+    /// The container to prefer for downloaded videos
+    #[clap(long = "download-container", env = "DOWNLOAD_CONTAINER", value_enum)]
+    pub container: Option<VideoContainer>,
+
+    /// Download only the audio of each video, unless a video is added with other settings
+    #[clap(long = "download-audio-only", env = "DOWNLOAD_AUDIO_ONLY")]
+    pub audio_only: bool,
+}
+
+impl DownloadConfig {
+    /// Fill in the settings that weren't set for a video from the defaults.
+    pub fn apply(&self, options: &DownloadOptions) -> DownloadOptions {
+        DownloadOptions {
+            max_height: options.max_height.or(self.max_height),
+            container: options.container.or(self.container),
+            audio_only: Some(options.audio_only.unwrap_or(self.audio_only)),
+        }
+    }
+}
+
+/// The yt-dlp arguments that select the format to download. The resolution and container are
+/// sorting preferences rather than filters, so a video without a matching format is still
+/// downloaded in the closest one.
+fn format_args(options: &DownloadOptions) -> Vec<String> {
+    let audio_only = options.audio_only.unwrap_or(false);
+    let mut args = Vec::new();
+    let mut sort = Vec::new();
+
+    if audio_only {
+        args.extend(["--format".to_string(), "bestaudio/best".to_string()]);
+    } else if let Some(max_height) = options.max_height {
+        sort.push(format!("res:{max_height}"));
+    }
+
+    match options.container {
+        Some(VideoContainer::Mp4) => sort.push("ext:mp4:m4a".to_string()),
+        Some(VideoContainer::Webm) => sort.push("ext:webm:webm".to_string()),
+        // Sites don't serve mkv, so it only applies when merging the video and audio.
+        Some(VideoContainer::Mkv) | None => {}
+    }
+
+    if !sort.is_empty() {
+        args.extend(["--format-sort".to_string(), sort.join(",")]);
+    }
+
+    if let Some(container) = options.container.filter(|_| !audio_only) {
+        args.extend([
+            "--merge-output-format".to_string(),
+            container.extension().to_string(),
+        ]);
+    }
+
+    args
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct SubtitlesConfig {
+    /// Download the subtitles for each video, and use them as the transcript instead of
+    /// transcribing the audio when they exist.
+    #[clap(long = "subtitles", env = "SUBTITLES_ENABLED")]
+    pub enabled: bool,
+
+    /// The subtitle languages to look for, in order of preference
+    #[clap(
+        long = "subtitle-languages",
+        env = "SUBTITLE_LANGUAGES",
+        value_delimiter = ',',
+        default_value = "en,en-US,en-GB"
+    )]
+    pub languages: Vec<String>,
+
+    /// Use automatically generated captions when a video has no subtitles. These are usually
+    /// worse than transcribing the audio, but cost nothing.
+    #[clap(long = "auto-subtitles", env = "SUBTITLES_AUTOMATIC")]
+    pub automatic: bool,
+}
+
+impl Default for SubtitlesConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            languages: vec!["en".to_string(), "en-US".to_string(), "en-GB".to_string()],
+            automatic: false,
+        }
+    }
+}
+
 /// The payload data for the download background job
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DownloadJobPayload {
     pub id: VideoId,
     pub download_url: String,
     pub storage_prefix: String,
+    /// The settings that the video was added with, which override the defaults
+    #[serde(default)]
+    pub options: DownloadOptions,
+    /// Check whether the organization already has the video once yt-dlp has identified it, and
+    /// remove this one if so. URLs from sites that aren't recognized when the video is added can
+    /// only be matched up here.
+    #[serde(default)]
+    pub check_duplicate: bool,
 }
 
 #[derive(Serialize, Deserialize)]
 struct InfoJson {
+    /// The video's ID on its site
+    id: String,
+    extractor_key: String,
     ext: String,
+    /// A description of the chosen format
+    format: Option<String>,
     title: String,
     thumbnail: Option<String>,
     aspect_ratio: f64,
     duration: usize,
     release_date: Option<String>,
     chapters: Option<Vec<VideoChapter>>,
+    description: Option<String>,
     upload_date: String,
     uploader: String,
     webpage_url: String,
+    /// The languages of the subtitles that weren't automatically generated
+    #[serde(default)]
+    subtitles: HashMap<String, serde_json::Value>,
 }
 
 /// Run the download background job
@@ -47,7 +172,7 @@ async fn run(job: RunningJob, state: ServerState) -> Result<(), error_stack::Rep
     sqlx::query!(
         "UPDATE videos SET
         processing_state=$2
-        WHERE id=$1
+        WHERE id=$1 AND processing_state <> 'cancelled'
         ",
         payload.id.as_uuid(),
         VideoProcessingState::Downloading as _,
@@ -67,26 +192,49 @@ async fn run(job: RunningJob, state: ServerState) -> Result<(), error_stack::Rep
     // Place the infojson at a fixed path
     let infojson_path_template = format!("infojson:{}", download_dir.join("video").display());
 
-    let download_process = tokio::process::Command::new("yt-dlp")
-        .args([
-            "--no-playlist",
-            "--write-info-json",
-            "--output",
-            video_path_template.to_string_lossy().as_ref(),
+    let mut command = tokio::process::Command::new("yt-dlp");
+    command.args([
+        "--no-playlist",
+        "--write-info-json",
+        "--newline",
+        "--progress",
+        "--progress-template",
+        YT_DLP_PROGRESS_TEMPLATE,
+        "--output",
+        video_path_template.to_string_lossy().as_ref(),
+        "--output",
+        &infojson_path_template,
+    ]);
+
+    let options = state.download.apply(&payload.options);
+    command.args(format_args(&options));
+
+    let subtitles = &state.subtitles;
+    if subtitles.enabled {
+        let subtitle_path_template = format!("subtitle:{}", download_dir.join("video").display());
+        command.args([
+            "--write-subs",
+            "--sub-langs",
+            &subtitles.languages.join(","),
+            "--sub-format",
+            "vtt/srt/best",
             "--output",
-            &infojson_path_template,
-            &payload.download_url,
-        ])
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn()
-        .change_context(JobError::StartingDownloader)?;
-
-    let result = download_process
-        .wait_with_output()
-        .await
-        .change_context(JobError::StartingDownloader)?;
-    // check success
+            &subtitle_path_template,
+        ]);
+        if subtitles.automatic {
+            command.arg("--write-auto-subs");
+        }
+    }
+
+    let progress = ProgressReporter::new(&state, payload.id, Stage::Download);
+    let result = run_with_progress(
+        command.arg(&payload.download_url),
+        Some(&progress),
+        parse_yt_dlp_progress,
+    )
+    .await
+    .change_context(JobError::StartingDownloader)?;
+    check_command_result(result, JobError::Download)?;
 
     let info_json_path = download_dir.join("video.info.json");
     let info_json_buffer = tokio::fs::read(&info_json_path)
@@ -94,6 +242,22 @@ async fn run(job: RunningJob, state: ServerState) -> Result<(), error_stack::Rep
         .change_context(JobError::ReadingInfoJson)?;
     let info_json: InfoJson =
         serde_json::from_slice(&info_json_buffer).change_context(JobError::ReadingInfoJson)?;
+
+    // yt-dlp knows the video's site and ID even when it couldn't be identified from the URL
+    // before it was added.
+    let source = VideoSource::from_info(
+        &info_json.webpage_url,
+        info_json.extractor_key.clone(),
+        info_json.id.clone(),
+    );
+
+    if payload.check_duplicate {
+        if let Some(existing) = find_duplicate(&state, payload.id, &source).await? {
+            event!(Level::INFO, id = %payload.id, %existing, "Removing duplicate video");
+            remove_duplicate(&state, payload.id, existing).await?;
+            return Ok(());
+        }
+    }
     let video_fs_path = format!("{}/video.{}", download_dir.display(), info_json.ext);
 
     let info_json_bytes = Bytes::from(info_json_buffer);
@@ -108,6 +272,10 @@ async fn run(job: RunningJob, state: ServerState) -> Result<(), error_stack::Rep
 
     let video_filename = format!("video.{}", info_json.ext);
     let video_storage_path = format!("{}/{}", payload.storage_prefix, video_filename);
+    let video_size = tokio::fs::metadata(&video_fs_path)
+        .await
+        .map(|m| m.len())
+        .ok();
 
     state
         .storage
@@ -155,6 +323,13 @@ async fn run(job: RunningJob, state: ServerState) -> Result<(), error_stack::Rep
             .attach_printable_lazy(|| thumbnail_output_path.clone())?;
     }
 
+    let subtitle_transcript = if subtitles.enabled {
+        read_subtitles(&state, &payload, download_dir, &info_json).await?
+    } else {
+        None
+    };
+    let (transcript, subtitle_source) = subtitle_transcript.unzip();
+
     let elapsed = start.elapsed();
 
     sqlx::query!(
@@ -164,7 +339,11 @@ async fn run(job: RunningJob, state: ServerState) -> Result<(), error_stack::Rep
         duration=$4,
         author=$5,
         processed_path=$6,
-        metadata=metadata || $7
+        metadata=metadata || $7,
+        transcript=COALESCE($8, transcript),
+        url=$9,
+        extractor=$10,
+        extractor_id=$11
         WHERE id=$1
         ",
         payload.id.as_uuid(),
@@ -177,27 +356,149 @@ async fn run(job: RunningJob, state: ServerState) -> Result<(), error_stack::Rep
             "download": {
                 "duration": elapsed.as_secs(),
                 "filename": video_filename,
+                "format": info_json.format,
+                "size": video_size,
             },
-            "chapters": info_json.chapters
+            "chapters": info_json.chapters,
+            "description": info_json.description,
+            "subtitles": subtitle_source,
         }),
+        transcript.map(sqlx::types::Json) as _,
+        source.url,
+        source.extractor,
+        source.extractor_id,
     )
     .execute(&state.db)
     .await
     .change_context(JobError::Db)?;
 
-    super::extract::enqueue(
-        &state,
-        payload.id,
-        &super::extract::ExtractJobPayload {
-            id: payload.id,
-            storage_prefix: payload.storage_prefix,
-            video_filename,
-        },
+    super::pipeline::stage_finished(&state, payload.id, Stage::Download, &[]).await?;
+
+    Ok(())
+}
+
+/// Find a video in the same organization with the same source that was added before this one.
+async fn find_duplicate(
+    state: &ServerState,
+    id: VideoId,
+    source: &VideoSource,
+) -> Result<Option<VideoId>, Report<JobError>> {
+    sqlx::query_scalar!(
+        r#"SELECT other.id AS "id: VideoId"
+        FROM videos this
+        JOIN videos other ON other.organization_id = this.organization_id
+            AND (other.created_at, other.id) < (this.created_at, this.id)
+        WHERE this.id = $1
+            AND (other.url = $2 OR (other.extractor = $3 AND other.extractor_id = $4))
+        ORDER BY other.created_at
+        LIMIT 1"#,
+        id.as_uuid(),
+        &source.url,
+        source.extractor.as_deref(),
+        source.extractor_id.as_deref(),
     )
+    .fetch_optional(&state.db)
     .await
-    .change_context(JobError::Queue)?;
+    .change_context(JobError::Db)
+}
 
-    Ok(())
+/// Delete a video that the organization already had, moving its collection over to the existing
+/// video if that one isn't in a collection. Nothing has been stored for it yet, so there is no
+/// media to remove.
+async fn remove_duplicate(
+    state: &ServerState,
+    id: VideoId,
+    existing: VideoId,
+) -> Result<(), Report<JobError>> {
+    let mut tx = state.db.begin().await.change_context(JobError::Db)?;
+    sqlx::query!(
+        "UPDATE videos
+        SET collection_id = COALESCE(videos.collection_id, duplicate.collection_id)
+        FROM videos duplicate
+        WHERE videos.id = $2 AND duplicate.id = $1",
+        id.as_uuid(),
+        existing.as_uuid(),
+    )
+    .execute(&mut *tx)
+    .await
+    .change_context(JobError::Db)?;
+
+    sqlx::query!("DELETE FROM videos WHERE id = $1", id.as_uuid())
+        .execute(&mut *tx)
+        .await
+        .change_context(JobError::Db)?;
+
+    tx.commit().await.change_context(JobError::Db)
+}
+
+/// Find the downloaded subtitles in the most preferred language and convert them to a
+/// transcript. Returns `None` when there are no usable subtitles, so that the audio is
+/// transcribed instead.
+async fn read_subtitles(
+    state: &ServerState,
+    payload: &DownloadJobPayload,
+    download_dir: &Path,
+    info_json: &InfoJson,
+) -> Result<Option<(VideoTranscript, SubtitleSource)>, Report<JobError>> {
+    let mut files = tokio::fs::read_dir(download_dir)
+        .await
+        .change_context(JobError::ReadingSubtitles)?;
+    let mut found = Vec::new();
+    while let Some(entry) = files
+        .next_entry()
+        .await
+        .change_context(JobError::ReadingSubtitles)?
+    {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if let Some((language, ext)) = subtitle_file_language(&name) {
+            found.push((language.to_string(), ext.to_string(), entry.path()));
+        }
+    }
+
+    let preferred = state.subtitles.languages.iter().find_map(|language| {
+        found
+            .iter()
+            .find(|(found_language, _, _)| found_language == language)
+    });
+    let Some((language, ext, path)) = preferred.or(found.first()) else {
+        return Ok(None);
+    };
+
+    let text = tokio::fs::read_to_string(path)
+        .await
+        .change_context(JobError::ReadingSubtitles)?;
+
+    // Keep the original file around so that it can be converted again if the transcript format
+    // changes.
+    let storage_path = format!("{}/subtitles.{ext}", payload.storage_prefix);
+    state
+        .storage
+        .uploads
+        .put(&storage_path, Bytes::from(text.clone()))
+        .await
+        .change_context(JobError::StorageUpload)
+        .attach_printable_lazy(|| storage_path.clone())?;
+
+    let transcript = match VideoTranscript::from_subtitles(&text, Some(language)) {
+        Ok(transcript) => transcript,
+        Err(e) => {
+            event!(Level::WARN, id = %payload.id, "Could not read subtitles: {e}");
+            return Ok(None);
+        }
+    };
+
+    let source = SubtitleSource {
+        language: language.clone(),
+        automatic: !info_json.subtitles.contains_key(language),
+    };
+    Ok(Some((transcript, source)))
+}
+
+/// Get the language and extension from a subtitle filename like `video.en-US.vtt`.
+fn subtitle_file_language(filename: &str) -> Option<(&str, &str)> {
+    let rest = filename.strip_prefix("video.")?;
+    let (language, ext) = rest.rsplit_once('.')?;
+    (ext == "vtt" || ext == "srt").then_some((language, ext))
 }
 
 /// Enqueue the download job to run immediately
@@ -238,10 +539,12 @@ pub async fn register(
     queue: &Queue,
     init_recurring_jobs: bool,
 ) -> Result<JobRunner<ServerState>, effectum::Error> {
-    let runner = JobRunner::builder("download", run)
-        .autoheartbeat(false)
-        .format_failures_with_debug(true)
-        .build();
+    let runner = JobRunner::builder("download", |job, state| {
+        super::track_failure(Stage::Download, job, state, run)
+    })
+    .autoheartbeat(false)
+    .format_failures_with_debug(true)
+    .build();
 
     Ok(runner)
 }
@@ -249,3 +552,61 @@ pub async fn register(
 fn create_job_builder() -> JobBuilder {
     JobBuilder::new("download").priority(1).weight(1)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn download_format_args() {
+        let config = DownloadConfig {
+            max_height: Some(720),
+            container: Some(VideoContainer::Mp4),
+            audio_only: false,
+        };
+
+        assert_eq!(
+            format_args(&config.apply(&DownloadOptions::default())),
+            vec![
+                "--format-sort",
+                "res:720,ext:mp4:m4a",
+                "--merge-output-format",
+                "mp4"
+            ]
+        );
+
+        let options = DownloadOptions {
+            max_height: Some(480),
+            container: Some(VideoContainer::Mkv),
+            audio_only: None,
+        };
+        assert_eq!(
+            format_args(&config.apply(&options)),
+            vec!["--format-sort", "res:480", "--merge-output-format", "mkv"]
+        );
+
+        let options = DownloadOptions {
+            audio_only: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_args(&config.apply(&options)),
+            vec!["--format", "bestaudio/best", "--format-sort", "ext:mp4:m4a"]
+        );
+
+        assert!(
+            format_args(&DownloadConfig::default().apply(&DownloadOptions::default())).is_empty()
+        );
+    }
+
+    #[test]
+    fn subtitle_filenames() {
+        assert_eq!(subtitle_file_language("video.en.vtt"), Some(("en", "vtt")));
+        assert_eq!(
+            subtitle_file_language("video.en-US.srt"),
+            Some(("en-US", "srt"))
+        );
+        assert_eq!(subtitle_file_language("video.mp4"), None);
+        assert_eq!(subtitle_file_language("video.info.json"), None);
+    }
+}
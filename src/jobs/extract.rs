@@ -10,13 +10,63 @@ use serde::{Deserialize, Serialize};
 use serde_json::json;
 use temp_dir::TempDir;
 use tokio::fs::DirBuilder;
+use tracing::{event, Level};
 
-use super::{check_command_result, JobError};
+use super::{
+    check_command_result,
+    pipeline::Stage,
+    progress::{run_with_progress, FfmpegProgress, ProgressReporter, FFMPEG_PROGRESS_ARGS},
+    JobError,
+};
 use crate::{
     models::video::{VideoId, VideoImages, VideoProcessingState, VIDEO_IMAGE_TEMPLATE},
     server::ServerState,
 };
 
+/// How to choose which frames of a video to save as images
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageExtractionMode {
+    /// Save a frame at a fixed interval
+    Interval,
+    /// Save a frame whenever the scene changes
+    Scene,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct ImageExtractionConfig {
+    /// How to choose which frames to extract from a video
+    #[clap(long = "image-extraction-mode", env = "IMAGE_EXTRACTION_MODE", value_enum, default_value_t = ImageExtractionMode::Interval)]
+    pub mode: ImageExtractionMode,
+
+    /// The number of seconds between images in interval mode
+    #[clap(long = "image-interval", env = "IMAGE_INTERVAL", default_value_t = 10)]
+    pub interval: usize,
+
+    /// How different a frame must be from the previous one to count as a scene change, from 0 to 1
+    #[clap(
+        long = "scene-threshold",
+        env = "SCENE_THRESHOLD",
+        default_value_t = 0.3
+    )]
+    pub scene_threshold: f64,
+
+    /// In scene mode, the maximum number of seconds to go without saving an image, so that long
+    /// scenes still get periodic images.
+    #[clap(long = "scene-max-gap", env = "SCENE_MAX_GAP", default_value_t = 60)]
+    pub scene_max_gap: usize,
+}
+
+impl Default for ImageExtractionConfig {
+    fn default() -> Self {
+        Self {
+            mode: ImageExtractionMode::Interval,
+            interval: 10,
+            scene_threshold: 0.3,
+            scene_max_gap: 60,
+        }
+    }
+}
+
 /// The payload data for the extract background job
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ExtractJobPayload {
@@ -32,7 +82,7 @@ async fn run(job: RunningJob, state: ServerState) -> Result<(), error_stack::Rep
     sqlx::query!(
         "UPDATE videos
         SET processing_state=$2
-        WHERE id=$1",
+        WHERE id=$1 AND processing_state <> 'cancelled'",
         payload.id.as_uuid(),
         VideoProcessingState::Processing as _
     )
@@ -53,10 +103,37 @@ async fn run(job: RunningJob, state: ServerState) -> Result<(), error_stack::Rep
         .change_context(JobError::StorageDownload)?;
 
     let output_location = output_location.to_string_lossy();
-    let ((audio_duration, audio_path), (image_duration, images)) = futures::try_join!(
-        extract_audio(&state, payload.id, dir, &output_location),
-        extract_images(&state, payload.id, dir, &output_location),
-    )?;
+    let probe = probe_media(&output_location).await?;
+    let audio_only = !probe.has_video_stream;
+    let duration = probe.duration.unwrap_or_default();
+
+    // Image extraction takes much longer than the audio, so its progress stands for the whole
+    // stage when both run.
+    let progress = ProgressReporter::new(&state, payload.id, Stage::Extract);
+    let ((audio_duration, audio_path), (image_duration, images)) = if audio_only {
+        let audio = extract_audio(
+            &state,
+            payload.id,
+            dir,
+            &output_location,
+            duration,
+            Some(&progress),
+        )
+        .await?;
+        (audio, (std::time::Duration::ZERO, VideoImages::default()))
+    } else {
+        futures::try_join!(
+            extract_audio(&state, payload.id, dir, &output_location, duration, None),
+            extract_images(
+                &state,
+                payload.id,
+                dir,
+                &output_location,
+                duration,
+                Some(&progress)
+            ),
+        )?
+    };
 
     sqlx::query!(
         "UPDATE videos
@@ -66,6 +143,7 @@ async fn run(job: RunningJob, state: ServerState) -> Result<(), error_stack::Rep
         payload.id.as_uuid(),
         sqlx::types::Json(&images) as _,
         json!({
+            "audio_only": audio_only,
             "audio_extraction": {
                 "duration": audio_duration.as_secs(),
                 "filename": "audio.mp4",
@@ -79,38 +157,113 @@ async fn run(job: RunningJob, state: ServerState) -> Result<(), error_stack::Rep
     .await
     .change_context(JobError::Db)?;
 
-    super::transcribe::enqueue(
-        &state,
-        payload.id,
-        &super::transcribe::TranscribeJobPayload {
-            id: payload.id,
-            storage_prefix: payload.storage_prefix.clone(),
-            audio_path,
-        },
+    // The download stage stores the subtitles as the transcript when they can be used, so
+    // there's no need to transcribe the audio.
+    let has_subtitles = sqlx::query_scalar!(
+        r#"SELECT COALESCE(transcript->>'source_format' = 'subtitles', false) AS "has_subtitles!"
+        FROM videos WHERE id = $1"#,
+        payload.id.as_uuid()
     )
+    .fetch_one(&state.db)
     .await
-    .change_context(JobError::Queue)?;
-
-    super::analyze::enqueue(
-        &state,
-        payload.id,
-        &super::analyze::AnalyzeJobPayload {
-            id: payload.id,
-            storage_prefix: payload.storage_prefix,
-            max_index: images.max_index,
-        },
-    )
-    .await
-    .change_context(JobError::Queue)?;
+    .change_context(JobError::Db)?;
+
+    let mut skipped = Vec::new();
+    if audio_only {
+        skipped.extend([Stage::Analyze, Stage::Ocr]);
+    }
+    if has_subtitles {
+        skipped.push(Stage::Transcribe);
+    }
+
+    super::pipeline::stage_finished(&state, payload.id, Stage::Extract, &skipped).await?;
 
     Ok(())
 }
 
+/// What ffprobe found in a media file
+#[derive(Debug, PartialEq)]
+struct MediaProbe {
+    /// If the media has a video stream to take images from. Cover art embedded in audio files
+    /// shows up as a video stream too, so that doesn't count.
+    has_video_stream: bool,
+    /// The duration of the media in seconds
+    duration: Option<f64>,
+}
+
+async fn probe_media(video_path: &str) -> Result<MediaProbe, Report<JobError>> {
+    let result = tokio::process::Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-show_entries",
+            "stream=codec_type:stream_disposition=attached_pic:format=duration",
+            "-of",
+            "json",
+            video_path,
+        ])
+        .kill_on_drop(true)
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .change_context(JobError::StartingFfmpeg)?
+        .wait_with_output()
+        .await
+        .change_context(JobError::Probe)?;
+    let result = check_command_result(result, JobError::Probe)?;
+
+    parse_media_probe(&result.stdout)
+}
+
+fn parse_media_probe(ffprobe_output: &[u8]) -> Result<MediaProbe, Report<JobError>> {
+    #[derive(Deserialize)]
+    struct ProbeOutput {
+        #[serde(default)]
+        streams: Vec<ProbeStream>,
+        #[serde(default)]
+        format: ProbeFormat,
+    }
+
+    #[derive(Deserialize, Default)]
+    struct ProbeFormat {
+        /// ffprobe prints the duration as a string
+        duration: Option<String>,
+    }
+
+    #[derive(Deserialize)]
+    struct ProbeStream {
+        codec_type: Option<String>,
+        #[serde(default)]
+        disposition: ProbeDisposition,
+    }
+
+    #[derive(Deserialize, Default)]
+    struct ProbeDisposition {
+        #[serde(default)]
+        attached_pic: u8,
+    }
+
+    let output: ProbeOutput =
+        serde_json::from_slice(ffprobe_output).change_context(JobError::Probe)?;
+    let has_video_stream = output
+        .streams
+        .iter()
+        .any(|s| s.codec_type.as_deref() == Some("video") && s.disposition.attached_pic == 0);
+    let duration = output.format.duration.and_then(|d| d.parse::<f64>().ok());
+
+    Ok(MediaProbe {
+        has_video_stream,
+        duration,
+    })
+}
+
 async fn extract_images(
     server: &ServerState,
     id: VideoId,
     dir: &Path,
     video_path: &str,
+    duration: f64,
+    progress: Option<&ProgressReporter<'_>>,
 ) -> Result<(std::time::Duration, VideoImages), Report<JobError>> {
     let start = tokio::time::Instant::now();
     let image_dir = dir.join("images");
@@ -120,27 +273,46 @@ async fn extract_images(
         .change_context(JobError::TempDir)?;
 
     let image_template = image_dir.join(VIDEO_IMAGE_TEMPLATE);
-    let interval = 10;
-    let fps = format!("fps=1/{interval}");
+    let config = &server.image_extraction;
+    // showinfo logs the timestamp of each frame that makes it through the filter, so we know
+    // where each image belongs in the video.
+    let (interval, filter) = match config.mode {
+        ImageExtractionMode::Interval => {
+            let interval = config.interval.max(1);
+            (interval, format!("fps=1/{interval},showinfo"))
+        }
+        ImageExtractionMode::Scene => (
+            0,
+            format!(
+                "select='isnan(prev_selected_t)+gt(scene,{})+gte(t-prev_selected_t,{})',showinfo",
+                config.scene_threshold, config.scene_max_gap
+            ),
+        ),
+    };
 
-    let result = tokio::process::Command::new("ffmpeg")
-        .args([
-            "-y",
-            "-i",
-            video_path,
-            "-vf",
-            &fps,
-            "-c:v",
-            "libwebp",
-            image_template.to_string_lossy().as_ref(),
-        ])
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn()
-        .change_context(JobError::StartingFfmpeg)?
-        .wait_with_output()
-        .await
-        .change_context(JobError::ExtractingImages)?;
+    let mut ffmpeg_progress = FfmpegProgress::new(duration);
+    let result = run_with_progress(
+        tokio::process::Command::new("ffmpeg")
+            .args(FFMPEG_PROGRESS_ARGS)
+            .args([
+                "-y",
+                "-i",
+                video_path,
+                "-vf",
+                &filter,
+                // Write only the selected frames instead of duplicating them to fill the gaps.
+                "-fps_mode",
+                "vfr",
+                "-c:v",
+                "libwebp",
+                image_template.to_string_lossy().as_ref(),
+            ]),
+        progress,
+        |line| ffmpeg_progress.parse_line(line),
+    )
+    .await
+    .change_context(JobError::ExtractingImages)?;
+    let timestamps = parse_showinfo_timestamps(&String::from_utf8_lossy(&result.stderr));
     check_command_result(result, JobError::ExtractingImages)?;
 
     let file_list = tokio::fs::read_dir(image_dir)
@@ -152,6 +324,26 @@ async fn extract_images(
         .change_context(JobError::ExtractingImages)?;
 
     let num_files = file_list.len();
+    let timestamps = if timestamps.len() == num_files {
+        timestamps
+    } else if interval > 0 {
+        // Images taken at an interval can still be placed without the timestamps.
+        event!(
+            Level::WARN,
+            %id,
+            num_files,
+            num_timestamps = timestamps.len(),
+            "Image timestamps did not match the extracted images"
+        );
+        Vec::new()
+    } else {
+        // Scene detection images are spaced unevenly, so there's no way to tell where they
+        // belong without the timestamps.
+        return Err(Report::new(JobError::ExtractingImages)).attach_printable(format!(
+            "Found timestamps for {} images, but extracted {num_files}",
+            timestamps.len()
+        ));
+    };
 
     futures::stream::iter(file_list)
         .map(Ok)
@@ -173,39 +365,60 @@ async fn extract_images(
             // ffmpeg numbers from 1
             max_index: num_files,
             interval,
+            timestamps,
             thumbnail_widths: Vec::new(),
             removed: Vec::new(),
+            similarity: Vec::new(),
+            similarity_threshold: None,
+            hashes: Vec::new(),
+            duplicate_of: Default::default(),
+            text: None,
         },
     ))
 }
 
+/// Read the timestamp of each frame from the output of ffmpeg's `showinfo` filter.
+fn parse_showinfo_timestamps(output: &str) -> Vec<f64> {
+    output
+        .lines()
+        .filter(|line| line.contains("Parsed_showinfo"))
+        .filter_map(|line| {
+            let (_, rest) = line.split_once(" pts_time:")?;
+            rest.split_whitespace().next()?.parse::<f64>().ok()
+        })
+        .collect()
+}
+
 async fn extract_audio(
     server: &ServerState,
     id: VideoId,
     dir: &Path,
     video_path: &str,
+    duration: f64,
+    progress: Option<&ProgressReporter<'_>>,
 ) -> Result<(std::time::Duration, String), Report<JobError>> {
     let start = tokio::time::Instant::now();
     let audio_path = dir.join("audio.mp4");
-    let result = tokio::process::Command::new("ffmpeg")
-        .args([
-            "-y",
-            "-i",
-            video_path,
-            "-vn",
-            "-c:a",
-            "aac",
-            "-b:a",
-            "128k",
-            audio_path.to_string_lossy().as_ref(),
-        ])
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn()
-        .change_context(JobError::StartingFfmpeg)?
-        .wait_with_output()
-        .await
-        .change_context(JobError::ExtractingAudio)?;
+    let mut ffmpeg_progress = FfmpegProgress::new(duration);
+    let result = run_with_progress(
+        tokio::process::Command::new("ffmpeg")
+            .args(FFMPEG_PROGRESS_ARGS)
+            .args([
+                "-y",
+                "-i",
+                video_path,
+                "-vn",
+                "-c:a",
+                "aac",
+                "-b:a",
+                "128k",
+                audio_path.to_string_lossy().as_ref(),
+            ]),
+        progress,
+        |line| ffmpeg_progress.parse_line(line),
+    )
+    .await
+    .change_context(JobError::ExtractingAudio)?;
 
     check_command_result(result, JobError::ExtractingAudio)?;
 
@@ -259,10 +472,12 @@ pub async fn register(
     queue: &Queue,
     init_recurring_jobs: bool,
 ) -> Result<JobRunner<ServerState>, effectum::Error> {
-    let runner = JobRunner::builder("extract", run)
-        .autoheartbeat(false)
-        .format_failures_with_debug(true)
-        .build();
+    let runner = JobRunner::builder("extract", |job, state| {
+        super::track_failure(Stage::Extract, job, state, run)
+    })
+    .autoheartbeat(false)
+    .format_failures_with_debug(true)
+    .build();
 
     Ok(runner)
 }
@@ -270,3 +485,50 @@ pub async fn register(
 fn create_job_builder() -> JobBuilder {
     JobBuilder::new("extract").priority(1).weight(1)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn showinfo_timestamps() {
+        let output = r#"Stream mapping:
+  Stream #0:0 -> #0:0 (h264 (native) -> webp (libwebp))
+[Parsed_showinfo_1 @ 0x600000c3c000] config in time_base: 1/15360, frame_rate: 30/1
+[Parsed_showinfo_1 @ 0x600000c3c000] n:   0 pts:      0 pts_time:0       duration:    512 duration_time:0.0333333 fmt:yuv420p
+[Parsed_showinfo_1 @ 0x600000c3c000]   color_range:tv color_space:bt709
+[Parsed_showinfo_1 @ 0x600000c3c000] n:   1 pts: 194560 pts_time:12.6667 duration:    512 duration_time:0.0333333 fmt:yuv420p
+frame=    2 fps=0.0 q=-0.0 Lsize=N/A time=00:00:12.70 bitrate=N/A speed=50x"#;
+
+        assert_eq!(parse_showinfo_timestamps(output), vec![0.0, 12.6667]);
+    }
+
+    #[test]
+    fn probe_media_streams() {
+        let video = br#"{"streams": [
+            {"codec_type": "video", "disposition": {"attached_pic": 0}},
+            {"codec_type": "audio", "disposition": {"attached_pic": 0}}
+        ]}"#;
+        assert!(parse_media_probe(video).unwrap().has_video_stream);
+
+        let podcast_with_cover = br#"{"streams": [
+            {"codec_type": "audio", "disposition": {"attached_pic": 0}},
+            {"codec_type": "video", "disposition": {"attached_pic": 1}}
+        ]}"#;
+        assert!(
+            !parse_media_probe(podcast_with_cover)
+                .unwrap()
+                .has_video_stream
+        );
+
+        let audio =
+            br#"{"streams": [{"codec_type": "audio"}], "format": {"duration": "1832.045000"}}"#;
+        assert_eq!(
+            parse_media_probe(audio).unwrap(),
+            MediaProbe {
+                has_video_stream: false,
+                duration: Some(1832.045),
+            }
+        );
+    }
+}
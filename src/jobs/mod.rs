@@ -1,23 +1,41 @@
 //! Background jobs
 //!
 //! The job flow is:
-//! download leads to extract
-//! extract leads to analyze and transcribe
-//! transcribe leads to summarize
+//! playlist adds a video for each entry, and each of those starts at download
+//! poll_subscriptions runs on a schedule and adds each new item in the same way
+//! delete_media removes the stored files of a video after it is deleted
+//! upgrade_transcripts runs at startup and converts transcripts stored in a provider's format
+//!
+//! The stages that process each video, and the order they run in, are defined in [pipeline].
 
 pub mod analyze;
+pub mod cancel;
+pub mod delete_media;
 pub mod download;
 pub mod extract;
+pub mod ocr;
+pub mod pipeline;
+pub mod playlist;
+mod progress;
+pub mod subscriptions;
 pub mod summarize;
 pub mod transcribe;
+pub mod upgrade_transcripts;
 
-use std::{os::unix::process::ExitStatusExt, path::Path};
+use std::{future::Future, os::unix::process::ExitStatusExt, path::Path};
 
-use effectum::{Queue, Worker};
+use effectum::{Queue, RunningJob, Worker};
 use error_stack::{Report, ResultExt};
 use futures::FutureExt;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{event, Level};
 
-use crate::{server::ServerState, Error};
+use crate::{
+    models::video::{StageFailure, VideoId, VideoProcessingState},
+    server::ServerState,
+    Error,
+};
 
 #[derive(thiserror::Error, Debug)]
 enum JobError {
@@ -25,6 +43,14 @@ enum JobError {
     Payload,
     #[error("Failed to start video downloader")]
     StartingDownloader,
+    #[error("Failed to download video")]
+    Download,
+    #[error("Failed to list playlist entries")]
+    ListingPlaylist,
+    #[error("Failed to read subtitles")]
+    ReadingSubtitles,
+    #[error("Failed to read subscription feed")]
+    ReadingFeed,
     #[error("Reading video.info.json")]
     ReadingInfoJson,
     #[error("Failed to download thumbnail")]
@@ -33,6 +59,8 @@ enum JobError {
     StorageUpload,
     #[error("Downloading from storage")]
     StorageDownload,
+    #[error("Deleting from storage")]
+    StorageDelete,
     #[error("Database error")]
     Db,
     #[error("Queue error")]
@@ -41,6 +69,8 @@ enum JobError {
     TempDir,
     #[error("Failed to start ffmpeg")]
     StartingFfmpeg,
+    #[error("Failed to read the streams in the media file")]
+    Probe,
     #[error("Failed to extract audio")]
     ExtractingAudio,
     #[error("Failed to extract images")]
@@ -57,6 +87,10 @@ enum JobError {
     NoTranscript,
     #[error("Failed calling summarize API")]
     Summarizing,
+    #[error("Failed to start tesseract")]
+    StartingTesseract,
+    #[error("Failed to recognize text in image")]
+    Ocr,
     /// Error encoding webp
     #[error("{0}")]
     WebPEncoder(String),
@@ -92,18 +126,34 @@ pub async fn init(
     let analyze_runner = analyze::register(&state.queue, init_recurring_jobs)
         .await
         .change_context(Error::TaskQueue)?;
+    let delete_media_runner = delete_media::register(&state.queue, init_recurring_jobs)
+        .await
+        .change_context(Error::TaskQueue)?;
     let download_runner = download::register(&state.queue, init_recurring_jobs)
         .await
         .change_context(Error::TaskQueue)?;
+    let playlist_runner = playlist::register(&state.queue, init_recurring_jobs)
+        .await
+        .change_context(Error::TaskQueue)?;
+    let subscriptions_runner = subscriptions::register(&state.queue, init_recurring_jobs)
+        .await
+        .change_context(Error::TaskQueue)?;
     let extract_runner = extract::register(&state.queue, init_recurring_jobs)
         .await
         .change_context(Error::TaskQueue)?;
+    let ocr_runner = ocr::register(&state.queue, init_recurring_jobs)
+        .await
+        .change_context(Error::TaskQueue)?;
     let summarize_runner = summarize::register(&state.queue, init_recurring_jobs)
         .await
         .change_context(Error::TaskQueue)?;
     let transcribe_runner = transcribe::register(&state.queue, init_recurring_jobs)
         .await
         .change_context(Error::TaskQueue)?;
+    let upgrade_transcripts_runner =
+        upgrade_transcripts::register(&state.queue, init_recurring_jobs)
+            .await
+            .change_context(Error::TaskQueue)?;
 
     // create the workers
     let worker_compute_min_concurrency =
@@ -118,7 +168,7 @@ pub async fn init(
     let worker_compute = Worker::builder(&state.queue, state.clone())
         .min_concurrency(worker_compute_min_concurrency)
         .max_concurrency(worker_compute_max_concurrency)
-        .jobs([analyze_runner, extract_runner])
+        .jobs([analyze_runner, extract_runner, ocr_runner])
         .build()
         .await
         .change_context(Error::TaskQueue)?;
@@ -137,7 +187,13 @@ pub async fn init(
     let worker_download = Worker::builder(&state.queue, state.clone())
         .min_concurrency(worker_download_min_concurrency)
         .max_concurrency(worker_download_max_concurrency)
-        .jobs([download_runner])
+        .jobs([
+            delete_media_runner,
+            download_runner,
+            playlist_runner,
+            subscriptions_runner,
+            upgrade_transcripts_runner,
+        ])
         .build()
         .await
         .change_context(Error::TaskQueue)?;
@@ -192,10 +248,10 @@ pub async fn init(
 
 /// Check the result of a command, and if it failed return the specified error with additional
 /// information attached.
-fn check_command_result(
+pub(crate) fn check_command_result<E: error_stack::Context>(
     output: std::process::Output,
-    err: JobError,
-) -> Result<std::process::Output, Report<JobError>> {
+    err: E,
+) -> Result<std::process::Output, Report<E>> {
     if output.status.success() {
         Ok(output)
     } else {
@@ -219,3 +275,130 @@ fn check_command_result(
             ))
     }
 }
+
+/// The part of the payload that every video processing job has in common.
+#[derive(Deserialize)]
+struct VideoJobPayload {
+    id: VideoId,
+}
+
+/// Run a job for a processing stage, and if it fails for the last time, mark the video as failed
+/// so that it doesn't sit in a processing state forever. The job stops early if the video's
+/// processing is cancelled.
+async fn track_failure<F, Fut>(
+    stage: pipeline::Stage,
+    job: RunningJob,
+    state: ServerState,
+    run: F,
+) -> Result<(), Report<JobError>>
+where
+    F: FnOnce(RunningJob, ServerState) -> Fut,
+    Fut: Future<Output = Result<(), Report<JobError>>>,
+{
+    let id = job.json_payload::<VideoJobPayload>().ok().map(|p| p.id);
+    let attempts = job.current_try + 1;
+    let final_attempt = attempts > job.max_retries;
+
+    let Some(id) = id else {
+        return run(job, state).await;
+    };
+
+    // Register the job before checking the state, so that a cancellation that happens in between
+    // still reaches it.
+    let running = state.running_jobs.start(job.id, id);
+    if is_cancelled(&state, id).await? {
+        event!(Level::INFO, %id, stage = stage.name(), "Skipping job for cancelled or deleted video");
+        return Ok(());
+    }
+
+    let result = tokio::select! {
+        result = run(job, state.clone()) => result,
+        _ = running.cancelled() => {
+            event!(Level::INFO, %id, stage = stage.name(), "Stopped job for cancelled or deleted video");
+            return Ok(());
+        }
+    };
+
+    if let Err(e) = &result {
+        if final_attempt {
+            let failure = StageFailure {
+                stage: stage.name().to_string(),
+                error: format!("{e:?}"),
+                attempts,
+                failed_at: chrono::Utc::now(),
+            };
+
+            let recorded = if stage.optional() {
+                record_optional_failure(&state, id, stage, &failure).await
+            } else {
+                record_failure(&state, id, &failure).await
+            };
+
+            if let Err(db_err) = recorded {
+                event!(Level::ERROR, %id, stage = stage.name(), "Failed to record stage failure: {db_err:?}");
+            }
+        }
+    }
+
+    result
+}
+
+async fn is_cancelled(state: &ServerState, id: VideoId) -> Result<bool, Report<JobError>> {
+    let processing_state = sqlx::query_scalar!(
+        r#"SELECT processing_state AS "processing_state: VideoProcessingState"
+        FROM videos WHERE id = $1"#,
+        id.as_uuid()
+    )
+    .fetch_optional(&state.db)
+    .await
+    .change_context(JobError::Db)?;
+
+    // A video that was deleted is treated as cancelled too.
+    Ok(processing_state.is_none_or(|s| s == VideoProcessingState::Cancelled))
+}
+
+/// Record the failure of an optional stage, and continue with the stages after it.
+async fn record_optional_failure(
+    state: &ServerState,
+    id: VideoId,
+    stage: pipeline::Stage,
+    failure: &StageFailure,
+) -> Result<(), Report<JobError>> {
+    sqlx::query!(
+        "UPDATE videos SET
+        metadata = jsonb_set(
+            COALESCE(metadata, '{}'::jsonb),
+            '{optional_failures}',
+            COALESCE(metadata->'optional_failures', '[]'::jsonb) || $2
+        )
+        WHERE id = $1",
+        id.as_uuid(),
+        json!([failure]),
+    )
+    .execute(&state.db)
+    .await
+    .change_context(JobError::Db)?;
+
+    pipeline::stage_finished(state, id, stage, &[]).await
+}
+
+async fn record_failure(
+    state: &ServerState,
+    id: VideoId,
+    failure: &StageFailure,
+) -> Result<(), Report<JobError>> {
+    sqlx::query!(
+        "UPDATE videos SET
+        processing_state = $2,
+        metadata = COALESCE(metadata, '{}'::jsonb) || $3
+        WHERE id = $1",
+        id.as_uuid(),
+        VideoProcessingState::Failed as _,
+        json!({ "failure": failure }),
+    )
+    .execute(&state.db)
+    .await
+    .change_context(JobError::Db)?;
+
+    Ok(())
+}
@@ -0,0 +1,226 @@
+//! ocr background job
+#![allow(unused_imports, unused_variables, dead_code)]
+
+use std::{collections::BTreeMap, io::Cursor};
+
+use effectum::{JobBuilder, JobRunner, Queue, RecurringJobSchedule, RunningJob};
+use error_stack::{Report, ResultExt};
+use futures::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::io::AsyncWriteExt;
+
+use super::{check_command_result, pipeline::Stage, JobError};
+use crate::{
+    models::video::{image_filename, VideoId, VideoImages},
+    server::ServerState,
+};
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct OcrConfig {
+    /// Recognize the text in the images from each video, so that slide text can be searched and
+    /// used for summarization.
+    #[clap(long = "ocr", env = "OCR_ENABLED")]
+    pub enabled: bool,
+
+    /// The tesseract executable to run
+    #[clap(long = "ocr-tesseract", env = "OCR_TESSERACT", default_value_t = String::from("tesseract"))]
+    pub tesseract: String,
+
+    /// The languages for tesseract to recognize, such as "eng" or "eng+deu"
+    #[clap(long = "ocr-language", env = "OCR_LANGUAGE", default_value_t = String::from("eng"))]
+    pub language: String,
+}
+
+impl Default for OcrConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            tesseract: String::from("tesseract"),
+            language: String::from("eng"),
+        }
+    }
+}
+
+/// The number of images to run OCR on at once. Tesseract uses multiple threads itself, so this
+/// is kept low.
+const OCR_CONCURRENCY: usize = 4;
+
+/// The payload data for the ocr background job
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OcrJobPayload {
+    pub id: VideoId,
+    pub storage_prefix: String,
+}
+
+/// Recognize the text in each image. Images removed as duplicates are included too, since
+/// changing the similarity threshold can bring them back.
+async fn run(job: RunningJob, state: ServerState) -> Result<(), error_stack::Report<JobError>> {
+    let payload: OcrJobPayload = job.json_payload().change_context(JobError::Payload)?;
+
+    let images = sqlx::query_scalar!(
+        r#"SELECT images AS "images: VideoImages" FROM videos WHERE id = $1"#,
+        payload.id.as_uuid()
+    )
+    .fetch_one(&state.db)
+    .await
+    .change_context(JobError::Db)?
+    .unwrap_or_default();
+
+    let text = futures::stream::iter(1..=images.max_index)
+        .map(|index| recognize_image(&state, &payload, index))
+        .buffered(OCR_CONCURRENCY)
+        .try_filter_map(|(index, text)| async move {
+            Ok((!text.is_empty()).then_some((index as u32, text)))
+        })
+        .try_collect::<BTreeMap<_, _>>()
+        .await?;
+
+    sqlx::query!(
+        "UPDATE videos SET images = images || $2 WHERE id = $1",
+        payload.id.as_uuid(),
+        json!({ "text": text })
+    )
+    .execute(&state.db)
+    .await
+    .change_context(JobError::Db)?;
+
+    super::pipeline::stage_finished(&state, payload.id, Stage::Ocr, &[]).await?;
+
+    Ok(())
+}
+
+async fn recognize_image(
+    state: &ServerState,
+    payload: &OcrJobPayload,
+    index: usize,
+) -> Result<(usize, String), Report<JobError>> {
+    let filename = image_filename(index, None);
+    let storage_path = format!("{}/{}", payload.storage_prefix, filename);
+    let data = state
+        .storage
+        .images
+        .get(&storage_path)
+        .await
+        .change_context(JobError::StorageDownload)
+        .attach_printable_lazy(|| storage_path.clone())?
+        .bytes()
+        .await
+        .change_context(JobError::StorageDownload)
+        .attach_printable_lazy(|| storage_path.clone())?;
+
+    // Not every tesseract build can read WebP, so convert the image to PNG first.
+    let png = tokio::task::spawn_blocking(move || {
+        let image = image::load_from_memory(&data)
+            .change_context(JobError::ReadImage)
+            .attach_printable(filename)?;
+        let mut png = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .change_context(JobError::ReadImage)?;
+        Ok::<_, Report<JobError>>(png)
+    })
+    .await
+    .unwrap()?;
+
+    let mut child = tokio::process::Command::new(&state.ocr.tesseract)
+        .args(["stdin", "stdout", "-l", &state.ocr.language])
+        .kill_on_drop(true)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .change_context(JobError::StartingTesseract)?;
+
+    let mut stdin = child.stdin.take().expect("stdin was piped");
+    stdin
+        .write_all(&png)
+        .await
+        .change_context(JobError::Ocr)
+        .attach_printable_lazy(|| format!("Image {index}"))?;
+    drop(stdin);
+
+    let output = child
+        .wait_with_output()
+        .await
+        .change_context(JobError::Ocr)?;
+    let output = check_command_result(output, JobError::Ocr)
+        .attach_printable_lazy(|| format!("Image {index}"))?;
+
+    Ok((index, clean_text(&String::from_utf8_lossy(&output.stdout))))
+}
+
+/// Remove the blank lines and stray whitespace that tesseract leaves between blocks of text.
+fn clean_text(text: &str) -> String {
+    text.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Enqueue the ocr job to run immediately
+pub async fn enqueue(
+    state: &ServerState,
+    name: impl ToString,
+    payload: &OcrJobPayload,
+) -> Result<uuid::Uuid, effectum::Error> {
+    create_job_builder()
+        .name(name)
+        .json_payload(payload)?
+        .add_to(&state.queue)
+        .await
+}
+
+/// Enqueue the ocr job to run at a specific time
+pub async fn enqueue_at(
+    state: &ServerState,
+    name: impl ToString,
+    at: chrono::DateTime<chrono::Utc>,
+    payload: &OcrJobPayload,
+) -> Result<uuid::Uuid, effectum::Error> {
+    // convert to time crate
+    let timestamp = at.timestamp();
+    let t = time::OffsetDateTime::from_unix_timestamp(timestamp)
+        .map_err(|_| effectum::Error::TimestampOutOfRange("at"))?;
+
+    create_job_builder()
+        .name(name)
+        .json_payload(payload)?
+        .run_at(t)
+        .add_to(&state.queue)
+        .await
+}
+
+/// Register this job with the queue and initialize any recurring jobs.
+pub async fn register(
+    queue: &Queue,
+    init_recurring_jobs: bool,
+) -> Result<JobRunner<ServerState>, effectum::Error> {
+    let runner = JobRunner::builder("ocr", |job, state| {
+        super::track_failure(Stage::Ocr, job, state, run)
+    })
+    .autoheartbeat(false)
+    .format_failures_with_debug(true)
+    .build();
+
+    Ok(runner)
+}
+
+fn create_job_builder() -> JobBuilder {
+    JobBuilder::new("ocr").priority(1).weight(1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn clean_ocr_text() {
+        let text = "  Rust in Production \n\n\n  - Memory safety\n- Fearless concurrency  \n\x0c";
+        assert_eq!(
+            clean_text(text),
+            "Rust in Production\n- Memory safety\n- Fearless concurrency"
+        );
+    }
+}
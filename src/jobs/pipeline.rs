@@ -0,0 +1,508 @@
+//! The stages that process a video, and the order they run in
+//!
+//! Each stage lists the stages that must finish before it can start. When a stage finishes it is
+//! added to `metadata.finished_stages`, and every stage that was waiting on it starts once all of
+//! its dependencies are finished. A stage that doesn't apply to a video is recorded as finished
+//! by the stage that decides to skip it, and a stage that is disabled in the configuration always
+//! counts as finished. An optional stage that fails also counts as finished, so that the video
+//! can still be used without its results. The video is only marked ready when every stage has
+//! finished.
+
+use error_stack::{Report, ResultExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{event, Level};
+
+use super::{
+    analyze::AnalyzeJobPayload, download::DownloadJobPayload, extract::ExtractJobPayload,
+    ocr::OcrJobPayload, summarize::SummarizeJobPayload, transcribe::TranscribeJobPayload, JobError,
+};
+use crate::{
+    models::video::{VideoId, VideoImages, VideoMetadata, VideoProcessingState},
+    server::ServerState,
+    Error,
+};
+
+/// A stage in the video processing pipeline
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, schemars::JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// Download the video from its URL. Uploaded files start out with this stage finished.
+    Download,
+    /// Extract the audio and images from the video
+    Extract,
+    /// Deduplicate the images. Skipped for audio-only media.
+    Analyze,
+    /// Recognize the text in the images. Skipped for audio-only media, and only runs when enabled.
+    Ocr,
+    /// Transcribe the audio. Skipped when the subtitles were used as the transcript.
+    Transcribe,
+    /// Summarize the transcript, along with the slide text when OCR is enabled
+    Summarize,
+}
+
+impl Stage {
+    /// Every stage, with each one listed after the stages it depends on
+    pub const ALL: [Stage; 6] = [
+        Stage::Download,
+        Stage::Extract,
+        Stage::Analyze,
+        Stage::Ocr,
+        Stage::Transcribe,
+        Stage::Summarize,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Download => "download",
+            Stage::Extract => "extract",
+            Stage::Analyze => "analyze",
+            Stage::Ocr => "ocr",
+            Stage::Transcribe => "transcribe",
+            Stage::Summarize => "summarize",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Stage> {
+        Self::ALL.into_iter().find(|s| s.name() == name)
+    }
+
+    /// The stages that must finish before this one can start
+    pub fn dependencies(&self) -> &'static [Stage] {
+        match self {
+            Stage::Download => &[],
+            Stage::Extract => &[Stage::Download],
+            Stage::Analyze => &[Stage::Extract],
+            Stage::Ocr => &[Stage::Analyze],
+            Stage::Transcribe => &[Stage::Extract],
+            Stage::Summarize => &[Stage::Transcribe, Stage::Ocr],
+        }
+    }
+
+    /// If the video is still useful without this stage. When an optional stage fails, the failure
+    /// is recorded and the stages after it run anyway.
+    pub fn optional(&self) -> bool {
+        matches!(self, Stage::Ocr)
+    }
+
+    /// If the stage is turned on in the configuration. Disabled stages count as finished.
+    fn enabled(&self, state: &ServerState) -> bool {
+        match self {
+            Stage::Ocr => state.ocr.enabled,
+            _ => true,
+        }
+    }
+
+    /// The stages that depend on this one, directly or through other stages
+    pub fn downstream(&self) -> Vec<Stage> {
+        let mut found = vec![*self];
+        for stage in Self::ALL {
+            if stage.dependencies().iter().any(|d| found.contains(d)) && !found.contains(&stage) {
+                found.push(stage);
+            }
+        }
+
+        found.remove(0);
+        found
+    }
+}
+
+/// The stages that can start now that the stages in `newly_finished` are done. Only stages that
+/// depend on one of the newly finished stages are returned, so that when two stages finish at
+/// the same time only one of them starts the stages that were waiting on both.
+fn ready_stages(
+    finished: &[Stage],
+    newly_finished: &[Stage],
+    enabled: impl Fn(Stage) -> bool,
+) -> Vec<Stage> {
+    let is_finished = |stage: &Stage| !enabled(*stage) || finished.contains(stage);
+
+    Stage::ALL
+        .into_iter()
+        .filter(|stage| !is_finished(stage))
+        .filter(|stage| {
+            stage
+                .dependencies()
+                .iter()
+                .any(|d| newly_finished.contains(d))
+        })
+        .filter(|stage| stage.dependencies().iter().all(is_finished))
+        .collect()
+}
+
+/// The stages that haven't finished but can run, because their dependencies have finished. This
+/// is where processing picks up again after it was cancelled.
+fn unfinished_stages(finished: &[Stage], enabled: impl Fn(Stage) -> bool) -> Vec<Stage> {
+    let is_finished = |stage: &Stage| !enabled(*stage) || finished.contains(stage);
+
+    Stage::ALL
+        .into_iter()
+        .filter(|stage| !is_finished(stage))
+        .filter(|stage| stage.dependencies().iter().all(is_finished))
+        .collect()
+}
+
+/// The stages that must finish before the video is ready
+fn required_stages(enabled: impl Fn(Stage) -> bool) -> Vec<Stage> {
+    Stage::ALL
+        .into_iter()
+        .filter(|stage| enabled(*stage))
+        .collect()
+}
+
+/// Record that a stage finished, along with any stages that it decided to skip, then start the
+/// stages that were waiting on them, or mark the video ready if there's nothing left to run.
+pub(super) async fn stage_finished(
+    state: &ServerState,
+    id: VideoId,
+    stage: Stage,
+    skipped: &[Stage],
+) -> Result<(), Report<JobError>> {
+    let mut newly_finished = vec![stage];
+    newly_finished.extend_from_slice(skipped);
+
+    // Doing this in one statement means that when stages finish at the same time, the row lock
+    // makes each one see the stages that finished before it. The stage's progress is cleared
+    // too, since it's done. The video becomes ready in the same statement once every required
+    // stage has finished, unless it was cancelled in the meantime.
+    let enabled = |stage: Stage| stage.enabled(state);
+    let video = sqlx::query!(
+        r#"UPDATE videos
+        SET processing_state = CASE
+                WHEN processing_state <> 'cancelled'
+                    AND COALESCE(metadata->'finished_stages', '[]'::jsonb) || $2 @> $4
+                THEN $5
+                ELSE processing_state
+            END,
+            metadata = jsonb_set(
+            COALESCE(metadata, '{}'::jsonb) - CASE
+                WHEN metadata->'stage_progress'->>'stage' = $3 THEN 'stage_progress'
+                ELSE ''
+            END,
+            '{finished_stages}',
+            (SELECT COALESCE(jsonb_agg(DISTINCT s), '[]'::jsonb)
+                FROM jsonb_array_elements(
+                    COALESCE(metadata->'finished_stages', '[]'::jsonb) || $2
+                ) s)
+        )
+        WHERE id = $1
+        RETURNING
+            metadata->'finished_stages' AS "finished!: sqlx::types::Json<Vec<Stage>>",
+            processing_state AS "processing_state: VideoProcessingState""#,
+        id.as_uuid(),
+        json!(newly_finished),
+        stage.name(),
+        json!(required_stages(enabled)),
+        VideoProcessingState::Ready as _,
+    )
+    .fetch_one(&state.db)
+    .await
+    .change_context(JobError::Db)?;
+
+    // Cancelling a video stops its jobs, but one may have been finishing at the same time.
+    if video.processing_state == VideoProcessingState::Cancelled {
+        return Ok(());
+    }
+
+    let finished = video.finished.0;
+
+    for next in ready_stages(&finished, &newly_finished, enabled) {
+        event!(Level::INFO, %id, stage = next.name(), "Starting next stage");
+        enqueue_stage(state, id, next)
+            .await
+            .change_context(JobError::Queue)?;
+    }
+
+    Ok(())
+}
+
+/// Start every stage that can run but hasn't finished, to continue processing a video where it
+/// stopped.
+pub async fn resume(state: &ServerState, id: VideoId) -> Result<Vec<uuid::Uuid>, Report<Error>> {
+    let finished = sqlx::query_scalar!(
+        r#"SELECT metadata->'finished_stages' AS "finished: sqlx::types::Json<Vec<Stage>>"
+        FROM videos WHERE id = $1"#,
+        id.as_uuid()
+    )
+    .fetch_optional(&state.db)
+    .await
+    .change_context(Error::Db)?
+    .ok_or(Error::NotFound("Video"))?
+    .map(|f| f.0)
+    .unwrap_or_default();
+
+    let mut job_ids = Vec::new();
+    for stage in unfinished_stages(&finished, |stage| stage.enabled(state)) {
+        job_ids.push(enqueue_stage(state, id, stage).await?);
+    }
+
+    Ok(job_ids)
+}
+
+/// Enqueue the job for a stage, using the results of the earlier stages stored on the video. This
+/// returns [Error::StageCanNotRun] without enqueueing anything if those results aren't there.
+pub async fn enqueue_stage(
+    state: &ServerState,
+    id: VideoId,
+    stage: Stage,
+) -> Result<uuid::Uuid, Report<Error>> {
+    prepare_stage(state, id, stage)
+        .await?
+        .enqueue(state, id)
+        .await
+}
+
+/// The job for a stage, ready to be enqueued
+#[derive(Debug)]
+pub enum StageJob {
+    Download(DownloadJobPayload),
+    Extract(ExtractJobPayload),
+    Analyze(AnalyzeJobPayload),
+    Ocr(OcrJobPayload),
+    Transcribe(TranscribeJobPayload),
+    Summarize(SummarizeJobPayload),
+}
+
+impl StageJob {
+    pub async fn enqueue(
+        &self,
+        state: &ServerState,
+        id: VideoId,
+    ) -> Result<uuid::Uuid, Report<Error>> {
+        let result = match self {
+            StageJob::Download(payload) => super::download::enqueue(state, id, payload).await,
+            StageJob::Extract(payload) => super::extract::enqueue(state, id, payload).await,
+            StageJob::Analyze(payload) => super::analyze::enqueue(state, id, payload).await,
+            StageJob::Ocr(payload) => super::ocr::enqueue(state, id, payload).await,
+            StageJob::Transcribe(payload) => super::transcribe::enqueue(state, id, payload).await,
+            StageJob::Summarize(payload) => super::summarize::enqueue(state, id, payload).await,
+        };
+
+        result.change_context(Error::TaskQueue)
+    }
+}
+
+/// Create the job for a stage from the results of the earlier stages stored on the video, without
+/// enqueueing it. This returns [Error::StageCanNotRun] if those results aren't there.
+pub async fn prepare_stage(
+    state: &ServerState,
+    id: VideoId,
+    stage: Stage,
+) -> Result<StageJob, Report<Error>> {
+    let video = sqlx::query!(
+        r#"SELECT url,
+            metadata AS "metadata: VideoMetadata",
+            images AS "images: VideoImages",
+            transcript IS NOT NULL AS "has_transcript!"
+        FROM videos WHERE id = $1"#,
+        id.as_uuid()
+    )
+    .fetch_optional(&state.db)
+    .await
+    .change_context(Error::Db)?
+    .ok_or(Error::NotFound("Video"))?;
+
+    let storage_prefix = id.to_string();
+
+    let job = match stage {
+        Stage::Download => {
+            let download_url = video.url.ok_or(Error::StageCanNotRun(
+                "the video has no URL to download from",
+            ))?;
+            StageJob::Download(DownloadJobPayload {
+                id,
+                storage_prefix,
+                download_url,
+                options: video
+                    .metadata
+                    .map(|m| m.download_options)
+                    .unwrap_or_default(),
+                check_duplicate: false,
+            })
+        }
+        Stage::Extract => {
+            let video_filename = video
+                .metadata
+                .and_then(|m| m.download)
+                .and_then(|d| d.filename)
+                .ok_or(Error::StageCanNotRun("the video has not been downloaded"))?;
+            StageJob::Extract(ExtractJobPayload {
+                id,
+                storage_prefix,
+                video_filename,
+            })
+        }
+        Stage::Analyze => {
+            let max_index = video
+                .images
+                .map(|i| i.max_index)
+                .ok_or(Error::StageCanNotRun("the images have not been extracted"))?;
+            StageJob::Analyze(AnalyzeJobPayload {
+                id,
+                storage_prefix,
+                max_index,
+            })
+        }
+        Stage::Ocr => {
+            if video.images.is_none() {
+                return Err(Report::new(Error::StageCanNotRun(
+                    "the images have not been extracted",
+                )));
+            }
+            StageJob::Ocr(OcrJobPayload { id, storage_prefix })
+        }
+        Stage::Transcribe => {
+            if video.metadata.and_then(|m| m.audio_extraction).is_none() {
+                return Err(Report::new(Error::StageCanNotRun(
+                    "the audio has not been extracted",
+                )));
+            }
+            StageJob::Transcribe(TranscribeJobPayload {
+                id,
+                audio_path: format!("{storage_prefix}/audio.mp4"),
+                storage_prefix,
+            })
+        }
+        Stage::Summarize => {
+            if !video.has_transcript {
+                return Err(Report::new(Error::StageCanNotRun(
+                    "the video has not been transcribed",
+                )));
+            }
+            StageJob::Summarize(SummarizeJobPayload { id })
+        }
+    };
+
+    Ok(job)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn all_enabled(_: Stage) -> bool {
+        true
+    }
+
+    fn ocr_disabled(stage: Stage) -> bool {
+        stage != Stage::Ocr
+    }
+
+    #[test]
+    fn extract_starts_analyze_and_transcribe() {
+        let finished = [Stage::Download, Stage::Extract];
+        assert_eq!(
+            ready_stages(&finished, &[Stage::Extract], all_enabled),
+            vec![Stage::Analyze, Stage::Transcribe]
+        );
+    }
+
+    #[test]
+    fn audio_only_skips_image_stages() {
+        let finished = [Stage::Download, Stage::Extract, Stage::Analyze, Stage::Ocr];
+        assert_eq!(
+            ready_stages(
+                &finished,
+                &[Stage::Extract, Stage::Analyze, Stage::Ocr],
+                all_enabled
+            ),
+            vec![Stage::Transcribe]
+        );
+    }
+
+    #[test]
+    fn summarize_waits_for_ocr() {
+        let finished = [Stage::Download, Stage::Extract, Stage::Transcribe];
+        assert!(ready_stages(&finished, &[Stage::Transcribe], all_enabled).is_empty());
+        assert_eq!(
+            ready_stages(&finished, &[Stage::Transcribe], ocr_disabled),
+            vec![Stage::Summarize]
+        );
+
+        let finished = [
+            Stage::Download,
+            Stage::Extract,
+            Stage::Transcribe,
+            Stage::Analyze,
+            Stage::Ocr,
+        ];
+        assert_eq!(
+            ready_stages(&finished, &[Stage::Ocr], all_enabled),
+            vec![Stage::Summarize]
+        );
+    }
+
+    #[test]
+    fn finished_stages_are_not_restarted() {
+        // Rerunning extract without cascading leaves the later stages as they were.
+        let finished = Stage::ALL;
+        assert!(ready_stages(&finished, &[Stage::Extract], all_enabled).is_empty());
+    }
+
+    fn is_subset(required: &[Stage], finished: &[Stage]) -> bool {
+        required.iter().all(|stage| finished.contains(stage))
+    }
+
+    #[test]
+    fn ready_only_when_everything_finished() {
+        let finished = [
+            Stage::Download,
+            Stage::Extract,
+            Stage::Transcribe,
+            Stage::Summarize,
+        ];
+        assert!(!is_subset(&required_stages(ocr_disabled), &finished));
+
+        let finished = [
+            Stage::Download,
+            Stage::Extract,
+            Stage::Analyze,
+            Stage::Transcribe,
+            Stage::Summarize,
+        ];
+        assert!(is_subset(&required_stages(ocr_disabled), &finished));
+        assert!(!is_subset(&required_stages(all_enabled), &finished));
+    }
+
+    #[test]
+    fn resume_unfinished_stages() {
+        assert_eq!(unfinished_stages(&[], all_enabled), vec![Stage::Download]);
+
+        let finished = [Stage::Download, Stage::Extract];
+        assert_eq!(
+            unfinished_stages(&finished, all_enabled),
+            vec![Stage::Analyze, Stage::Transcribe]
+        );
+
+        let finished = [
+            Stage::Download,
+            Stage::Extract,
+            Stage::Analyze,
+            Stage::Transcribe,
+        ];
+        assert_eq!(
+            unfinished_stages(&finished, ocr_disabled),
+            vec![Stage::Summarize]
+        );
+    }
+
+    #[test]
+    fn downstream_stages() {
+        assert_eq!(
+            Stage::Analyze.downstream(),
+            vec![Stage::Ocr, Stage::Summarize]
+        );
+        assert_eq!(
+            Stage::Extract.downstream(),
+            vec![
+                Stage::Analyze,
+                Stage::Ocr,
+                Stage::Transcribe,
+                Stage::Summarize
+            ]
+        );
+        assert!(Stage::Summarize.downstream().is_empty());
+    }
+}
@@ -0,0 +1,238 @@
+//! playlist background job
+#![allow(unused_imports, unused_variables, dead_code)]
+
+use effectum::{JobBuilder, JobRunner, Queue, RecurringJobSchedule, RunningJob};
+use error_stack::{Report, ResultExt};
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+
+use super::{check_command_result, JobError};
+use crate::{
+    models::{collection::CollectionId, organization::OrganizationId, video::source::VideoSource},
+    server::ServerState,
+};
+
+/// The payload data for the playlist background job
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlaylistJobPayload {
+    pub collection_id: CollectionId,
+    pub organization_id: OrganizationId,
+    pub url: String,
+}
+
+/// The parts of yt-dlp's flat playlist listing that we use
+#[derive(Debug, Deserialize)]
+pub(super) struct FlatPlaylist {
+    pub title: Option<String>,
+    #[serde(default)]
+    pub entries: Vec<FlatPlaylistEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct FlatPlaylistEntry {
+    url: Option<String>,
+    webpage_url: Option<String>,
+    pub title: Option<String>,
+    /// The duration in seconds, when the site lists it
+    pub duration: Option<f64>,
+}
+
+impl FlatPlaylistEntry {
+    pub fn url(&self) -> Option<&str> {
+        self.webpage_url.as_deref().or(self.url.as_deref())
+    }
+}
+
+impl FlatPlaylist {
+    /// The canonical URL of each entry, without duplicates. Playlists can list the same video
+    /// more than once.
+    fn unique_urls(&self) -> Vec<String> {
+        let mut urls = Vec::<String>::with_capacity(self.entries.len());
+        for url in self.entries.iter().filter_map(|e| e.url()) {
+            let url = VideoSource::from_url(url).url;
+            if !urls.contains(&url) {
+                urls.push(url);
+            }
+        }
+        urls
+    }
+}
+
+/// List the entries of a playlist or channel, and add a video for each one that the
+/// organization doesn't have yet. Videos that the organization already has are added to the
+/// collection unless they are in another one.
+async fn run(job: RunningJob, state: ServerState) -> Result<(), error_stack::Report<JobError>> {
+    let payload: PlaylistJobPayload = job.json_payload().change_context(JobError::Payload)?;
+
+    let playlist = match list_playlist(&payload.url, None).await {
+        Ok(playlist) => playlist,
+        Err(e) => {
+            // Show the error on the collection, since it otherwise just stays empty.
+            sqlx::query!(
+                "UPDATE collections SET last_error = $2, updated_at = now() WHERE id = $1",
+                payload.collection_id.as_uuid(),
+                format!("{e:?}"),
+            )
+            .execute(&state.db)
+            .await
+            .change_context(JobError::Db)?;
+            return Err(e);
+        }
+    };
+    let title = playlist.title.as_deref();
+    let urls = playlist.unique_urls();
+
+    sqlx::query!(
+        "UPDATE collections
+        SET name = COALESCE(name, $2), last_error = NULL, updated_at = now()
+        WHERE id = $1",
+        payload.collection_id.as_uuid(),
+        title
+    )
+    .execute(&state.db)
+    .await
+    .change_context(JobError::Db)?;
+
+    let existing = sqlx::query_scalar!(
+        r#"UPDATE videos SET collection_id = COALESCE(collection_id, $3)
+        WHERE organization_id = $1 AND url = ANY($2)
+        RETURNING url AS "url!""#,
+        payload.organization_id.as_uuid(),
+        &urls,
+        payload.collection_id.as_uuid(),
+    )
+    .fetch_all(&state.db)
+    .await
+    .change_context(JobError::Db)?;
+
+    let new_urls = urls
+        .iter()
+        .filter(|url| !existing.contains(url))
+        .collect::<Vec<_>>();
+    event!(
+        Level::INFO,
+        collection_id = %payload.collection_id,
+        entries = urls.len(),
+        new = new_urls.len(),
+        "Adding videos from playlist"
+    );
+
+    for url in new_urls {
+        crate::models::video::create_for_url(
+            &state,
+            payload.organization_id,
+            url,
+            Some(payload.collection_id),
+        )
+        .await
+        .change_context(JobError::Queue)?;
+    }
+
+    Ok(())
+}
+
+/// List the entries of a playlist or channel without downloading them. When `max_entries` is
+/// set, only that many entries from the start of the list are returned.
+pub(super) async fn list_playlist(
+    url: &str,
+    max_entries: Option<usize>,
+) -> Result<FlatPlaylist, Report<JobError>> {
+    let mut command = tokio::process::Command::new("yt-dlp");
+    command.args(["--flat-playlist", "--dump-single-json"]);
+    if let Some(max_entries) = max_entries {
+        command.args(["--playlist-end", &max_entries.to_string()]);
+    }
+
+    let result = command
+        .arg(url)
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .change_context(JobError::StartingDownloader)?
+        .wait_with_output()
+        .await
+        .change_context(JobError::ListingPlaylist)?;
+    let result = check_command_result(result, JobError::ListingPlaylist)?;
+
+    serde_json::from_slice(&result.stdout).change_context(JobError::ListingPlaylist)
+}
+
+/// Enqueue the playlist job to run immediately
+pub async fn enqueue(
+    state: &ServerState,
+    name: impl ToString,
+    payload: &PlaylistJobPayload,
+) -> Result<uuid::Uuid, effectum::Error> {
+    create_job_builder()
+        .name(name)
+        .json_payload(payload)?
+        .add_to(&state.queue)
+        .await
+}
+
+/// Enqueue the playlist job to run at a specific time
+pub async fn enqueue_at(
+    state: &ServerState,
+    name: impl ToString,
+    at: chrono::DateTime<chrono::Utc>,
+    payload: &PlaylistJobPayload,
+) -> Result<uuid::Uuid, effectum::Error> {
+    // convert to time crate
+    let timestamp = at.timestamp();
+    let t = time::OffsetDateTime::from_unix_timestamp(timestamp)
+        .map_err(|_| effectum::Error::TimestampOutOfRange("at"))?;
+
+    create_job_builder()
+        .name(name)
+        .json_payload(payload)?
+        .run_at(t)
+        .add_to(&state.queue)
+        .await
+}
+
+/// Register this job with the queue and initialize any recurring jobs.
+pub async fn register(
+    queue: &Queue,
+    init_recurring_jobs: bool,
+) -> Result<JobRunner<ServerState>, effectum::Error> {
+    let runner = JobRunner::builder("playlist", run)
+        .autoheartbeat(false)
+        .format_failures_with_debug(true)
+        .build();
+
+    Ok(runner)
+}
+
+fn create_job_builder() -> JobBuilder {
+    JobBuilder::new("playlist").priority(1).weight(1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn flat_playlist_entries() {
+        let output = br#"{
+            "_type": "playlist",
+            "title": "RustConf 2024",
+            "entries": [
+                {"_type": "url", "url": "https://www.youtube.com/watch?v=aaa", "title": "Talk 1", "duration": 1834.0},
+                {"_type": "url", "url": "bbb", "webpage_url": "https://www.youtube.com/watch?v=bbb"},
+                {"_type": "url", "url": "https://www.youtube.com/watch?v=aaa", "title": "Talk 1"},
+                {"_type": "url", "title": "Private video"}
+            ]
+        }"#;
+
+        let playlist: FlatPlaylist = serde_json::from_slice(output).unwrap();
+        assert_eq!(playlist.title.as_deref(), Some("RustConf 2024"));
+        assert_eq!(playlist.entries[0].duration, Some(1834.0));
+        assert_eq!(
+            playlist.unique_urls(),
+            vec![
+                "https://www.youtube.com/watch?v=aaa",
+                "https://www.youtube.com/watch?v=bbb"
+            ]
+        );
+    }
+}
@@ -0,0 +1,239 @@
+//! Progress reporting for the stages that run long external commands
+//!
+//! The download and extract stages can take many minutes for large videos, so they read the
+//! progress that yt-dlp and ffmpeg print and store it in `metadata.stage_progress`, where the
+//! home page can show it.
+
+use std::{process::Output, time::Duration};
+
+use error_stack::Report;
+use serde_json::json;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, BufReader},
+    process::Command,
+    sync::Mutex,
+    time::Instant,
+};
+use tracing::{event, Level};
+
+use super::pipeline::Stage;
+use crate::{
+    models::video::{StageProgress, VideoId},
+    server::ServerState,
+};
+
+/// The minimum time between writes of a stage's progress to the database
+const UPDATE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Writes the progress of a stage to the video, at most once every [UPDATE_INTERVAL].
+pub(super) struct ProgressReporter<'a> {
+    state: &'a ServerState,
+    id: VideoId,
+    stage: Stage,
+    last_update: Mutex<Option<Instant>>,
+}
+
+impl<'a> ProgressReporter<'a> {
+    pub fn new(state: &'a ServerState, id: VideoId, stage: Stage) -> Self {
+        Self {
+            state,
+            id,
+            stage,
+            last_update: Mutex::new(None),
+        }
+    }
+
+    /// Record the stage's progress, unless it was recorded very recently. Progress is only
+    /// informational, so a failure to write it is logged instead of failing the job.
+    pub async fn report(&self, percent: f32, eta: Option<u32>) {
+        let mut last_update = self.last_update.lock().await;
+        if last_update.is_some_and(|t| t.elapsed() < UPDATE_INTERVAL) {
+            return;
+        }
+        *last_update = Some(Instant::now());
+
+        let progress = StageProgress {
+            stage: self.stage,
+            percent: percent.clamp(0.0, 100.0),
+            eta,
+        };
+
+        let result = sqlx::query!(
+            "UPDATE videos SET metadata = COALESCE(metadata, '{}'::jsonb) || $2 WHERE id = $1",
+            self.id.as_uuid(),
+            json!({ "stage_progress": progress }),
+        )
+        .execute(&self.state.db)
+        .await;
+
+        if let Err(e) = result {
+            event!(Level::WARN, id = %self.id, stage = self.stage.name(), "Failed to record progress: {e:?}");
+        }
+    }
+}
+
+/// Run a command and pass each line that it writes to stdout to `parse_line`, reporting the
+/// progress that it returns when there is a `reporter`. The returned output contains everything
+/// that the command wrote.
+pub(super) async fn run_with_progress(
+    command: &mut Command,
+    reporter: Option<&ProgressReporter<'_>>,
+    mut parse_line: impl FnMut(&str) -> Option<(f32, Option<u32>)>,
+) -> Result<Output, Report<std::io::Error>> {
+    let mut child = command
+        .kill_on_drop(true)
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+
+    let stdout = child.stdout.take().expect("stdout was piped");
+    let mut stderr = child.stderr.take().expect("stderr was piped");
+
+    let read_stdout = async {
+        let mut output = Vec::new();
+        let mut lines = BufReader::new(stdout).lines();
+        while let Some(line) = lines.next_line().await? {
+            if let (Some(reporter), Some((percent, eta))) = (reporter, parse_line(&line)) {
+                reporter.report(percent, eta).await;
+            }
+
+            output.extend_from_slice(line.as_bytes());
+            output.push(b'\n');
+        }
+
+        Ok::<_, std::io::Error>(output)
+    };
+
+    let read_stderr = async {
+        let mut output = Vec::new();
+        stderr.read_to_end(&mut output).await?;
+        Ok::<_, std::io::Error>(output)
+    };
+
+    let (stdout, stderr) = tokio::try_join!(read_stdout, read_stderr)?;
+    let status = child.wait().await?;
+
+    Ok(Output {
+        status,
+        stdout,
+        stderr,
+    })
+}
+
+/// The prefix of the progress lines that yt-dlp prints with [YT_DLP_PROGRESS_TEMPLATE]
+const YT_DLP_PROGRESS_PREFIX: &str = "sbbp-progress";
+
+/// Makes yt-dlp print the download progress in a form that [parse_yt_dlp_progress] can read.
+pub(super) const YT_DLP_PROGRESS_TEMPLATE: &str = "download:sbbp-progress %(progress.downloaded_bytes)s %(progress.total_bytes)s %(progress.total_bytes_estimate)s %(progress.eta)s";
+
+/// Read a progress line printed by yt-dlp. Values that yt-dlp doesn't know are printed as "NA".
+/// yt-dlp downloads the video and audio separately for some sites, so the progress starts over
+/// for each one.
+pub(super) fn parse_yt_dlp_progress(line: &str) -> Option<(f32, Option<u32>)> {
+    let mut fields = line
+        .strip_prefix(YT_DLP_PROGRESS_PREFIX)?
+        .split_whitespace()
+        .map(|f| f.parse::<f64>().ok());
+
+    let downloaded = fields.next()??;
+    let total = fields.next().flatten();
+    let estimate = fields.next().flatten();
+    let eta = fields.next().flatten();
+
+    let total = total.or(estimate).filter(|t| *t > 0.0)?;
+    Some((
+        (downloaded / total * 100.0) as f32,
+        eta.map(|e| e.max(0.0) as u32),
+    ))
+}
+
+/// Arguments that make ffmpeg print its progress in the form that [FfmpegProgress] reads.
+pub(super) const FFMPEG_PROGRESS_ARGS: [&str; 3] = ["-progress", "pipe:1", "-nostats"];
+
+/// Reads the blocks of `key=value` lines that ffmpeg prints with `-progress`. Each block ends
+/// with a `progress` line, which is when the progress is reported.
+pub(super) struct FfmpegProgress {
+    /// The duration of the input in seconds
+    duration: f64,
+    out_time: Option<f64>,
+    speed: Option<f64>,
+}
+
+impl FfmpegProgress {
+    pub fn new(duration: f64) -> Self {
+        Self {
+            duration,
+            out_time: None,
+            speed: None,
+        }
+    }
+
+    pub fn parse_line(&mut self, line: &str) -> Option<(f32, Option<u32>)> {
+        let (key, value) = line.trim().split_once('=')?;
+        match key {
+            // Despite the name, out_time_ms is in microseconds too.
+            "out_time_us" | "out_time_ms" => {
+                self.out_time = value.parse::<f64>().ok().map(|us| us / 1_000_000.0);
+                None
+            }
+            "speed" => {
+                self.speed = value
+                    .trim()
+                    .trim_end_matches('x')
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|s| *s > 0.0);
+                None
+            }
+            "progress" => {
+                if self.duration <= 0.0 {
+                    return None;
+                }
+
+                let out_time = if value == "end" {
+                    self.duration
+                } else {
+                    self.out_time?
+                };
+                let remaining = (self.duration - out_time).max(0.0);
+                let eta = self.speed.map(|speed| (remaining / speed).round() as u32);
+                Some(((out_time / self.duration * 100.0) as f32, eta))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn yt_dlp_progress() {
+        assert_eq!(
+            parse_yt_dlp_progress("sbbp-progress 2500 10000 NA 30"),
+            Some((25.0, Some(30)))
+        );
+        assert_eq!(
+            parse_yt_dlp_progress("sbbp-progress 5000 NA 20000.0 NA"),
+            Some((25.0, None))
+        );
+        assert_eq!(parse_yt_dlp_progress("sbbp-progress 5000 NA NA NA"), None);
+        assert_eq!(
+            parse_yt_dlp_progress("[youtube] abc: Downloading webpage"),
+            None
+        );
+    }
+
+    #[test]
+    fn ffmpeg_progress() {
+        let mut progress = FfmpegProgress::new(120.0);
+        let output = "frame=12\nfps=0.00\nout_time_us=30000000\nout_time=00:00:30.000000\nspeed=2x\nprogress=continue\nout_time_us=N/A\nspeed=N/A\nprogress=continue\nout_time_us=120000000\nprogress=end";
+
+        let reports = output
+            .lines()
+            .filter_map(|line| progress.parse_line(line))
+            .collect::<Vec<_>>();
+        assert_eq!(reports, vec![(25.0, Some(45)), (100.0, None)]);
+    }
+}
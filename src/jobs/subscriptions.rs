@@ -0,0 +1,532 @@
+//! poll_subscriptions background job
+#![allow(unused_imports, unused_variables, dead_code)]
+
+use std::time::Duration;
+
+use effectum::{JobBuilder, JobRunner, Queue, RecurringJobSchedule, RunningJob};
+use error_stack::{Report, ResultExt};
+use quick_xml::events::{BytesStart, Event};
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+
+use super::JobError;
+use crate::{
+    models::{
+        organization::OrganizationId,
+        subscription::{SubscriptionFilters, SubscriptionId},
+        video::source::VideoSource,
+    },
+    server::ServerState,
+};
+
+/// How often every subscription is checked for new items
+const POLL_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The most items to read from a channel or playlist on each check. New items are at the start
+/// of the list, so this only needs to cover what could be published between checks.
+const MAX_ITEMS: usize = 30;
+
+/// The payload data for the poll_subscriptions background job
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PollSubscriptionsPayload {
+    /// Check only this subscription. When empty, every subscription is checked.
+    pub id: Option<SubscriptionId>,
+}
+
+/// An item from a subscription, newest first
+#[derive(Debug, PartialEq)]
+struct SubscriptionItem {
+    url: String,
+    title: Option<String>,
+    /// The duration in seconds, when the source lists it
+    duration: Option<f64>,
+}
+
+struct SubscriptionRow {
+    id: SubscriptionId,
+    organization_id: OrganizationId,
+    url: String,
+    filters: SubscriptionFilters,
+    last_seen_url: Option<String>,
+}
+
+/// Check subscriptions for new items and add them as videos
+async fn run(job: RunningJob, state: ServerState) -> Result<(), error_stack::Report<JobError>> {
+    let payload: PollSubscriptionsPayload = job.json_payload().change_context(JobError::Payload)?;
+
+    let subscriptions = sqlx::query_as!(
+        SubscriptionRow,
+        r#"SELECT id AS "id: SubscriptionId",
+            organization_id AS "organization_id: OrganizationId",
+            url,
+            filters AS "filters: SubscriptionFilters",
+            last_seen_url
+        FROM subscriptions
+        WHERE $1::uuid IS NULL OR id = $1"#,
+        payload.id.as_ref().map(|id| id.as_uuid()),
+    )
+    .fetch_all(&state.db)
+    .await
+    .change_context(JobError::Db)?;
+
+    // A problem with one subscription shouldn't stop the others from being checked, so errors
+    // are recorded on the subscription instead of failing the job.
+    for subscription in subscriptions {
+        let (last_seen_url, error) = match poll(&state, &subscription).await {
+            Ok(result) => (result.newest_url, result.warning),
+            Err(e) => {
+                event!(Level::WARN, id = %subscription.id, "Failed to check subscription: {e:?}");
+                (None, Some(format!("{e:?}")))
+            }
+        };
+
+        sqlx::query!(
+            "UPDATE subscriptions
+            SET last_seen_url = COALESCE($2, last_seen_url),
+                last_checked_at = now(),
+                last_error = $3,
+                updated_at = now()
+            WHERE id = $1",
+            subscription.id.as_uuid(),
+            last_seen_url,
+            error
+        )
+        .execute(&state.db)
+        .await
+        .change_context(JobError::Db)?;
+    }
+
+    Ok(())
+}
+
+/// The outcome of checking a subscription
+struct PollResult {
+    /// The URL of the newest item, which the next check starts from
+    newest_url: Option<String>,
+    /// A problem that didn't stop the check, to show on the subscription
+    warning: Option<String>,
+}
+
+/// Add the items published since the last check.
+async fn poll(
+    state: &ServerState,
+    subscription: &SubscriptionRow,
+) -> Result<PollResult, Report<JobError>> {
+    let items = list_items(state, &subscription.url).await?;
+    let mut result = PollResult {
+        newest_url: items.first().map(|item| item.url.clone()),
+        warning: None,
+    };
+
+    // The first check only records where the subscription starts, instead of adding the
+    // whole back catalog.
+    let Some(last_seen_url) = &subscription.last_seen_url else {
+        return Ok(result);
+    };
+
+    let Some(items) = items_since(&items, last_seen_url) else {
+        event!(
+            Level::WARN,
+            id = %subscription.id,
+            %last_seen_url,
+            "Last seen item is no longer listed, so no items were added"
+        );
+        result.warning = Some(format!(
+            "The last seen item {last_seen_url} is no longer listed, so items published since the previous check may have been missed"
+        ));
+        return Ok(result);
+    };
+
+    let new_items = items
+        .iter()
+        .filter(|item| {
+            subscription
+                .filters
+                .matches(item.title.as_deref(), item.duration)
+        })
+        .collect::<Vec<_>>();
+    if new_items.is_empty() {
+        return Ok(result);
+    }
+
+    let urls = new_items
+        .iter()
+        .map(|item| VideoSource::from_url(&item.url).url)
+        .collect::<Vec<_>>();
+    let existing = sqlx::query_scalar!(
+        r#"SELECT url AS "url!" FROM videos WHERE organization_id = $1 AND url = ANY($2)"#,
+        subscription.organization_id.as_uuid(),
+        &urls
+    )
+    .fetch_all(&state.db)
+    .await
+    .change_context(JobError::Db)?;
+
+    // Add the oldest first so that the video list shows them in publishing order.
+    for (item, url) in new_items.iter().zip(&urls).rev() {
+        if existing.contains(url) {
+            continue;
+        }
+
+        event!(Level::INFO, subscription = %subscription.id, url = %item.url, "Adding video from subscription");
+        crate::models::video::create_for_url(state, subscription.organization_id, &item.url, None)
+            .await
+            .change_context(JobError::Queue)?;
+    }
+
+    Ok(result)
+}
+
+/// The items listed before the last seen item. If the last seen item isn't in the list, there's
+/// no way to tell which items are new, so this returns `None` rather than risk adding every
+/// listed item. This happens when the item is deleted or more items than [MAX_ITEMS] were
+/// published since the last check.
+fn items_since<'a>(
+    items: &'a [SubscriptionItem],
+    last_seen_url: &str,
+) -> Option<&'a [SubscriptionItem]> {
+    let position = items.iter().position(|item| item.url == last_seen_url)?;
+    Some(&items[..position])
+}
+
+/// Read the items of a subscription. RSS and Atom feeds are read directly, and anything else is
+/// listed through yt-dlp.
+async fn list_items(
+    state: &ServerState,
+    url: &str,
+) -> Result<Vec<SubscriptionItem>, Report<JobError>> {
+    let response = state
+        .filigree
+        .http_client
+        .get(url)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .change_context(JobError::ReadingFeed)
+        .attach_printable_lazy(|| url.to_string())?;
+    let is_xml = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|c| c.to_str().ok())
+        .is_some_and(|c| c.contains("xml"));
+
+    if is_xml {
+        let body = response
+            .text()
+            .await
+            .change_context(JobError::ReadingFeed)?;
+        return parse_feed(&body);
+    }
+
+    let playlist = super::playlist::list_playlist(url, Some(MAX_ITEMS)).await?;
+    let items = playlist
+        .entries
+        .into_iter()
+        .filter_map(|entry| {
+            Some(SubscriptionItem {
+                url: entry.url()?.to_string(),
+                title: entry.title,
+                duration: entry.duration,
+            })
+        })
+        .collect();
+    Ok(items)
+}
+
+/// The parts of an RSS item or Atom entry that we read
+#[derive(Clone, Copy, PartialEq)]
+enum FeedField {
+    Title,
+    Link,
+    Duration,
+}
+
+/// Read the items from an RSS or Atom feed. Podcast items are linked to their audio file when
+/// they have one.
+fn parse_feed(xml: &str) -> Result<Vec<SubscriptionItem>, Report<JobError>> {
+    #[derive(Default)]
+    struct PartialItem {
+        link: Option<String>,
+        enclosure: Option<String>,
+        title: Option<String>,
+        duration: Option<f64>,
+    }
+
+    let mut reader = quick_xml::Reader::from_str(xml);
+    reader.trim_text(true);
+
+    let mut items = Vec::new();
+    let mut current: Option<PartialItem> = None;
+    let mut field = None;
+
+    loop {
+        let event = reader.read_event().change_context(JobError::ReadingFeed)?;
+        match event {
+            Event::Start(e) | Event::Empty(e) if is_item(&e) => {
+                current = Some(PartialItem::default());
+            }
+            Event::Start(e) | Event::Empty(e) => {
+                let Some(item) = current.as_mut() else {
+                    continue;
+                };
+
+                match e.local_name().as_ref() {
+                    b"title" if item.title.is_none() => field = Some(FeedField::Title),
+                    b"duration" => field = Some(FeedField::Duration),
+                    // Atom links are in an attribute, and RSS links are the element text.
+                    b"link" => match attribute(&e, b"href") {
+                        Some(href) => {
+                            let rel = attribute(&e, b"rel");
+                            if rel.is_none() || rel.as_deref() == Some("alternate") {
+                                item.link = Some(href);
+                            }
+                        }
+                        None => field = Some(FeedField::Link),
+                    },
+                    b"enclosure" => item.enclosure = attribute(&e, b"url"),
+                    _ => {}
+                }
+            }
+            Event::Text(text) => {
+                let (Some(item), Some(f)) = (current.as_mut(), field) else {
+                    continue;
+                };
+                let text = text.unescape().change_context(JobError::ReadingFeed)?;
+                set_field(item, f, &text);
+            }
+            Event::CData(text) => {
+                let (Some(item), Some(f)) = (current.as_mut(), field) else {
+                    continue;
+                };
+                let text = String::from_utf8_lossy(&text);
+                set_field(item, f, &text);
+            }
+            Event::End(e) if is_item_end(e.local_name().as_ref()) => {
+                if let Some(item) = current.take() {
+                    if let Some(url) = item.enclosure.or(item.link) {
+                        items.push(SubscriptionItem {
+                            url,
+                            title: item.title,
+                            duration: item.duration,
+                        });
+                    }
+                }
+                field = None;
+            }
+            Event::End(_) => field = None,
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    fn is_item(e: &BytesStart) -> bool {
+        is_item_end(e.local_name().as_ref())
+    }
+
+    fn is_item_end(name: &[u8]) -> bool {
+        name == b"item" || name == b"entry"
+    }
+
+    fn set_field(item: &mut PartialItem, field: FeedField, text: &str) {
+        let text = text.trim();
+        match field {
+            FeedField::Title => item.title = Some(text.to_string()),
+            FeedField::Link => item.link = Some(text.to_string()),
+            FeedField::Duration => item.duration = parse_duration(text),
+        }
+    }
+
+    Ok(items)
+}
+
+fn attribute(e: &BytesStart, name: &[u8]) -> Option<String> {
+    let attr = e.try_get_attribute(name).ok()??;
+    attr.unescape_value().ok().map(|v| v.into_owned())
+}
+
+/// Parse a podcast duration, which is either a number of seconds or "h:mm:ss"
+fn parse_duration(text: &str) -> Option<f64> {
+    text.split(':').try_fold(0.0, |total, part| {
+        Some(total * 60.0 + part.parse::<f64>().ok()?)
+    })
+}
+
+/// Enqueue the poll_subscriptions job to run immediately
+pub async fn enqueue(
+    state: &ServerState,
+    name: impl ToString,
+    payload: &PollSubscriptionsPayload,
+) -> Result<uuid::Uuid, effectum::Error> {
+    create_job_builder()
+        .name(name)
+        .json_payload(payload)?
+        .add_to(&state.queue)
+        .await
+}
+
+/// Enqueue the poll_subscriptions job to run at a specific time
+pub async fn enqueue_at(
+    state: &ServerState,
+    name: impl ToString,
+    at: chrono::DateTime<chrono::Utc>,
+    payload: &PollSubscriptionsPayload,
+) -> Result<uuid::Uuid, effectum::Error> {
+    // convert to time crate
+    let timestamp = at.timestamp();
+    let t = time::OffsetDateTime::from_unix_timestamp(timestamp)
+        .map_err(|_| effectum::Error::TimestampOutOfRange("at"))?;
+
+    create_job_builder()
+        .name(name)
+        .json_payload(payload)?
+        .run_at(t)
+        .add_to(&state.queue)
+        .await
+}
+
+/// Register this job with the queue and initialize any recurring jobs.
+pub async fn register(
+    queue: &Queue,
+    init_recurring_jobs: bool,
+) -> Result<JobRunner<ServerState>, effectum::Error> {
+    let runner = JobRunner::builder("poll_subscriptions", run)
+        .autoheartbeat(true)
+        .format_failures_with_debug(true)
+        .build();
+
+    if init_recurring_jobs {
+        let job = create_job_builder()
+            .json_payload(&PollSubscriptionsPayload { id: None })?
+            .build();
+        queue
+            .upsert_recurring_job(
+                "poll_subscriptions".to_string(),
+                RecurringJobSchedule::RepeatEvery {
+                    interval: POLL_INTERVAL,
+                },
+                job,
+                false,
+            )
+            .await?;
+    }
+
+    Ok(runner)
+}
+
+fn create_job_builder() -> JobBuilder {
+    JobBuilder::new("poll_subscriptions").priority(1).weight(1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn podcast_feed() {
+        let feed = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0" xmlns:itunes="http://www.itunes.com/dtds/podcast-1.0.dtd">
+  <channel>
+    <title>A Podcast</title>
+    <link>https://example.com</link>
+    <item>
+      <title><![CDATA[Episode 2: Borrowing & Lending]]></title>
+      <link>https://example.com/2</link>
+      <enclosure url="https://cdn.example.com/2.mp3" type="audio/mpeg" length="1000"/>
+      <itunes:duration>1:02:03</itunes:duration>
+    </item>
+    <item>
+      <title>Episode 1 &amp; Intro</title>
+      <link>https://example.com/1</link>
+      <itunes:duration>1805</itunes:duration>
+    </item>
+  </channel>
+</rss>"#;
+
+        assert_eq!(
+            parse_feed(feed).unwrap(),
+            vec![
+                SubscriptionItem {
+                    url: "https://cdn.example.com/2.mp3".to_string(),
+                    title: Some("Episode 2: Borrowing & Lending".to_string()),
+                    duration: Some(3723.0),
+                },
+                SubscriptionItem {
+                    url: "https://example.com/1".to_string(),
+                    title: Some("Episode 1 & Intro".to_string()),
+                    duration: Some(1805.0),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn atom_feed() {
+        let feed = r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom" xmlns:media="http://search.yahoo.com/mrss/">
+  <title>A Channel</title>
+  <link rel="alternate" href="https://www.youtube.com/channel/abc"/>
+  <entry>
+    <title>Newest video</title>
+    <link rel="alternate" href="https://www.youtube.com/watch?v=new"/>
+    <media:group>
+      <media:title>Newest video</media:title>
+    </media:group>
+  </entry>
+  <entry>
+    <title>Older video</title>
+    <link rel="self" href="https://example.com/self"/>
+    <link href="https://www.youtube.com/watch?v=old"/>
+  </entry>
+</feed>"#;
+
+        assert_eq!(
+            parse_feed(feed).unwrap(),
+            vec![
+                SubscriptionItem {
+                    url: "https://www.youtube.com/watch?v=new".to_string(),
+                    title: Some("Newest video".to_string()),
+                    duration: None,
+                },
+                SubscriptionItem {
+                    url: "https://www.youtube.com/watch?v=old".to_string(),
+                    title: Some("Older video".to_string()),
+                    duration: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn new_items() {
+        let items = [
+            "https://example.com/3",
+            "https://example.com/2",
+            "https://example.com/1",
+        ]
+        .into_iter()
+        .map(|url| SubscriptionItem {
+            url: url.to_string(),
+            title: None,
+            duration: None,
+        })
+        .collect::<Vec<_>>();
+
+        assert_eq!(
+            items_since(&items, "https://example.com/2"),
+            Some(&items[..1])
+        );
+        assert_eq!(
+            items_since(&items, "https://example.com/3"),
+            Some(&items[..0])
+        );
+        assert_eq!(items_since(&items, "https://example.com/0"), None);
+    }
+
+    #[test]
+    fn podcast_durations() {
+        assert_eq!(parse_duration("3600"), Some(3600.0));
+        assert_eq!(parse_duration("45:10"), Some(2710.0));
+        assert_eq!(parse_duration("1:02:03"), Some(3723.0));
+        assert_eq!(parse_duration("about an hour"), None);
+    }
+}
@@ -1,18 +1,36 @@
 //! summarize background job
 #![allow(unused_imports, unused_variables, dead_code)]
 
+use std::sync::Mutex;
+
+use backon::Retryable;
 use effectum::{JobBuilder, JobRunner, Queue, RecurringJobSchedule, RunningJob};
 use error_stack::ResultExt;
-use filigree::testing::ResponseExt;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use tracing::{event, Level};
 
-use super::JobError;
-use crate::{models::video::VideoId, server::ServerState};
+use super::{pipeline::Stage, JobError};
+use crate::{
+    llm::{
+        estimate_tokens,
+        prompts::{PromptInput, PromptSection, PromptTemplate, Prompts, VideoPromptInfo},
+        CompletionRequest, OrganizationLlm,
+    },
+    models::{
+        organization::OrganizationId,
+        usage::TokenUsage,
+        video::{
+            ChapterSummary, SectionSummary, TranscriptChunk, VideoChapter, VideoId, VideoImages,
+            VideoMetadata, VideoSummarySections, VideoTranscript,
+        },
+    },
+    server::ServerState,
+};
 
-const SYSTEM_PROMPT: &str = "Your task is to summarize Youtube video transcripts. Clearly explain the topics discussed, and notable or surprising details, and the general sentiment around them.";
-const SUMMARIZE_PROMPT_PREFIX: &str = "The video transcript follows:";
 const SUMMARIZE_ASSISTANT_PREFIX: &str = "The summary of the above transcript is:";
+const CHUNK_ASSISTANT_PREFIX: &str = "The summary of this section is:";
+const COMBINE_ASSISTANT_PREFIX: &str = "The summary of the whole video is:";
 
 /// The payload data for the summarize background job
 #[derive(Debug, Serialize, Deserialize)]
@@ -25,70 +43,390 @@ async fn run(job: RunningJob, state: ServerState) -> Result<(), error_stack::Rep
     let payload: SummarizeJobPayload = job.json_payload().change_context(JobError::Payload)?;
 
     // Get the transcript from the database
-    let transcript: serde_json::Value = sqlx::query_scalar!(
-        "SELECT transcript FROM videos WHERE id = $1",
+    let video = sqlx::query!(
+        r#"SELECT organization_id, title, author, duration,
+            transcript AS "transcript: VideoTranscript",
+            metadata AS "metadata: VideoMetadata",
+            images AS "images: VideoImages"
+        FROM videos WHERE id = $1"#,
         payload.id.as_uuid()
     )
     .fetch_one(&state.db)
     .await
-    .change_context(JobError::Db)?
-    .ok_or(JobError::NoTranscript)
-    .attach_printable("Video row had no transcript object")?;
-
-    // Send it to the LLM for summary
-    let transcript_text = transcript
-        .pointer("/results/channels/0/alternatives/0/paragraphs/transcript")
-        .and_then(|v| v.as_str())
+    .change_context(JobError::Db)?;
+
+    let transcript = video
+        .transcript
         .ok_or(JobError::NoTranscript)
-        .attach_printable("Found object but transcript was not at the expected path")?
-        .trim();
-
-    let summary_request = json!({
-        "model": "claude-3-haiku-20240307",
-        "max_tokens": 768,
-        "temperature": 0.5,
-        "system": SYSTEM_PROMPT,
-        "messages": [
-            { "role": "user", "content": format!("{SUMMARIZE_PROMPT_PREFIX}:\n\n{transcript_text}") },
-            { "role": "assistant", "content": SUMMARIZE_ASSISTANT_PREFIX }
-        ]
-    });
-
-    let result: serde_json::Value = state
-        .filigree
-        .http_client
-        .post("https://api.anthropic.com/v1/messages")
-        .header("x-api-key", &state.secrets.anthropic)
-        .header("anthropic-version", "2023-06-01")
-        .json(&summary_request)
-        .send()
-        .await
-        .change_context(JobError::Summarizing)?
-        .log_error()
-        .await
-        .change_context(JobError::Summarizing)?
-        .json()
+        .attach_printable("Video row had no transcript object")?;
+
+    let images = video.images.unwrap_or_default();
+
+    let llm = state
+        .llm
+        .for_organization(&state.db, OrganizationId::from_uuid(video.organization_id))
         .await
         .change_context(JobError::Summarizing)?;
 
-    let summary = result["content"][0]["text"]
-        .as_str()
-        .ok_or(JobError::Summarizing)
-        .attach_printable("Failed to find summary in response")?;
+    let metadata = video.metadata.unwrap_or_default();
+    let chapters = metadata.chapters.unwrap_or_default();
+    let summarizer = Summarizer {
+        llm: &llm,
+        prompts: &state.llm.prompts,
+        video: VideoPromptInfo {
+            title: video.title,
+            author: video.author,
+            duration: video.duration,
+            duration_text: video.duration.map(format_duration),
+            description: metadata.description,
+            chapters: chapters.clone(),
+        },
+        images,
+        tokens: Mutex::new(TokenUsage::default()),
+    };
+    let max_tokens = llm.settings.chunk_tokens as usize;
 
-    // Store the summary in the database and set processing_state to Ready
+    let result = summarize_transcript(&summarizer, &transcript, &chapters, max_tokens).await;
+    let usage = state.pricing.llm_usage(
+        Stage::Summarize,
+        llm.provider.as_ref(),
+        &llm.settings.model,
+        summarizer.tokens.into_inner().unwrap(),
+    );
+
+    let (summary, sections) = match result {
+        Ok(result) => result,
+        Err(e) => {
+            // The requests that were sent before the failure still count.
+            if usage.requests > 0 {
+                let recorded = sqlx::query!(
+                    "UPDATE videos SET metadata = jsonb_set(
+                        COALESCE(metadata, '{}'::jsonb),
+                        '{usage}',
+                        COALESCE(metadata->'usage', '[]'::jsonb) || $2
+                    )
+                    WHERE id = $1",
+                    payload.id.as_uuid(),
+                    json!([usage]),
+                )
+                .execute(&state.db)
+                .await;
+                if let Err(db_err) = recorded {
+                    event!(Level::ERROR, video_id=%payload.id, err=?db_err, "Failed to record usage");
+                }
+            }
+            return Err(e);
+        }
+    };
     sqlx::query!(
-        "UPDATE videos SET summary = $1, processing_state = 'ready' WHERE id = $2",
+        "UPDATE videos SET summary = $1, summary_sections = $2,
+            metadata = jsonb_set(
+                COALESCE(metadata, '{}'::jsonb),
+                '{usage}',
+                COALESCE(metadata->'usage', '[]'::jsonb) || $4
+            )
+        WHERE id = $3",
         summary,
-        payload.id.as_uuid()
+        sections.map(|s| json!(s)),
+        payload.id.as_uuid(),
+        json!([usage]),
     )
     .execute(&state.db)
     .await
     .change_context(JobError::Db)?;
 
+    super::pipeline::stage_finished(&state, payload.id, Stage::Summarize, &[]).await?;
+
     Ok(())
 }
 
+/// Summarize the transcript, by chapter when the video has chapters, and return the summary
+/// along with the summaries of the sections that were summarized separately.
+async fn summarize_transcript(
+    summarizer: &Summarizer<'_>,
+    transcript: &VideoTranscript,
+    chapters: &[VideoChapter],
+    max_tokens: usize,
+) -> Result<(String, Option<VideoSummarySections>), error_stack::Report<JobError>> {
+    let mut sections = VideoSummarySections::default();
+    let summary = if chapters.len() > 1 {
+        // Summarize each chapter, and then combine the chapter summaries.
+        for (i, chapter) in chapters.iter().enumerate() {
+            // Assign every paragraph to a chapter, even if the chapter times don't quite cover
+            // the transcript.
+            let start = if i == 0 {
+                f64::NEG_INFINITY
+            } else {
+                chapter.start_time as f64
+            };
+            let end = chapters
+                .get(i + 1)
+                .map(|c| c.start_time as f64)
+                .unwrap_or(f64::INFINITY);
+
+            let chunks = transcript.excerpt(start, end).chunks(max_tokens, &[]);
+            let summary = match chunks.as_slice() {
+                [] => continue,
+                [chunk] => {
+                    summarizer
+                        .complete(
+                            PromptTemplate::ChunkSystem,
+                            PromptTemplate::Chunk,
+                            &PromptInput {
+                                transcript: Some(&chunk.text),
+                                chapter: Some(&chapter.title),
+                                slides: summarizer.slides(chunk.start, chunk.end),
+                                ..Default::default()
+                            },
+                            CHUNK_ASSISTANT_PREFIX,
+                        )
+                        .await?
+                }
+                chunks => {
+                    let partials = summarizer
+                        .summarize_chunks(chunks, Some(&chapter.title), &mut sections.chunks)
+                        .await?;
+                    summarizer
+                        .combine_summaries(
+                            partials.into_iter().map(|s| (None, s)).collect(),
+                            PromptTemplate::ChunkSystem,
+                            CHUNK_ASSISTANT_PREFIX,
+                        )
+                        .await?
+                }
+            };
+
+            sections.chapters.push(ChapterSummary {
+                title: chapter.title.clone(),
+                start: chapter.start_time as f64,
+                end: chapter.end_time as f64,
+                summary,
+            });
+        }
+
+        let partials = sections
+            .chapters
+            .iter()
+            .map(|c| (Some(c.title.clone()), c.summary.clone()))
+            .collect();
+        summarizer
+            .combine_summaries(
+                partials,
+                PromptTemplate::CombineSystem,
+                COMBINE_ASSISTANT_PREFIX,
+            )
+            .await?
+    } else {
+        let chunks = transcript.chunks(max_tokens, &[]);
+        if chunks.len() <= 1 {
+            let transcript_text = transcript.text();
+            summarizer
+                .complete(
+                    PromptTemplate::SummarizeSystem,
+                    PromptTemplate::Summarize,
+                    &PromptInput {
+                        transcript: Some(&transcript_text),
+                        slides: summarizer.slides(f64::NEG_INFINITY, f64::INFINITY),
+                        ..Default::default()
+                    },
+                    SUMMARIZE_ASSISTANT_PREFIX,
+                )
+                .await?
+        } else {
+            // Summarize each chunk, and then summarize the summaries.
+            let partials = summarizer
+                .summarize_chunks(&chunks, None, &mut sections.chunks)
+                .await?;
+            summarizer
+                .combine_summaries(
+                    partials.into_iter().map(|s| (None, s)).collect(),
+                    PromptTemplate::CombineSystem,
+                    COMBINE_ASSISTANT_PREFIX,
+                )
+                .await?
+        }
+    };
+
+    Ok((summary, (!sections.is_empty()).then_some(sections)))
+}
+
+/// Format a duration in seconds as "h:mm:ss" or "m:ss"
+fn format_duration(seconds: i32) -> String {
+    let hours = seconds / 3600;
+    let minutes = (seconds / 60) % 60;
+    let seconds = seconds % 60;
+    if hours > 0 {
+        format!("{hours}:{minutes:02}:{seconds:02}")
+    } else {
+        format!("{minutes}:{seconds:02}")
+    }
+}
+
+struct Summarizer<'a> {
+    llm: &'a OrganizationLlm,
+    prompts: &'a Prompts,
+    video: VideoPromptInfo,
+    images: VideoImages,
+    /// The tokens used by the requests sent so far
+    tokens: Mutex<TokenUsage>,
+}
+
+impl<'a> Summarizer<'a> {
+    /// The slide text to include when summarizing the part of the video between `start` and `end`
+    fn slides(&self, start: f64, end: f64) -> Vec<&str> {
+        self.images.text_between(start, end)
+    }
+
+    /// Summarize each chunk of a transcript, recording the summaries in `sections`.
+    async fn summarize_chunks(
+        &self,
+        chunks: &[TranscriptChunk],
+        chapter_title: Option<&str>,
+        sections: &mut Vec<SectionSummary>,
+    ) -> Result<Vec<String>, error_stack::Report<JobError>> {
+        let mut summaries = Vec::with_capacity(chunks.len());
+        for chunk in chunks {
+            let summary = self
+                .complete(
+                    PromptTemplate::ChunkSystem,
+                    PromptTemplate::Chunk,
+                    &PromptInput {
+                        transcript: Some(&chunk.text),
+                        chapter: chapter_title,
+                        slides: self.slides(chunk.start, chunk.end),
+                        ..Default::default()
+                    },
+                    CHUNK_ASSISTANT_PREFIX,
+                )
+                .await
+                .attach_printable_lazy(|| format!("Summarizing chunk at {}s", chunk.start))?;
+
+            sections.push(SectionSummary {
+                start: chunk.start,
+                end: chunk.end,
+                summary: summary.clone(),
+            });
+            summaries.push(summary);
+        }
+
+        Ok(summaries)
+    }
+
+    /// Combine the summaries of consecutive sections, each with an optional title, into a single
+    /// summary, using `system` and `response_prefix` for the final request. If there are too many
+    /// to fit in one request, neighboring summaries are combined in groups first.
+    async fn combine_summaries(
+        &self,
+        mut summaries: Vec<(Option<String>, String)>,
+        system: PromptTemplate,
+        response_prefix: &str,
+    ) -> Result<String, error_stack::Report<JobError>> {
+        loop {
+            let mut groups: Vec<Vec<(Option<String>, String)>> = vec![];
+            let mut group_tokens = 0;
+            for summary in summaries {
+                let tokens = estimate_tokens(&summary.1);
+                match groups.last_mut() {
+                    Some(group)
+                        if group_tokens + tokens <= self.llm.settings.chunk_tokens as usize =>
+                    {
+                        group.push(summary);
+                        group_tokens += tokens;
+                    }
+                    _ => {
+                        groups.push(vec![summary]);
+                        group_tokens = tokens;
+                    }
+                }
+            }
+
+            // If grouping made no progress then just send everything and let the model cope.
+            let finished = groups.len() == 1 || groups.iter().all(|g| g.len() == 1);
+            if finished {
+                let all = groups.into_iter().flatten().collect::<Vec<_>>();
+                return self
+                    .complete(
+                        system,
+                        PromptTemplate::Combine,
+                        &combine_input(&all),
+                        response_prefix,
+                    )
+                    .await;
+            }
+
+            let mut combined = Vec::with_capacity(groups.len());
+            for group in groups {
+                let summary = self
+                    .complete(
+                        PromptTemplate::ChunkSystem,
+                        PromptTemplate::Combine,
+                        &combine_input(&group),
+                        CHUNK_ASSISTANT_PREFIX,
+                    )
+                    .await?;
+                combined.push((None, summary));
+            }
+
+            summaries = combined;
+        }
+    }
+
+    /// Render the prompts and send them to the LLM, retrying on transient errors, and return the
+    /// response text.
+    async fn complete(
+        &self,
+        system: PromptTemplate,
+        prompt: PromptTemplate,
+        input: &PromptInput<'_>,
+        response_prefix: &str,
+    ) -> Result<String, error_stack::Report<JobError>> {
+        let overrides = &self.llm.prompt_overrides;
+        let system = self
+            .prompts
+            .render(system, overrides, &self.video, input)
+            .change_context(JobError::Summarizing)?;
+        let prompt = self
+            .prompts
+            .render(prompt, overrides, &self.video, input)
+            .change_context(JobError::Summarizing)?;
+
+        let llm = &self.llm.provider;
+        let settings = &self.llm.settings;
+        let backoff = backon::ExponentialBuilder::default();
+        let result = (|| {
+            llm.complete(CompletionRequest {
+                settings,
+                system: &system,
+                prompt: &prompt,
+                response_prefix: Some(response_prefix),
+            })
+        })
+        .retry(&backoff)
+        .when(|e| e.current_context().is_retryable())
+        .notify(|_, _| self.tokens.lock().unwrap().add_failed())
+        .await
+        .inspect_err(|_| self.tokens.lock().unwrap().add_failed())
+        .change_context(JobError::Summarizing)
+        .attach_printable_lazy(|| format!("{} model {}", llm.name(), settings.model))?;
+
+        self.tokens.lock().unwrap().add(&result);
+        Ok(result.text.trim().to_string())
+    }
+}
+
+fn combine_input(summaries: &[(Option<String>, String)]) -> PromptInput<'_> {
+    PromptInput {
+        sections: summaries
+            .iter()
+            .map(|(title, summary)| PromptSection {
+                title: title.as_deref(),
+                summary: summary.trim(),
+            })
+            .collect(),
+        ..Default::default()
+    }
+}
+
 /// Enqueue the summarize job to run immediately
 pub async fn enqueue(
     state: &ServerState,
@@ -127,10 +465,12 @@ pub async fn register(
     queue: &Queue,
     init_recurring_jobs: bool,
 ) -> Result<JobRunner<ServerState>, effectum::Error> {
-    let runner = JobRunner::builder("summarize", run)
-        .autoheartbeat(false)
-        .format_failures_with_debug(true)
-        .build();
+    let runner = JobRunner::builder("summarize", |job, state| {
+        super::track_failure(Stage::Summarize, job, state, run)
+    })
+    .autoheartbeat(false)
+    .format_failures_with_debug(true)
+    .build();
 
     Ok(runner)
 }
@@ -4,14 +4,14 @@
 use backon::Retryable;
 use effectum::{JobBuilder, JobRunner, Queue, RecurringJobSchedule, RunningJob};
 use error_stack::ResultExt;
-use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use super::JobError;
+use super::{pipeline::Stage, JobError};
 use crate::{
-    models::video::{VideoId, VideoProcessingState},
+    models::video::{VideoId, VideoProcessingState, VideoTranscript},
     server::ServerState,
+    transcription::TranscriptionRequest,
 };
 
 /// The payload data for the transcribe background job
@@ -28,111 +28,71 @@ async fn run(job: RunningJob, state: ServerState) -> Result<(), error_stack::Rep
 
     let start = tokio::time::Instant::now();
 
-    // send it to deepgram
     let backoff = backon::ExponentialBuilder::default();
 
-    let mut transcribe_result = (|| send_request(&state, payload.id, &payload.audio_path))
-        .retry(&backoff)
-        .when(|(retryable, _)| *retryable)
-        .await
-        .map_err(|(_, e)| e)
-        .attach_printable_lazy(|| payload.audio_path.clone())?
-        .error_for_status()
-        .change_context(JobError::Transcribe)
-        .attach_printable_lazy(|| payload.audio_path.clone())?
-        .json::<serde_json::Value>()
+    let transcribe_result = (|| {
+        state.transcription.transcribe(TranscriptionRequest {
+            id: payload.id,
+            storage: &state.storage.uploads,
+            audio_path: &payload.audio_path,
+        })
+    })
+    .retry(&backoff)
+    .when(|e| e.current_context().is_retryable())
+    .await
+    .change_context(JobError::Transcribe)
+    .attach_printable_lazy(|| payload.audio_path.clone())?;
+
+    // Keep the raw response around so that it can be converted again if the normalized
+    // format changes.
+    let raw_path = format!("{}/transcript_raw.json", payload.storage_prefix);
+    let raw_bytes =
+        serde_json::to_vec(&transcribe_result).change_context(JobError::StorageUpload)?;
+    state
+        .storage
+        .uploads
+        .put(&raw_path, raw_bytes.into())
         .await
+        .change_context(JobError::StorageUpload)
+        .attach_printable(raw_path)?;
+
+    let transcript = VideoTranscript::from_provider_response(&transcribe_result)
         .change_context(JobError::Transcribe)
-        .attach_printable_lazy(|| payload.audio_path.clone())?;
+        .attach_printable("Failed to convert transcription response")?;
 
-    // Tag it with the format so that we can more easily use the transcript elsewhere
-    transcribe_result["_provider_format"] = "deepgram_v1".into();
+    let usage = state
+        .pricing
+        .transcription_usage(state.transcription.as_ref(), &transcribe_result);
 
     sqlx::query!(
         "UPDATE videos SET
         transcript = $2,
-        metadata = metadata || $3
+        metadata = jsonb_set(
+            metadata || $3,
+            '{usage}',
+            COALESCE(metadata->'usage', '[]'::jsonb) || $4
+        )
         WHERE id = $1",
         payload.id.as_uuid(),
-        json!(transcribe_result),
+        json!(transcript),
         json!({
             "transcription": {
                 "duration": start.elapsed().as_secs(),
-            }
+                "provider": state.transcription.name(),
+            },
+            "subtitles": null,
         }),
+        json!([usage]),
     )
     .execute(&state.db)
     .await
     .change_context(JobError::Db)?;
 
-    super::summarize::enqueue(
-        &state,
-        payload.id,
-        &super::summarize::SummarizeJobPayload { id: payload.id },
-    )
-    .await
-    .change_context(JobError::Queue)?;
+    super::pipeline::stage_finished(&state, payload.id, Stage::Transcribe, &[]).await?;
 
     Ok(())
 }
 
-async fn send_request(
-    state: &ServerState,
-    id: VideoId,
-    audio_path: &str,
-) -> Result<reqwest::Response, (bool, error_stack::Report<JobError>)> {
-    // get audio stream from storage
-    let audio = state
-        .storage
-        .uploads
-        .get(audio_path)
-        .await
-        .change_context(JobError::StorageDownload)
-        .map_err(|e| (false, e))?;
-    let body = reqwest::Body::wrap_stream(audio.into_stream());
-
-    let id = id.to_string();
-    let response = state
-        .filigree
-        .http_client
-        .post("https://api.deepgram.com/v1/listen")
-        .header(
-            "Authorization",
-            format!("Token {}", &state.secrets.deepgram),
-        )
-        .header("Content-Type", "audio/mpeg")
-        .query(&[
-            ("model", "nova-2"),
-            ("paragraphs", "true"),
-            ("punctuate", "true"),
-            ("utterances", "true"),
-            ("smart_format", "true"),
-            ("tag", &id),
-        ])
-        // It comes back pretty quick even with long videos so just wait inline
-        .timeout(std::time::Duration::from_secs(300))
-        .body(body)
-        .send()
-        .await
-        .change_context(JobError::Transcribe)
-        .map_err(|e| (false, e))?
-        .error_for_status();
-
-    // TODO This and the "return a bool" are messy, make it work within the error
-    let retryable = if let Err(e) = &response {
-        let status = e.status().unwrap();
-        status.is_server_error()
-            || status == StatusCode::TOO_MANY_REQUESTS
-            || status == StatusCode::REQUEST_TIMEOUT
-    } else {
-        false
-    };
-
-    response
-        .change_context(JobError::Transcribe)
-        .map_err(|e| (retryable, e))
-}
-
 /// Enqueue the transcribe job to run immediately
 pub async fn enqueue(
     state: &ServerState,
@@ -171,10 +131,12 @@ pub async fn register(
     queue: &Queue,
     init_recurring_jobs: bool,
 ) -> Result<JobRunner<ServerState>, effectum::Error> {
-    let runner = JobRunner::builder("transcribe", run)
-        .autoheartbeat(false)
-        .format_failures_with_debug(true)
-        .build();
+    let runner = JobRunner::builder("transcribe", |job, state| {
+        super::track_failure(Stage::Transcribe, job, state, run)
+    })
+    .autoheartbeat(false)
+    .format_failures_with_debug(true)
+    .build();
 
     Ok(runner)
 }
@@ -0,0 +1,101 @@
+//! upgrade_transcripts background job
+//!
+//! Transcripts stored before they were normalized hold the raw provider response. They are
+//! converted whenever they are read, and this job writes the converted transcripts back so that
+//! the conversion only has to happen once. It is queued each time the server starts, and does
+//! nothing once every transcript has been converted.
+
+use effectum::{JobBuilder, JobRunner, Queue, RunningJob};
+use error_stack::ResultExt;
+use serde_json::json;
+use tracing::{event, Level};
+
+use super::JobError;
+use crate::{
+    models::video::{VideoId, VideoTranscript},
+    server::ServerState,
+};
+
+/// The number of transcripts to read from the database at once
+const BATCH_SIZE: i64 = 50;
+
+/// Convert every transcript that is still in a provider's format
+async fn run(_job: RunningJob, state: ServerState) -> Result<(), error_stack::Report<JobError>> {
+    // Transcripts that can't be converted are left alone, so step through the videos in order
+    // instead of querying for the remaining ones again.
+    let mut after = uuid::Uuid::nil();
+    let mut upgraded = 0;
+    loop {
+        let rows = sqlx::query!(
+            r#"SELECT id, transcript AS "transcript!: serde_json::Value"
+            FROM videos
+            WHERE transcript ? '_provider_format' AND id > $1
+            ORDER BY id
+            LIMIT $2"#,
+            after,
+            BATCH_SIZE
+        )
+        .fetch_all(&state.db)
+        .await
+        .change_context(JobError::Db)?;
+
+        let Some(last) = rows.last() else {
+            break;
+        };
+        after = last.id;
+
+        for row in rows {
+            let id = VideoId::from_uuid(row.id);
+            let transcript = match VideoTranscript::from_provider_response(&row.transcript) {
+                Ok(transcript) => transcript,
+                Err(e) => {
+                    event!(Level::ERROR, video_id=%id, err=%e, "Failed to upgrade transcript");
+                    continue;
+                }
+            };
+
+            sqlx::query!(
+                "UPDATE videos SET transcript = $2
+                WHERE id = $1 AND transcript ? '_provider_format'",
+                row.id,
+                json!(transcript)
+            )
+            .execute(&state.db)
+            .await
+            .change_context(JobError::Db)?;
+            upgraded += 1;
+        }
+    }
+
+    if upgraded > 0 {
+        event!(Level::INFO, count = upgraded, "Upgraded stored transcripts");
+    }
+
+    Ok(())
+}
+
+/// Enqueue the upgrade_transcripts job to run immediately
+pub async fn enqueue(queue: &Queue) -> Result<uuid::Uuid, effectum::Error> {
+    create_job_builder().add_to(queue).await
+}
+
+/// Register this job with the queue, and queue a run when recurring jobs are initialized.
+pub async fn register(
+    queue: &Queue,
+    init_recurring_jobs: bool,
+) -> Result<JobRunner<ServerState>, effectum::Error> {
+    let runner = JobRunner::builder("upgrade_transcripts", run)
+        .autoheartbeat(true)
+        .format_failures_with_debug(true)
+        .build();
+
+    if init_recurring_jobs {
+        enqueue(queue).await?;
+    }
+
+    Ok(runner)
+}
+
+fn create_job_builder() -> JobBuilder {
+    JobBuilder::new("upgrade_transcripts").priority(0).weight(1)
+}
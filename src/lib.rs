@@ -4,12 +4,14 @@ pub mod db;
 pub mod emails;
 pub mod error;
 pub mod jobs;
+pub mod llm;
 pub mod models;
 pub mod pages;
 pub mod server;
 pub mod storage;
 #[cfg(test)]
 pub mod tests;
+pub mod transcription;
 pub mod users;
 
 pub use error::Error;
@@ -0,0 +1,78 @@
+use async_trait::async_trait;
+use error_stack::{Report, ResultExt};
+use serde_json::json;
+
+use super::{check_response_status, CompletionRequest, CompletionResponse, LlmError, LlmProvider};
+
+/// The Anthropic messages API
+pub struct Anthropic {
+    http_client: reqwest::Client,
+    base_url: String,
+    api_key: String,
+}
+
+impl Anthropic {
+    pub fn new(http_client: reqwest::Client, base_url: String, api_key: String) -> Self {
+        Self {
+            http_client,
+            base_url,
+            api_key,
+        }
+    }
+}
+
+#[async_trait]
+impl LlmProvider for Anthropic {
+    fn name(&self) -> &'static str {
+        "anthropic"
+    }
+
+    async fn complete(
+        &self,
+        request: CompletionRequest<'_>,
+    ) -> Result<CompletionResponse, Report<LlmError>> {
+        let mut messages = vec![json!({ "role": "user", "content": request.prompt })];
+        if let Some(prefix) = request.response_prefix {
+            messages.push(json!({ "role": "assistant", "content": prefix }));
+        }
+
+        let body = json!({
+            "model": request.settings.model,
+            "max_tokens": request.settings.max_tokens,
+            "temperature": request.settings.temperature,
+            "system": request.system,
+            "messages": messages,
+        });
+
+        let response = self
+            .http_client
+            .post(format!("{}/messages", self.base_url.trim_end_matches('/')))
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", "2023-06-01")
+            .json(&body)
+            .send()
+            .await
+            .change_context(LlmError::Request)?;
+
+        let result: serde_json::Value = check_response_status(response)
+            .await?
+            .json()
+            .await
+            .change_context(LlmError::Response)?;
+
+        let text = result["content"][0]["text"]
+            .as_str()
+            .ok_or(LlmError::Response)
+            .attach_printable("Failed to find text in response")?;
+
+        Ok(CompletionResponse {
+            text: text.to_string(),
+            model: result["model"]
+                .as_str()
+                .unwrap_or(&request.settings.model)
+                .to_string(),
+            input_tokens: result["usage"]["input_tokens"].as_u64().map(|t| t as u32),
+            output_tokens: result["usage"]["output_tokens"].as_u64().map(|t| t as u32),
+        })
+    }
+}
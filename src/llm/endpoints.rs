@@ -0,0 +1,115 @@
+//! Endpoints for managing an organization's summarization settings
+
+use std::collections::HashMap;
+
+use axum::{extract::State, response::IntoResponse, routing};
+use axum_jsonschema::Json;
+use error_stack::ResultExt;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    prompts::{validate_template, OrganizationPrompts, PromptTemplate},
+    OrganizationLlmSettings,
+};
+use crate::{
+    auth::{has_any_permission, Authed},
+    server::ServerState,
+    Error,
+};
+
+#[derive(Serialize, Deserialize, Debug, Default, JsonSchema)]
+pub struct SummarizeSettings {
+    /// Overrides of the deployment's language model settings
+    #[serde(default)]
+    pub llm: OrganizationLlmSettings,
+    /// The organization's prompt templates, keyed by template name. Templates not listed here
+    /// use the default version.
+    #[serde(default)]
+    pub prompt_templates: OrganizationPrompts,
+}
+
+#[derive(Serialize, Debug, JsonSchema)]
+pub struct SummarizeSettingsResponse {
+    #[serde(flatten)]
+    pub settings: SummarizeSettings,
+    /// The built-in prompt templates, for reference when writing new ones
+    pub default_prompt_templates: HashMap<String, String>,
+}
+
+async fn get_settings(
+    State(state): State<ServerState>,
+    auth: Authed,
+) -> Result<impl IntoResponse, Error> {
+    let row = sqlx::query!(
+        r#"SELECT
+            llm_settings AS "llm_settings: sqlx::types::Json<OrganizationLlmSettings>",
+            prompt_templates AS "prompt_templates: sqlx::types::Json<OrganizationPrompts>"
+        FROM organizations WHERE id = $1"#,
+        auth.organization_id.as_uuid()
+    )
+    .fetch_optional(&state.db)
+    .await
+    .change_context(Error::Db)?
+    .ok_or(Error::NotFound("Organization"))?;
+
+    let default_prompt_templates = PromptTemplate::ALL
+        .iter()
+        .map(|t| (t.name().to_string(), t.default_source().into_owned()))
+        .collect();
+
+    Ok(Json(SummarizeSettingsResponse {
+        settings: SummarizeSettings {
+            llm: row.llm_settings.map(|s| s.0).unwrap_or_default(),
+            prompt_templates: row.prompt_templates.map(|p| p.0).unwrap_or_default(),
+        },
+        default_prompt_templates,
+    }))
+}
+
+async fn update_settings(
+    State(state): State<ServerState>,
+    auth: Authed,
+    Json(body): Json<SummarizeSettings>,
+) -> Result<impl IntoResponse, Error> {
+    state
+        .llm
+        .validate(body.llm.clone())
+        .map_err(|e| Error::InvalidSettings(format!("{}", e.current_context())))?;
+
+    for (name, source) in &body.prompt_templates {
+        validate_template(name, source).map_err(|e| {
+            let message = e
+                .frames()
+                .filter_map(|f| f.downcast_ref::<tera::Error>())
+                .map(|e| format!("{e:?}"))
+                .next()
+                .unwrap_or_else(|| e.current_context().to_string());
+            Error::InvalidSettings(message)
+        })?;
+    }
+
+    sqlx::query!(
+        "UPDATE organizations SET llm_settings = $2, prompt_templates = $3 WHERE id = $1",
+        auth.organization_id.as_uuid(),
+        sqlx::types::Json(&body.llm) as _,
+        sqlx::types::Json(&body.prompt_templates) as _,
+    )
+    .execute(&state.db)
+    .await
+    .change_context(Error::Db)?;
+
+    Ok(Json(body))
+}
+
+pub fn create_routes() -> axum::Router<ServerState> {
+    axum::Router::new()
+        .route(
+            "/organization/summarize_settings",
+            routing::get(get_settings).route_layer(has_any_permission(vec!["org_admin"])),
+        )
+        .route(
+            "/organization/summarize_settings",
+            routing::put(update_settings).route_layer(has_any_permission(vec!["org_admin"])),
+        )
+}
@@ -0,0 +1,546 @@
+//! Language model providers, used for summarization
+
+mod anthropic;
+pub mod endpoints;
+mod ollama;
+mod openai;
+pub mod prompts;
+
+use std::{path::PathBuf, sync::Arc};
+
+use async_trait::async_trait;
+use error_stack::{Report, ResultExt};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use self::prompts::{OrganizationPrompts, Prompts};
+pub use self::{anthropic::Anthropic, ollama::Ollama, openai::OpenAiCompatible};
+use crate::{models::organization::OrganizationId, server::Secrets, Error};
+
+#[derive(thiserror::Error, Debug)]
+pub enum LlmError {
+    #[error("Failed to send request to language model")]
+    Request,
+    #[error("Language model returned status {0}")]
+    Status(StatusCode),
+    #[error("Failed to read language model response")]
+    Response,
+    #[error("Invalid language model settings")]
+    Config,
+    #[error("Invalid language model settings: {0}")]
+    InvalidSettings(String),
+    #[error("Failed to read organization settings")]
+    Db,
+}
+
+impl LlmError {
+    /// Whether the request might succeed if tried again.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            LlmError::Status(status) => {
+                status.is_server_error()
+                    || *status == StatusCode::TOO_MANY_REQUESTS
+                    || *status == StatusCode::REQUEST_TIMEOUT
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The model parameters used for a request
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelSettings {
+    pub model: String,
+    pub temperature: f32,
+    pub max_tokens: u32,
+    /// The maximum number of input tokens to send in a single request. Longer inputs are split up.
+    pub chunk_tokens: u32,
+}
+
+/// A rough estimate of the number of tokens in some text. This doesn't match any particular
+/// tokenizer, but is close enough for deciding how to split up input.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.len().div_ceil(4)
+}
+
+pub struct CompletionRequest<'a> {
+    pub settings: &'a ModelSettings,
+    pub system: &'a str,
+    pub prompt: &'a str,
+    /// Text to start the model's response with. This is only used by providers that support
+    /// prefilling the response, and ignored by the others.
+    pub response_prefix: Option<&'a str>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CompletionResponse {
+    pub text: String,
+    /// The model that generated the response, as reported by the provider
+    pub model: String,
+    pub input_tokens: Option<u32>,
+    pub output_tokens: Option<u32>,
+}
+
+#[async_trait]
+pub trait LlmProvider: Send + Sync {
+    /// A short name for the provider, for logging and stats
+    fn name(&self) -> &'static str;
+
+    /// Whether the provider charges for the tokens it processes. Local models are free to run.
+    fn billed(&self) -> bool {
+        true
+    }
+
+    async fn complete(
+        &self,
+        request: CompletionRequest<'_>,
+    ) -> Result<CompletionResponse, Report<LlmError>>;
+}
+
+#[derive(
+    clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, schemars::JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum LlmProviderKind {
+    /// The Anthropic messages API
+    Anthropic,
+    /// A server implementing the OpenAI chat completions API
+    #[value(name = "openai")]
+    #[serde(rename = "openai")]
+    OpenAi,
+    /// An Ollama server
+    Ollama,
+}
+
+impl LlmProviderKind {
+    fn default_api_url(&self) -> &'static str {
+        match self {
+            LlmProviderKind::Anthropic => "https://api.anthropic.com/v1",
+            LlmProviderKind::OpenAi => "https://api.openai.com/v1",
+            LlmProviderKind::Ollama => "http://localhost:11434",
+        }
+    }
+
+    fn default_model(&self) -> Option<&'static str> {
+        match self {
+            LlmProviderKind::Anthropic => Some("claude-3-haiku-20240307"),
+            LlmProviderKind::OpenAi => Some("gpt-4o-mini"),
+            // There's no sensible default for a local model
+            LlmProviderKind::Ollama => None,
+        }
+    }
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct LlmConfig {
+    /// The language model service to use for summarization
+    #[clap(long = "llm-provider", env = "LLM_PROVIDER", value_enum, default_value_t = LlmProviderKind::Anthropic)]
+    pub provider: LlmProviderKind,
+
+    /// The model to use. Defaults to Claude 3 Haiku for Anthropic and gpt-4o-mini for OpenAI, and
+    /// is required for Ollama.
+    #[clap(long = "llm-model", env = "LLM_MODEL")]
+    pub model: Option<String>,
+
+    /// The base URL of the provider's API, if not using the provider's default
+    #[clap(long = "llm-api-url", env = "LLM_API_URL")]
+    pub api_url: Option<String>,
+
+    #[clap(
+        long = "llm-temperature",
+        env = "LLM_TEMPERATURE",
+        default_value_t = 0.5
+    )]
+    pub temperature: f32,
+
+    /// The maximum number of tokens to generate
+    #[clap(long = "llm-max-tokens", env = "LLM_MAX_TOKENS", default_value_t = 768)]
+    pub max_tokens: u32,
+
+    /// The maximum number of input tokens to send in a single request. Transcripts longer than
+    /// this are summarized in chunks. This should be comfortably below the model's context size.
+    #[clap(
+        long = "llm-chunk-tokens",
+        env = "LLM_CHUNK_TOKENS",
+        default_value_t = 24000
+    )]
+    pub chunk_tokens: u32,
+
+    /// A directory of prompt templates which override the built-in prompts. The templates are
+    /// read each time they are used, so they can be changed without restarting the server.
+    #[clap(long, env = "PROMPT_TEMPLATE_DIR")]
+    pub prompt_template_dir: Option<PathBuf>,
+}
+
+impl Default for LlmConfig {
+    fn default() -> Self {
+        Self {
+            provider: LlmProviderKind::Anthropic,
+            model: None,
+            api_url: None,
+            temperature: 0.5,
+            max_tokens: 768,
+            chunk_tokens: 24000,
+            prompt_template_dir: None,
+        }
+    }
+}
+
+/// Per-organization overrides of the deployment's [LlmConfig], stored in
+/// `organizations.llm_settings`. Unset fields use the deployment's configuration.
+///
+/// Organizations can't set the API URL, since requests are sent with the deployment's API keys.
+/// For the same reason, they can only choose a provider other than the deployment's when the
+/// deployment has that provider's own API key, which is only the case for Anthropic.
+/// `LLM_API_KEY` belongs to the deployment's provider and URL, and Ollama has no key at all.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, schemars::JsonSchema)]
+pub struct OrganizationLlmSettings {
+    pub provider: Option<LlmProviderKind>,
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+    pub chunk_tokens: Option<u32>,
+}
+
+/// The language model setup for a particular organization
+pub struct OrganizationLlm {
+    pub provider: Arc<dyn LlmProvider>,
+    pub settings: ModelSettings,
+    /// The organization's versions of the prompt templates
+    pub prompt_overrides: OrganizationPrompts,
+}
+
+/// The language model providers available to the application
+pub struct Llm {
+    pub prompts: Prompts,
+    config: LlmConfig,
+    default_provider: Arc<dyn LlmProvider>,
+    default_settings: ModelSettings,
+    http_client: reqwest::Client,
+    anthropic_api_key: Option<String>,
+    openai_api_key: Option<String>,
+}
+
+impl Llm {
+    pub fn new(
+        config: LlmConfig,
+        secrets: &Secrets,
+        http_client: reqwest::Client,
+    ) -> Result<Self, Report<Error>> {
+        let anthropic_api_key = secrets.anthropic.clone();
+        let openai_api_key = secrets.llm_api_key.clone();
+
+        let default_provider = create_provider(
+            config.provider,
+            config
+                .api_url
+                .clone()
+                .unwrap_or_else(|| config.provider.default_api_url().to_string()),
+            http_client.clone(),
+            anthropic_api_key.clone(),
+            openai_api_key.clone(),
+        )
+        .change_context(Error::Config)?;
+
+        let default_settings = ModelSettings {
+            model: config
+                .model
+                .clone()
+                .or_else(|| config.provider.default_model().map(String::from))
+                .ok_or(Error::Config)
+                .attach_printable("LLM_MODEL is required for this provider")?,
+            temperature: config.temperature,
+            max_tokens: config.max_tokens,
+            chunk_tokens: config.chunk_tokens,
+        };
+
+        Ok(Self {
+            prompts: Prompts::new(config.prompt_template_dir.clone()),
+            config,
+            default_provider,
+            default_settings,
+            http_client,
+            anthropic_api_key,
+            openai_api_key,
+        })
+    }
+
+    /// Get the provider, model settings, and prompts to use for an organization, taking into
+    /// account its overrides.
+    pub async fn for_organization(
+        &self,
+        db: &PgPool,
+        organization_id: OrganizationId,
+    ) -> Result<OrganizationLlm, Report<LlmError>> {
+        let row = sqlx::query!(
+            r#"SELECT
+                llm_settings AS "llm_settings: sqlx::types::Json<OrganizationLlmSettings>",
+                prompt_templates AS "prompt_templates: sqlx::types::Json<OrganizationPrompts>"
+            FROM organizations WHERE id = $1"#,
+            organization_id.as_uuid()
+        )
+        .fetch_optional(db)
+        .await
+        .change_context(LlmError::Db)?;
+
+        let (settings, prompt_overrides) = row
+            .map(|row| {
+                (
+                    row.llm_settings.map(|s| s.0).unwrap_or_default(),
+                    row.prompt_templates.map(|p| p.0).unwrap_or_default(),
+                )
+            })
+            .unwrap_or_default();
+
+        let (provider, settings) = self.resolve(settings)?;
+        Ok(OrganizationLlm {
+            provider,
+            settings,
+            prompt_overrides,
+        })
+    }
+
+    /// Check that an organization's settings describe a usable provider.
+    pub fn validate(&self, overrides: OrganizationLlmSettings) -> Result<(), Report<LlmError>> {
+        self.resolve(overrides).map(|_| ())
+    }
+
+    fn resolve(
+        &self,
+        overrides: OrganizationLlmSettings,
+    ) -> Result<(Arc<dyn LlmProvider>, ModelSettings), Report<LlmError>> {
+        let provider_kind = overrides.provider.unwrap_or(self.config.provider);
+        let same_provider = provider_kind == self.config.provider;
+        let has_own_key =
+            provider_kind == LlmProviderKind::Anthropic && self.anthropic_api_key.is_some();
+        if !same_provider && !has_own_key {
+            return Err(Report::new(LlmError::InvalidSettings(format!(
+                "the {provider_kind:?} provider is not available"
+            ))));
+        }
+
+        let model = match overrides.model {
+            Some(model) => model,
+            None if same_provider => self.default_settings.model.clone(),
+            None => provider_kind
+                .default_model()
+                .map(String::from)
+                .ok_or(LlmError::Config)
+                .attach_printable("Organization LLM settings must include a model")?,
+        };
+
+        let settings = ModelSettings {
+            model,
+            temperature: overrides
+                .temperature
+                .unwrap_or(self.default_settings.temperature),
+            max_tokens: overrides
+                .max_tokens
+                .unwrap_or(self.default_settings.max_tokens),
+            chunk_tokens: overrides
+                .chunk_tokens
+                .unwrap_or(self.default_settings.chunk_tokens),
+        };
+        check_ranges(&settings)?;
+
+        let provider = if same_provider {
+            self.default_provider.clone()
+        } else {
+            create_provider(
+                provider_kind,
+                provider_kind.default_api_url().to_string(),
+                self.http_client.clone(),
+                self.anthropic_api_key.clone(),
+                self.openai_api_key.clone(),
+            )?
+        };
+
+        Ok((provider, settings))
+    }
+}
+
+/// The smallest `chunk_tokens` allowed. Smaller chunks would split a transcript into a huge
+/// number of requests.
+const MIN_CHUNK_TOKENS: u32 = 1000;
+/// The largest `max_tokens` allowed, which is more than any summary needs.
+const MAX_OUTPUT_TOKENS: u32 = 16_384;
+
+/// Check that the model settings are in the ranges that the providers accept.
+fn check_ranges(settings: &ModelSettings) -> Result<(), Report<LlmError>> {
+    let error = |message: &str| Err(Report::new(LlmError::InvalidSettings(message.to_string())));
+
+    if !(0.0..=2.0).contains(&settings.temperature) {
+        return error("temperature must be between 0 and 2");
+    }
+    if !(1..=MAX_OUTPUT_TOKENS).contains(&settings.max_tokens) {
+        return error(&format!(
+            "max_tokens must be between 1 and {MAX_OUTPUT_TOKENS}"
+        ));
+    }
+    if settings.chunk_tokens < MIN_CHUNK_TOKENS {
+        return error(&format!("chunk_tokens must be at least {MIN_CHUNK_TOKENS}"));
+    }
+
+    Ok(())
+}
+
+fn create_provider(
+    kind: LlmProviderKind,
+    api_url: String,
+    http_client: reqwest::Client,
+    anthropic_api_key: Option<String>,
+    openai_api_key: Option<String>,
+) -> Result<Arc<dyn LlmProvider>, Report<LlmError>> {
+    let provider: Arc<dyn LlmProvider> = match kind {
+        LlmProviderKind::Anthropic => {
+            let api_key = anthropic_api_key
+                .ok_or(LlmError::Config)
+                .attach_printable("ANTHROPIC_API_KEY is required for the anthropic provider")?;
+            Arc::new(Anthropic::new(http_client, api_url, api_key))
+        }
+        LlmProviderKind::OpenAi => {
+            Arc::new(OpenAiCompatible::new(http_client, api_url, openai_api_key))
+        }
+        LlmProviderKind::Ollama => Arc::new(Ollama::new(http_client, api_url)),
+    };
+
+    Ok(provider)
+}
+
+/// Return an error if the response did not have a successful status code, with the response body
+/// attached for context.
+async fn check_response_status(
+    response: reqwest::Response,
+) -> Result<reqwest::Response, Report<LlmError>> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let body = response.text().await.unwrap_or_default();
+    Err(Report::new(LlmError::Status(status))).attach_printable(body)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn llm(config: LlmConfig) -> Llm {
+        let secrets = Secrets {
+            anthropic: Some("test-key".to_string()),
+            ..Secrets::empty()
+        };
+        Llm::new(config, &secrets, reqwest::Client::new()).unwrap()
+    }
+
+    #[test]
+    fn organization_overrides() {
+        let llm = llm(LlmConfig::default());
+
+        let (provider, settings) = llm.resolve(OrganizationLlmSettings::default()).unwrap();
+        assert_eq!(provider.name(), "anthropic");
+        assert_eq!(settings, llm.default_settings);
+
+        let (provider, settings) = llm
+            .resolve(OrganizationLlmSettings {
+                temperature: Some(0.0),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(provider.name(), "anthropic");
+        assert_eq!(settings.model, "claude-3-haiku-20240307");
+        assert_eq!(settings.temperature, 0.0);
+
+        let (provider, settings) = llm
+            .resolve(OrganizationLlmSettings {
+                provider: Some(LlmProviderKind::Anthropic),
+                model: Some("claude-3-5-sonnet-20240620".to_string()),
+                chunk_tokens: Some(4000),
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(Arc::ptr_eq(&provider, &llm.default_provider));
+        assert_eq!(settings.model, "claude-3-5-sonnet-20240620");
+        assert_eq!(settings.chunk_tokens, 4000);
+        assert_eq!(settings.max_tokens, 768);
+    }
+
+    #[test]
+    fn organization_can_not_set_api_url() {
+        let llm = llm(LlmConfig::default());
+        let settings: OrganizationLlmSettings = serde_json::from_value(serde_json::json!({
+            "api_url": "http://169.254.169.254/latest",
+        }))
+        .unwrap();
+
+        let (provider, _) = llm.resolve(settings).unwrap();
+        assert!(Arc::ptr_eq(&provider, &llm.default_provider));
+    }
+
+    #[test]
+    fn switching_to_ollama_requires_model() {
+        let llm = llm(LlmConfig::default());
+        let result = llm.resolve(OrganizationLlmSettings {
+            provider: Some(LlmProviderKind::Ollama),
+            ..Default::default()
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn organization_can_only_switch_to_providers_with_their_own_key() {
+        let llm = llm(LlmConfig {
+            provider: LlmProviderKind::OpenAi,
+            api_url: Some("http://llm.internal/v1".to_string()),
+            ..Default::default()
+        });
+
+        let (provider, _) = llm
+            .resolve(OrganizationLlmSettings {
+                provider: Some(LlmProviderKind::Anthropic),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(provider.name(), "anthropic");
+
+        let result = llm.resolve(OrganizationLlmSettings {
+            provider: Some(LlmProviderKind::Ollama),
+            model: Some("llama3".to_string()),
+            ..Default::default()
+        });
+        assert!(result.is_err());
+
+        let llm = self::llm(LlmConfig::default());
+        let result = llm.resolve(OrganizationLlmSettings {
+            provider: Some(LlmProviderKind::OpenAi),
+            ..Default::default()
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn organization_settings_ranges() {
+        let llm = llm(LlmConfig::default());
+        for settings in [
+            OrganizationLlmSettings {
+                temperature: Some(-0.5),
+                ..Default::default()
+            },
+            OrganizationLlmSettings {
+                temperature: Some(f32::NAN),
+                ..Default::default()
+            },
+            OrganizationLlmSettings {
+                max_tokens: Some(0),
+                ..Default::default()
+            },
+            OrganizationLlmSettings {
+                chunk_tokens: Some(0),
+                ..Default::default()
+            },
+        ] {
+            assert!(llm.validate(settings.clone()).is_err(), "{settings:?}");
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use async_trait::async_trait;
+use error_stack::{Report, ResultExt};
+use serde_json::json;
+
+use super::{check_response_status, CompletionRequest, CompletionResponse, LlmError, LlmProvider};
+
+/// A local or remote Ollama server, using its native chat API.
+pub struct Ollama {
+    http_client: reqwest::Client,
+    base_url: String,
+}
+
+impl Ollama {
+    pub fn new(http_client: reqwest::Client, base_url: String) -> Self {
+        Self {
+            http_client,
+            base_url,
+        }
+    }
+}
+
+#[async_trait]
+impl LlmProvider for Ollama {
+    fn name(&self) -> &'static str {
+        "ollama"
+    }
+
+    fn billed(&self) -> bool {
+        false
+    }
+
+    async fn complete(
+        &self,
+        request: CompletionRequest<'_>,
+    ) -> Result<CompletionResponse, Report<LlmError>> {
+        let mut messages = vec![
+            json!({ "role": "system", "content": request.system }),
+            json!({ "role": "user", "content": request.prompt }),
+        ];
+        // Ollama continues a trailing assistant message instead of starting a new one.
+        if let Some(prefix) = request.response_prefix {
+            messages.push(json!({ "role": "assistant", "content": prefix }));
+        }
+
+        let body = json!({
+            "model": request.settings.model,
+            "stream": false,
+            "messages": messages,
+            "options": {
+                "temperature": request.settings.temperature,
+                "num_predict": request.settings.max_tokens,
+            },
+        });
+
+        let response = self
+            .http_client
+            .post(format!("{}/api/chat", self.base_url.trim_end_matches('/')))
+            .json(&body)
+            // Local models can be slow, especially when they first have to load.
+            .timeout(std::time::Duration::from_secs(600))
+            .send()
+            .await
+            .change_context(LlmError::Request)?;
+
+        let result: serde_json::Value = check_response_status(response)
+            .await?
+            .json()
+            .await
+            .change_context(LlmError::Response)?;
+
+        let text = result["message"]["content"]
+            .as_str()
+            .ok_or(LlmError::Response)
+            .attach_printable("Failed to find text in response")?;
+
+        Ok(CompletionResponse {
+            text: text.to_string(),
+            model: result["model"]
+                .as_str()
+                .unwrap_or(&request.settings.model)
+                .to_string(),
+            input_tokens: result["prompt_eval_count"].as_u64().map(|t| t as u32),
+            output_tokens: result["eval_count"].as_u64().map(|t| t as u32),
+        })
+    }
+}
@@ -0,0 +1,78 @@
+use async_trait::async_trait;
+use error_stack::{Report, ResultExt};
+use serde_json::json;
+
+use super::{check_response_status, CompletionRequest, CompletionResponse, LlmError, LlmProvider};
+
+/// A server that implements the OpenAI `/chat/completions` endpoint, such as OpenAI itself,
+/// vLLM, or llama.cpp's server.
+pub struct OpenAiCompatible {
+    http_client: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+}
+
+impl OpenAiCompatible {
+    pub fn new(http_client: reqwest::Client, base_url: String, api_key: Option<String>) -> Self {
+        Self {
+            http_client,
+            base_url,
+            api_key,
+        }
+    }
+}
+
+#[async_trait]
+impl LlmProvider for OpenAiCompatible {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    async fn complete(
+        &self,
+        request: CompletionRequest<'_>,
+    ) -> Result<CompletionResponse, Report<LlmError>> {
+        // The chat completions API doesn't support prefilling the response, so response_prefix
+        // is ignored.
+        let body = json!({
+            "model": request.settings.model,
+            "max_tokens": request.settings.max_tokens,
+            "temperature": request.settings.temperature,
+            "messages": [
+                { "role": "system", "content": request.system },
+                { "role": "user", "content": request.prompt },
+            ],
+        });
+
+        let url = format!("{}/chat/completions", self.base_url.trim_end_matches('/'));
+        let mut builder = self.http_client.post(url).json(&body);
+        if let Some(api_key) = &self.api_key {
+            builder = builder.bearer_auth(api_key);
+        }
+
+        let response = builder.send().await.change_context(LlmError::Request)?;
+
+        let result: serde_json::Value = check_response_status(response)
+            .await?
+            .json()
+            .await
+            .change_context(LlmError::Response)?;
+
+        let text = result["choices"][0]["message"]["content"]
+            .as_str()
+            .ok_or(LlmError::Response)
+            .attach_printable("Failed to find text in response")?;
+
+        Ok(CompletionResponse {
+            text: text.to_string(),
+            model: result["model"]
+                .as_str()
+                .unwrap_or(&request.settings.model)
+                .to_string(),
+            input_tokens: result["usage"]["prompt_tokens"].as_u64().map(|t| t as u32),
+            output_tokens: result["usage"]["completion_tokens"]
+                .as_u64()
+                .map(|t| t as u32),
+        })
+    }
+}
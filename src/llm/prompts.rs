@@ -0,0 +1,450 @@
+//! Templates for the prompts sent to language models
+//!
+//! Each prompt is a Tera template. The built-in templates can be overridden for the whole
+//! deployment by placing a file with the same name in the configured template directory, and
+//! for a single organization by storing a template in `organizations.prompt_templates`.
+
+use std::{borrow::Cow, collections::HashMap, path::PathBuf};
+
+use error_stack::{Report, ResultExt};
+use rust_embed::RustEmbed;
+use serde::Serialize;
+use tera::ast::{Expr, ExprVal, Node};
+
+use crate::models::video::VideoChapter;
+
+#[derive(RustEmbed)]
+#[folder = "src/llm/prompts"]
+struct DefaultPrompts;
+
+#[derive(thiserror::Error, Debug)]
+pub enum PromptError {
+    #[error("Unknown prompt template {0}")]
+    UnknownTemplate(String),
+    #[error("Failed to read prompt template {0}")]
+    Read(&'static str),
+    #[error("Failed to render prompt template {0}")]
+    Render(&'static str),
+    #[error("Prompt templates can not call functions, but this one calls {0}")]
+    FunctionCall(String),
+}
+
+/// The prompts that can be customized
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PromptTemplate {
+    /// The system prompt when summarizing a whole transcript at once
+    SummarizeSystem,
+    /// The prompt when summarizing a whole transcript at once
+    Summarize,
+    /// The system prompt when summarizing one section of a transcript
+    ChunkSystem,
+    /// The prompt when summarizing one section of a transcript
+    Chunk,
+    /// The system prompt when combining section summaries into the final summary
+    CombineSystem,
+    /// The prompt when combining section summaries
+    Combine,
+}
+
+impl PromptTemplate {
+    pub const ALL: [PromptTemplate; 6] = [
+        PromptTemplate::SummarizeSystem,
+        PromptTemplate::Summarize,
+        PromptTemplate::ChunkSystem,
+        PromptTemplate::Chunk,
+        PromptTemplate::CombineSystem,
+        PromptTemplate::Combine,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PromptTemplate::SummarizeSystem => "summarize_system",
+            PromptTemplate::Summarize => "summarize",
+            PromptTemplate::ChunkSystem => "chunk_system",
+            PromptTemplate::Chunk => "chunk",
+            PromptTemplate::CombineSystem => "combine_system",
+            PromptTemplate::Combine => "combine",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<PromptTemplate> {
+        Self::ALL.into_iter().find(|t| t.name() == name)
+    }
+
+    fn filename(&self) -> String {
+        format!("{}.tera", self.name())
+    }
+
+    /// The built-in version of the template
+    pub fn default_source(&self) -> Cow<'static, str> {
+        let file = DefaultPrompts::get(&self.filename())
+            .expect("Built-in prompt templates should exist for every PromptTemplate");
+        match file.data {
+            Cow::Borrowed(data) => String::from_utf8_lossy(data),
+            Cow::Owned(data) => Cow::Owned(String::from_utf8_lossy(&data).into_owned()),
+        }
+    }
+}
+
+/// Information about a video that is available to prompt templates
+#[derive(Serialize, Debug, Default)]
+pub struct VideoPromptInfo {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    /// The duration of the video in seconds
+    pub duration: Option<i32>,
+    /// The duration formatted as "h:mm:ss"
+    pub duration_text: Option<String>,
+    pub description: Option<String>,
+    pub chapters: Vec<VideoChapter>,
+}
+
+/// A section summary passed to the `combine` template
+#[derive(Serialize, Debug)]
+pub struct PromptSection<'a> {
+    pub title: Option<&'a str>,
+    pub summary: &'a str,
+}
+
+/// The variables for rendering a prompt, in addition to the [VideoPromptInfo]
+#[derive(Serialize, Debug, Default)]
+pub struct PromptInput<'a> {
+    /// The transcript text, for the `summarize` and `chunk` templates
+    pub transcript: Option<&'a str>,
+    /// The title of the chapter being summarized, for the `chunk` template
+    pub chapter: Option<&'a str>,
+    /// The text of the slides shown during the transcript, for the `summarize` and `chunk`
+    /// templates
+    pub slides: Vec<&'a str>,
+    /// The summaries to combine, for the `combine` template
+    pub sections: Vec<PromptSection<'a>>,
+}
+
+/// Per-organization template overrides, keyed by template name
+pub type OrganizationPrompts = HashMap<String, String>;
+
+/// Renders prompt templates
+pub struct Prompts {
+    template_dir: Option<PathBuf>,
+}
+
+impl Prompts {
+    pub fn new(template_dir: Option<PathBuf>) -> Self {
+        Self { template_dir }
+    }
+
+    /// Render a prompt, using the organization's version of the template if it has one.
+    pub fn render(
+        &self,
+        template: PromptTemplate,
+        overrides: &OrganizationPrompts,
+        video: &VideoPromptInfo,
+        input: &PromptInput,
+    ) -> Result<String, Report<PromptError>> {
+        let source = self.source(template, overrides)?;
+        render_template(template, &source, video, input)
+    }
+
+    fn source<'a>(
+        &self,
+        template: PromptTemplate,
+        overrides: &'a OrganizationPrompts,
+    ) -> Result<Cow<'a, str>, Report<PromptError>> {
+        if let Some(source) = overrides.get(template.name()) {
+            return Ok(Cow::Borrowed(source));
+        }
+
+        // Templates in the directory are read each time, so they can be edited without restarting.
+        if let Some(dir) = &self.template_dir {
+            let path = dir.join(template.filename());
+            if path.exists() {
+                let source = std::fs::read_to_string(&path)
+                    .change_context(PromptError::Read(template.name()))
+                    .attach_printable_lazy(|| path.display().to_string())?;
+                return Ok(Cow::Owned(source));
+            }
+        }
+
+        Ok(template.default_source())
+    }
+}
+
+fn render_template(
+    template: PromptTemplate,
+    source: &str,
+    video: &VideoPromptInfo,
+    input: &PromptInput,
+) -> Result<String, Report<PromptError>> {
+    let mut context = tera::Context::from_serialize(video)
+        .change_context(PromptError::Render(template.name()))?;
+    context.extend(
+        tera::Context::from_serialize(input)
+            .change_context(PromptError::Render(template.name()))?,
+    );
+
+    let output = template_engine(source)
+        .and_then(|tera| tera.render(TEMPLATE_NAME, &context))
+        .change_context(PromptError::Render(template.name()))?;
+    Ok(output.trim().to_string())
+}
+
+/// The name of the template in the Tera instance created by [template_engine]
+const TEMPLATE_NAME: &str = "prompt";
+
+/// Create a Tera instance that contains only the given template. Organization admins can edit
+/// templates, so `get_env` is replaced with a function that always fails, to keep templates
+/// from reading the server's environment.
+fn template_engine(source: &str) -> Result<tera::Tera, tera::Error> {
+    let mut tera = tera::Tera::default();
+    tera.autoescape_on(vec![]);
+    tera.register_function("get_env", disabled_function);
+    tera.add_raw_template(TEMPLATE_NAME, source)?;
+    Ok(tera)
+}
+
+fn disabled_function(_: &HashMap<String, tera::Value>) -> tera::Result<tera::Value> {
+    Err(tera::Error::msg(
+        "This function is not available in prompt templates",
+    ))
+}
+
+/// Find the first function call in a template's nodes.
+fn find_function_call(nodes: &[Node]) -> Option<&str> {
+    nodes.iter().find_map(|node| match node {
+        Node::VariableBlock(_, expr) => expr_function_call(expr),
+        Node::Set(_, set) => expr_function_call(&set.value),
+        Node::MacroDefinition(_, def, _) => def
+            .args
+            .values()
+            .flatten()
+            .find_map(expr_function_call)
+            .or_else(|| find_function_call(&def.body)),
+        Node::FilterSection(_, section, _) => section
+            .filter
+            .args
+            .values()
+            .find_map(expr_function_call)
+            .or_else(|| find_function_call(&section.body)),
+        Node::Block(_, block, _) => find_function_call(&block.body),
+        Node::Forloop(_, forloop, _) => expr_function_call(&forloop.container)
+            .or_else(|| find_function_call(&forloop.body))
+            .or_else(|| forloop.empty_body.as_deref().and_then(find_function_call)),
+        Node::If(if_node, _) => if_node
+            .conditions
+            .iter()
+            .find_map(|(_, expr, body)| {
+                expr_function_call(expr).or_else(|| find_function_call(body))
+            })
+            .or_else(|| {
+                if_node
+                    .otherwise
+                    .as_ref()
+                    .and_then(|(_, body)| find_function_call(body))
+            }),
+        _ => None,
+    })
+}
+
+fn expr_function_call(expr: &Expr) -> Option<&str> {
+    let in_filters = || {
+        expr.filters
+            .iter()
+            .flat_map(|filter| filter.args.values())
+            .find_map(expr_function_call)
+    };
+
+    let in_value = match &expr.val {
+        ExprVal::FunctionCall(call) => return Some(&call.name),
+        ExprVal::Math(math) => {
+            expr_function_call(&math.lhs).or_else(|| expr_function_call(&math.rhs))
+        }
+        ExprVal::Logic(logic) => {
+            expr_function_call(&logic.lhs).or_else(|| expr_function_call(&logic.rhs))
+        }
+        ExprVal::In(in_expr) => {
+            expr_function_call(&in_expr.lhs).or_else(|| expr_function_call(&in_expr.rhs))
+        }
+        ExprVal::Test(test) => test.args.iter().find_map(expr_function_call),
+        ExprVal::MacroCall(call) => call.args.values().find_map(expr_function_call),
+        ExprVal::Array(items) => items.iter().find_map(expr_function_call),
+        ExprVal::StringConcat(concat) => concat.values.iter().find_map(|val| match val {
+            ExprVal::FunctionCall(call) => Some(call.name.as_str()),
+            _ => None,
+        }),
+        _ => None,
+    };
+
+    in_value.or_else(in_filters)
+}
+
+/// Check that a template with the given name exists, that it doesn't call any functions, and
+/// that the source renders successfully with some sample data.
+pub fn validate_template(name: &str, source: &str) -> Result<(), Report<PromptError>> {
+    let template = PromptTemplate::from_name(name)
+        .ok_or_else(|| PromptError::UnknownTemplate(name.to_string()))?;
+
+    let tera = template_engine(source).change_context(PromptError::Render(template.name()))?;
+    let parsed = tera
+        .get_template(TEMPLATE_NAME)
+        .change_context(PromptError::Render(template.name()))?;
+    if let Some(function) = find_function_call(&parsed.ast) {
+        return Err(Report::new(PromptError::FunctionCall(function.to_string())));
+    }
+
+    let video = VideoPromptInfo {
+        title: Some("A Video".to_string()),
+        author: Some("Someone".to_string()),
+        duration: Some(600),
+        duration_text: Some("10:00".to_string()),
+        description: Some("A description".to_string()),
+        chapters: vec![VideoChapter {
+            start_time: 0.0,
+            end_time: 600.0,
+            title: "Introduction".to_string(),
+        }],
+    };
+    let input = PromptInput {
+        transcript: Some("Hello."),
+        chapter: Some("Introduction"),
+        slides: vec!["Welcome\nto the talk"],
+        sections: vec![PromptSection {
+            title: Some("Introduction"),
+            summary: "A greeting.",
+        }],
+    };
+
+    render_template(template, source, &video, &input)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn default_templates_render() {
+        for template in PromptTemplate::ALL {
+            validate_template(template.name(), &template.default_source()).unwrap();
+        }
+    }
+
+    #[test]
+    fn summarize_prompt() {
+        let prompts = Prompts::new(None);
+        let video = VideoPromptInfo {
+            title: Some("Rust in Production".to_string()),
+            author: Some("Someone".to_string()),
+            duration: Some(3723),
+            duration_text: Some("1:02:03".to_string()),
+            ..Default::default()
+        };
+        let input = PromptInput {
+            transcript: Some("The transcript."),
+            ..Default::default()
+        };
+
+        let prompt = prompts
+            .render(
+                PromptTemplate::Summarize,
+                &OrganizationPrompts::new(),
+                &video,
+                &input,
+            )
+            .unwrap();
+        assert_eq!(
+            prompt,
+            "The video is titled \"Rust in Production\" and was created by Someone. It is 1:02:03 long.\n\nThe video transcript follows:\n\nThe transcript."
+        );
+    }
+
+    #[test]
+    fn chunk_prompt_with_slides() {
+        let prompts = Prompts::new(None);
+        let video = VideoPromptInfo {
+            title: Some("Rust in Production".to_string()),
+            ..Default::default()
+        };
+        let input = PromptInput {
+            transcript: Some("The transcript."),
+            slides: vec!["Why Rust?\nMemory safety", "Questions"],
+            ..Default::default()
+        };
+
+        let prompt = prompts
+            .render(
+                PromptTemplate::Chunk,
+                &OrganizationPrompts::new(),
+                &video,
+                &input,
+            )
+            .unwrap();
+        assert_eq!(
+            prompt,
+            "The video is titled \"Rust in Production\".\n\nText from the slides shown in this section:\n- Why Rust? / Memory safety\n- Questions\n\nThis section of the video transcript follows:\n\nThe transcript."
+        );
+    }
+
+    #[test]
+    fn organization_override() {
+        let prompts = Prompts::new(None);
+        let overrides = OrganizationPrompts::from([(
+            "summarize".to_string(),
+            "Summarize {{ title }}: {{ transcript }}".to_string(),
+        )]);
+        let video = VideoPromptInfo {
+            title: Some("Talk".to_string()),
+            ..Default::default()
+        };
+        let input = PromptInput {
+            transcript: Some("words"),
+            ..Default::default()
+        };
+
+        let prompt = prompts
+            .render(PromptTemplate::Summarize, &overrides, &video, &input)
+            .unwrap();
+        assert_eq!(prompt, "Summarize Talk: words");
+    }
+
+    #[test]
+    fn reject_invalid_templates() {
+        assert!(validate_template("summarize", "{{ transcript").is_err());
+        assert!(validate_template("not_a_template", "Hello").is_err());
+    }
+
+    #[test]
+    fn reject_function_calls() {
+        for source in [
+            "{{ get_env(name=\"HOME\") }}",
+            "{% set home = get_env(name=\"HOME\") %}{{ home }}",
+            "{% if false %}{{ now() }}{% endif %}",
+            "{% for i in range(end=3) %}{{ i }}{% endfor %}",
+            "{{ title | default(value=get_env(name=\"HOME\")) }}",
+        ] {
+            let err = validate_template("summarize", source).unwrap_err();
+            assert!(
+                matches!(err.current_context(), PromptError::FunctionCall(_)),
+                "{source}"
+            );
+        }
+
+        assert!(validate_template("summarize", "{{ title | upper }}").is_ok());
+    }
+
+    #[test]
+    fn get_env_does_not_render() {
+        let prompts = Prompts::new(None);
+        let overrides = OrganizationPrompts::from([(
+            "summarize".to_string(),
+            "{{ get_env(name=\"PATH\") }}".to_string(),
+        )]);
+
+        let result = prompts.render(
+            PromptTemplate::Summarize,
+            &overrides,
+            &VideoPromptInfo::default(),
+            &PromptInput::default(),
+        );
+        assert!(result.is_err());
+    }
+}
@@ -19,7 +19,7 @@ enum Command {
     Util(cmd::util::UtilCommand),
 
     Db(cmd::db::DbCommand),
-    Serve(ServeCommand),
+    Serve(Box<ServeCommand>),
 }
 
 #[derive(Args, Debug)]
@@ -61,6 +61,10 @@ struct ServeCommand {
     #[clap(long, env = "REQUEST_TIMEOUT", default_value_t = 60)]
     request_timeout: u64,
 
+    /// Timeout for media file uploads, in seconds
+    #[clap(long, env = "UPLOAD_TIMEOUT", default_value_t = 4 * 60 * 60)]
+    upload_timeout: u64,
+
     #[clap(long, env = "COOKIE_SAME_SITE", value_enum, default_value_t = SameSiteArg::Strict)]
     cookie_same_site: SameSiteArg,
 
@@ -131,6 +135,30 @@ struct ServeCommand {
     /// The location to store the queue database
     #[clap(long, env = "QUEUE_PATH", default_value_t = String::from("queue.db"))]
     queue_path: String,
+
+    #[clap(flatten)]
+    transcription: sbbp::transcription::TranscriptionConfig,
+
+    #[clap(flatten)]
+    llm: sbbp::llm::LlmConfig,
+
+    #[clap(flatten)]
+    download: sbbp::jobs::download::DownloadConfig,
+
+    #[clap(flatten)]
+    pricing: sbbp::models::usage::PricingConfig,
+
+    #[clap(flatten)]
+    subtitles: sbbp::jobs::download::SubtitlesConfig,
+
+    #[clap(flatten)]
+    image_extraction: sbbp::jobs::extract::ImageExtractionConfig,
+
+    #[clap(flatten)]
+    analyze: sbbp::jobs::analyze::AnalyzeConfig,
+
+    #[clap(flatten)]
+    ocr: sbbp::jobs::ocr::OcrConfig,
 }
 
 async fn serve(cmd: ServeCommand) -> Result<(), Report<Error>> {
@@ -212,6 +240,7 @@ async fn serve(cmd: ServeCommand) -> Result<(), Report<Error>> {
         },
         insecure: cmd.insecure,
         request_timeout: std::time::Duration::from_secs(cmd.request_timeout),
+        upload_timeout: std::time::Duration::from_secs(cmd.upload_timeout),
         cookie_configuration: SessionCookieBuilder::new(secure_cookies, cmd.cookie_same_site),
         session_expiry: filigree::auth::ExpiryStyle::AfterIdle(std::time::Duration::from_secs(
             cmd.session_expiry * 24 * 60 * 60,
@@ -237,6 +266,14 @@ async fn serve(cmd: ServeCommand) -> Result<(), Report<Error>> {
         queue_path: std::path::PathBuf::from(cmd.queue_path),
         init_recurring_jobs: true,
         storage: sbbp::storage::AppStorageConfig::new().change_context(Error::ServerStart)?,
+        transcription: cmd.transcription,
+        llm: cmd.llm,
+        download: cmd.download,
+        pricing: cmd.pricing,
+        subtitles: cmd.subtitles,
+        image_extraction: cmd.image_extraction,
+        analyze: cmd.analyze,
+        ocr: cmd.ocr,
     })
     .await?;
 
@@ -286,7 +323,7 @@ pub async fn actual_main() -> Result<(), Report<Error>> {
 
     match cli.command {
         Command::Db(cmd) => cmd.handle().await?,
-        Command::Serve(cmd) => serve(cmd).await?,
+        Command::Serve(cmd) => serve(*cmd).await?,
 
         Command::Util(cmd) => cmd.handle().await?,
     }
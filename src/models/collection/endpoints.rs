@@ -0,0 +1,70 @@
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    routing,
+};
+use axum_jsonschema::Json;
+use filigree::extract::FormOrJson;
+use schemars::JsonSchema;
+
+use super::CollectionId;
+use crate::{
+    auth::{has_any_permission, Authed},
+    models::video::{CREATE_PERMISSION, READ_PERMISSION},
+    server::ServerState,
+    Error,
+};
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, JsonSchema)]
+pub struct CreateCollectionPayload {
+    /// A playlist or channel URL
+    pub url: String,
+    /// The name of the collection. When omitted, the playlist's title is used.
+    pub name: Option<String>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, JsonSchema)]
+pub struct CreateCollectionResponse {
+    pub id: CollectionId,
+}
+
+async fn create(
+    State(state): State<ServerState>,
+    auth: Authed,
+    FormOrJson(payload): FormOrJson<CreateCollectionPayload>,
+) -> Result<impl IntoResponse, Error> {
+    let id = super::create_via_url(&state, &auth, &payload.url, payload.name.as_deref()).await?;
+    Ok(Json(CreateCollectionResponse { id }))
+}
+
+async fn list(State(state): State<ServerState>, auth: Authed) -> Result<impl IntoResponse, Error> {
+    let collections = super::list(&state, &auth).await?;
+    Ok(Json(collections))
+}
+
+async fn get(
+    State(state): State<ServerState>,
+    auth: Authed,
+    Path(id): Path<CollectionId>,
+) -> Result<impl IntoResponse, Error> {
+    let collection = super::get(&state, &auth, id).await?;
+    Ok(Json(collection))
+}
+
+/// Collections are groups of videos, so access to them uses the video permissions.
+pub fn create_routes() -> axum::Router<ServerState> {
+    axum::Router::new()
+        .route(
+            "/collections",
+            routing::get(list).route_layer(has_any_permission(vec![READ_PERMISSION, "org_admin"])),
+        )
+        .route(
+            "/collections",
+            routing::post(create)
+                .route_layer(has_any_permission(vec![CREATE_PERMISSION, "org_admin"])),
+        )
+        .route(
+            "/collections/:id",
+            routing::get(get).route_layer(has_any_permission(vec![READ_PERMISSION, "org_admin"])),
+        )
+}
@@ -0,0 +1,136 @@
+//! Collections group the videos that were added together from a playlist or channel, so that
+//! the batch can be tracked as a whole.
+
+pub mod endpoints;
+
+use error_stack::{Report, ResultExt};
+use serde::Serialize;
+
+use crate::{
+    auth::Authed, jobs::playlist::PlaylistJobPayload, models::video::VideoProcessingState,
+    server::ServerState, Error,
+};
+
+filigree::make_object_id!(CollectionId, col);
+
+/// A collection, along with the processing status of its videos
+#[derive(Serialize, Debug, Clone, schemars::JsonSchema)]
+pub struct Collection {
+    pub id: CollectionId,
+    /// The name of the collection. This is filled in from the playlist's title if no name was
+    /// given when it was created.
+    pub name: Option<String>,
+    pub url: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    /// The number of videos in the collection
+    pub videos: i64,
+    /// The number of videos which have finished processing
+    pub ready: i64,
+    /// The number of videos which failed to process
+    pub failed: i64,
+    /// The error from reading the playlist, if it failed
+    pub last_error: Option<String>,
+}
+
+impl Collection {
+    /// The name to show for the collection
+    pub fn display_name(&self) -> &str {
+        self.name
+            .as_deref()
+            .or(self.url.as_deref())
+            .unwrap_or("Untitled collection")
+    }
+}
+
+/// Create a collection from a playlist or channel URL. The playlist is read in the background,
+/// which adds a video for each entry that the organization doesn't already have, and adds the
+/// videos it does have to the collection if they aren't in another one.
+pub async fn create_via_url(
+    state: &ServerState,
+    auth: &Authed,
+    url: &str,
+    name: Option<&str>,
+) -> Result<CollectionId, Report<Error>> {
+    let id = CollectionId::new();
+    let name = name.map(|n| n.trim()).filter(|n| !n.is_empty());
+
+    sqlx::query!(
+        "INSERT INTO collections (id, organization_id, name, url) VALUES ($1, $2, $3, $4)",
+        id.as_uuid(),
+        auth.organization_id.as_uuid(),
+        name,
+        url
+    )
+    .execute(&state.db)
+    .await
+    .change_context(Error::Db)?;
+
+    crate::jobs::playlist::enqueue(
+        state,
+        id,
+        &PlaylistJobPayload {
+            collection_id: id,
+            organization_id: auth.organization_id,
+            url: url.to_string(),
+        },
+    )
+    .await
+    .change_context(Error::TaskQueue)
+    .attach_printable("Failed to enqueue playlist job")?;
+
+    Ok(id)
+}
+
+/// List the organization's collections, newest first.
+pub async fn list(state: &ServerState, auth: &Authed) -> Result<Vec<Collection>, Report<Error>> {
+    let collections = sqlx::query_as!(
+        Collection,
+        r#"SELECT c.id AS "id: CollectionId", c.name, c.url, c.created_at,
+            COUNT(v.id) AS "videos!",
+            COUNT(v.id) FILTER (WHERE v.processing_state = $2) AS "ready!",
+            COUNT(v.id) FILTER (WHERE v.processing_state = $3) AS "failed!",
+            c.last_error
+        FROM collections c
+        LEFT JOIN videos v ON v.collection_id = c.id
+        WHERE c.organization_id = $1
+        GROUP BY c.id
+        ORDER BY c.created_at DESC"#,
+        auth.organization_id.as_uuid(),
+        VideoProcessingState::Ready as _,
+        VideoProcessingState::Failed as _,
+    )
+    .fetch_all(&state.db)
+    .await
+    .change_context(Error::Db)?;
+
+    Ok(collections)
+}
+
+/// Fetch a single collection
+pub async fn get(
+    state: &ServerState,
+    auth: &Authed,
+    id: CollectionId,
+) -> Result<Collection, Report<Error>> {
+    sqlx::query_as!(
+        Collection,
+        r#"SELECT c.id AS "id: CollectionId", c.name, c.url, c.created_at,
+            COUNT(v.id) AS "videos!",
+            COUNT(v.id) FILTER (WHERE v.processing_state = $3) AS "ready!",
+            COUNT(v.id) FILTER (WHERE v.processing_state = $4) AS "failed!",
+            c.last_error
+        FROM collections c
+        LEFT JOIN videos v ON v.collection_id = c.id
+        WHERE c.id = $1 AND c.organization_id = $2
+        GROUP BY c.id"#,
+        id.as_uuid(),
+        auth.organization_id.as_uuid(),
+        VideoProcessingState::Ready as _,
+        VideoProcessingState::Failed as _,
+    )
+    .fetch_optional(&state.db)
+    .await
+    .change_context(Error::Db)?
+    .ok_or(Error::NotFound("Collection"))
+    .map_err(Report::new)
+}
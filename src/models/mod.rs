@@ -1,5 +1,8 @@
+pub mod collection;
 pub mod organization;
 pub mod role;
+pub mod subscription;
+pub mod usage;
 pub mod user;
 pub mod video;
 
@@ -9,7 +12,10 @@ use crate::server::ServerState;
 
 pub fn create_routes() -> Router<ServerState> {
     Router::new()
+        .merge(collection::endpoints::create_routes())
         .merge(role::endpoints::create_routes())
+        .merge(subscription::endpoints::create_routes())
+        .merge(usage::endpoints::create_routes())
         .merge(user::endpoints::create_routes())
         .merge(video::endpoints::create_routes())
 }
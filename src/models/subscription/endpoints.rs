@@ -0,0 +1,79 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing,
+};
+use axum_jsonschema::Json;
+use schemars::JsonSchema;
+
+use super::{SubscriptionFilters, SubscriptionId};
+use crate::{
+    auth::{has_any_permission, Authed},
+    models::video::{CREATE_PERMISSION, READ_PERMISSION},
+    server::ServerState,
+    Error,
+};
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, JsonSchema)]
+pub struct CreateSubscriptionPayload {
+    /// A YouTube channel or playlist, or an RSS or Atom feed
+    pub url: String,
+    pub name: Option<String>,
+    #[serde(default)]
+    pub filters: SubscriptionFilters,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, JsonSchema)]
+pub struct CreateSubscriptionResponse {
+    pub id: SubscriptionId,
+}
+
+async fn create(
+    State(state): State<ServerState>,
+    auth: Authed,
+    Json(payload): Json<CreateSubscriptionPayload>,
+) -> Result<impl IntoResponse, Error> {
+    let id = super::create(
+        &state,
+        &auth,
+        &payload.url,
+        payload.name.as_deref(),
+        payload.filters,
+    )
+    .await?;
+    Ok(Json(CreateSubscriptionResponse { id }))
+}
+
+async fn list(State(state): State<ServerState>, auth: Authed) -> Result<impl IntoResponse, Error> {
+    let subscriptions = super::list(&state, &auth).await?;
+    Ok(Json(subscriptions))
+}
+
+async fn delete(
+    State(state): State<ServerState>,
+    auth: Authed,
+    Path(id): Path<SubscriptionId>,
+) -> Result<impl IntoResponse, Error> {
+    super::delete(&state, &auth, id).await?;
+    Ok(StatusCode::OK)
+}
+
+/// Subscriptions add videos, so access to them uses the video permissions.
+pub fn create_routes() -> axum::Router<ServerState> {
+    axum::Router::new()
+        .route(
+            "/subscriptions",
+            routing::get(list).route_layer(has_any_permission(vec![READ_PERMISSION, "org_admin"])),
+        )
+        .route(
+            "/subscriptions",
+            routing::post(create)
+                .route_layer(has_any_permission(vec![CREATE_PERMISSION, "org_admin"])),
+        )
+        .route(
+            "/subscriptions/:id",
+            routing::delete(delete)
+                .route_layer(has_any_permission(vec![CREATE_PERMISSION, "org_admin"])),
+        )
+}
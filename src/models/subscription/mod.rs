@@ -0,0 +1,221 @@
+//! Subscriptions to YouTube channels, playlists, and RSS or Atom feeds. New items are added as
+//! videos by the `poll_subscriptions` job.
+
+pub mod endpoints;
+
+use error_stack::{Report, ResultExt};
+use serde::{Deserialize, Serialize};
+use sqlx_transparent_json_decode::sqlx_json_decode;
+
+use crate::{
+    auth::Authed, jobs::subscriptions::PollSubscriptionsPayload, server::ServerState, Error,
+};
+
+filigree::make_object_id!(SubscriptionId, sub);
+
+/// Rules for which new items of a subscription are added
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, schemars::JsonSchema)]
+pub struct SubscriptionFilters {
+    /// Skip items shorter than this many seconds
+    pub min_duration: Option<u32>,
+    /// Skip items longer than this many seconds
+    pub max_duration: Option<u32>,
+    /// When not empty, only add items with a title containing one of these keywords
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Skip items with a title containing any of these keywords
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+sqlx_json_decode!(SubscriptionFilters);
+
+impl SubscriptionFilters {
+    /// Check if an item passes the filters. Keywords match case-insensitively, and items without
+    /// a known duration are not filtered by duration.
+    pub fn matches(&self, title: Option<&str>, duration: Option<f64>) -> bool {
+        if let Some(duration) = duration {
+            if self.min_duration.is_some_and(|min| duration < min as f64)
+                || self.max_duration.is_some_and(|max| duration > max as f64)
+            {
+                return false;
+            }
+        }
+
+        let title = title.unwrap_or_default().to_lowercase();
+        let contains = |keyword: &String| title.contains(&keyword.to_lowercase());
+        (self.include.is_empty() || self.include.iter().any(contains))
+            && !self.exclude.iter().any(contains)
+    }
+
+    /// Remove blank keywords and check that the settings make sense.
+    fn validate(mut self) -> Result<Self, Error> {
+        for keywords in [&mut self.include, &mut self.exclude] {
+            *keywords = keywords
+                .iter()
+                .map(|k| k.trim().to_string())
+                .filter(|k| !k.is_empty())
+                .collect();
+        }
+
+        if let (Some(min), Some(max)) = (self.min_duration, self.max_duration) {
+            if min > max {
+                return Err(Error::InvalidSettings(
+                    "The minimum duration must not be longer than the maximum duration".to_string(),
+                ));
+            }
+        }
+
+        Ok(self)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, schemars::JsonSchema)]
+pub struct Subscription {
+    pub id: SubscriptionId,
+    pub name: Option<String>,
+    pub url: String,
+    pub filters: SubscriptionFilters,
+    /// The newest item seen the last time the subscription was checked
+    pub last_seen_url: Option<String>,
+    pub last_checked_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// The error from the last check, if it failed
+    pub last_error: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl Subscription {
+    /// The name to show for the subscription
+    pub fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.url)
+    }
+}
+
+/// Subscribe the organization to a channel, playlist, or feed. The subscription is checked
+/// right away to record its newest item, and only items published after that are added.
+pub async fn create(
+    state: &ServerState,
+    auth: &Authed,
+    url: &str,
+    name: Option<&str>,
+    filters: SubscriptionFilters,
+) -> Result<SubscriptionId, Report<Error>> {
+    let url = url::Url::parse(url.trim())
+        .map_err(|_| Error::InvalidSettings("The subscription URL is not valid".to_string()))?;
+    let filters = filters.validate()?;
+    let name = name.map(|n| n.trim()).filter(|n| !n.is_empty());
+
+    let id = SubscriptionId::new();
+    sqlx::query!(
+        "INSERT INTO subscriptions (id, organization_id, url, name, filters)
+        VALUES ($1, $2, $3, $4, $5)",
+        id.as_uuid(),
+        auth.organization_id.as_uuid(),
+        url.as_str(),
+        name,
+        sqlx::types::Json(&filters) as _
+    )
+    .execute(&state.db)
+    .await
+    .change_context(Error::Db)?;
+
+    crate::jobs::subscriptions::enqueue(state, id, &PollSubscriptionsPayload { id: Some(id) })
+        .await
+        .change_context(Error::TaskQueue)
+        .attach_printable("Failed to enqueue subscription check")?;
+
+    Ok(id)
+}
+
+/// List the organization's subscriptions
+pub async fn list(state: &ServerState, auth: &Authed) -> Result<Vec<Subscription>, Report<Error>> {
+    let subscriptions = sqlx::query_as!(
+        Subscription,
+        r#"SELECT id AS "id: SubscriptionId", name, url,
+            filters AS "filters: SubscriptionFilters",
+            last_seen_url, last_checked_at, last_error, created_at
+        FROM subscriptions
+        WHERE organization_id = $1
+        ORDER BY created_at"#,
+        auth.organization_id.as_uuid(),
+    )
+    .fetch_all(&state.db)
+    .await
+    .change_context(Error::Db)?;
+
+    Ok(subscriptions)
+}
+
+/// Remove a subscription. Videos that were already added from it are kept.
+pub async fn delete(
+    state: &ServerState,
+    auth: &Authed,
+    id: SubscriptionId,
+) -> Result<(), Report<Error>> {
+    let result = sqlx::query!(
+        "DELETE FROM subscriptions WHERE id = $1 AND organization_id = $2",
+        id.as_uuid(),
+        auth.organization_id.as_uuid(),
+    )
+    .execute(&state.db)
+    .await
+    .change_context(Error::Db)?;
+
+    if result.rows_affected() == 0 {
+        return Err(Report::new(Error::NotFound("Subscription")));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn filter_by_duration() {
+        let filters = SubscriptionFilters {
+            min_duration: Some(600),
+            max_duration: Some(3600),
+            ..Default::default()
+        };
+
+        assert!(filters.matches(Some("Talk"), Some(1200.0)));
+        assert!(!filters.matches(Some("Short"), Some(59.0)));
+        assert!(!filters.matches(Some("Livestream"), Some(4.0 * 3600.0)));
+        assert!(filters.matches(Some("Unknown length"), None));
+    }
+
+    #[test]
+    fn filter_by_keywords() {
+        let filters = SubscriptionFilters {
+            include: vec!["rust".to_string(), "Wasm".to_string()],
+            exclude: vec!["#shorts".to_string()],
+            ..Default::default()
+        };
+
+        assert!(filters.matches(Some("Intro to Rust"), None));
+        assert!(filters.matches(Some("WASM in the browser"), None));
+        assert!(!filters.matches(Some("Rust in 60 seconds #shorts"), None));
+        assert!(!filters.matches(Some("Go generics"), None));
+        assert!(!filters.matches(None, None));
+    }
+
+    #[test]
+    fn validate_filters() {
+        let filters = SubscriptionFilters {
+            include: vec![" rust ".to_string(), "  ".to_string()],
+            ..Default::default()
+        }
+        .validate()
+        .unwrap();
+        assert_eq!(filters.include, vec!["rust"]);
+
+        let filters = SubscriptionFilters {
+            min_duration: Some(600),
+            max_duration: Some(60),
+            ..Default::default()
+        };
+        assert!(filters.validate().is_err());
+    }
+}
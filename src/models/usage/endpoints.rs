@@ -0,0 +1,42 @@
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+    routing,
+};
+use axum_jsonschema::Json;
+use schemars::JsonSchema;
+
+use super::UsagePeriod;
+use crate::{
+    auth::{has_any_permission, Authed},
+    server::ServerState,
+    Error,
+};
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, JsonSchema)]
+pub struct UsageReportQuery {
+    /// Whether to sum the usage for each day or each month
+    #[serde(default)]
+    pub period: UsagePeriod,
+    /// The first day to include
+    pub start: Option<chrono::NaiveDate>,
+    /// The last day to include
+    pub end: Option<chrono::NaiveDate>,
+}
+
+async fn report(
+    State(state): State<ServerState>,
+    auth: Authed,
+    Query(query): Query<UsageReportQuery>,
+) -> Result<impl IntoResponse, Error> {
+    let report = super::report(&state, &auth, query.period, query.start, query.end).await?;
+    Ok(Json(report))
+}
+
+/// Usage covers the whole organization, so only admins can see it.
+pub fn create_routes() -> axum::Router<ServerState> {
+    axum::Router::new().route(
+        "/usage",
+        routing::get(report).route_layer(has_any_permission(vec!["org_admin"])),
+    )
+}
@@ -0,0 +1,346 @@
+//! Usage and cost accounting for the paid services used to process videos
+//!
+//! Each run of a stage that uses a transcription or language model service records a
+//! [StageUsage] in the video's metadata, with its cost estimated from the configured prices.
+//! The usage report sums these for an organization. Usage is stored with the video, so it is
+//! removed from the report when the video is deleted.
+
+pub mod endpoints;
+
+use std::str::FromStr;
+
+use error_stack::{Report, ResultExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::Authed,
+    jobs::pipeline::Stage,
+    llm::{CompletionResponse, LlmProvider},
+    models::video::StageUsage,
+    server::ServerState,
+    transcription::TranscriptionProvider,
+    Error,
+};
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct PricingConfig {
+    /// The price of transcription per minute of audio, in US dollars. The default is Deepgram's
+    /// pay-as-you-go price for nova-2.
+    #[clap(
+        long = "transcription-price-per-minute",
+        env = "TRANSCRIPTION_PRICE_PER_MINUTE",
+        default_value_t = 0.0043
+    )]
+    pub transcription_per_minute: f64,
+
+    /// The prices of language models, as a comma-separated list of
+    /// `provider/model=input:output`, where `input` and `output` are the prices of a million
+    /// tokens in US dollars. Usage of a model that isn't listed is recorded without a cost. The
+    /// defaults cover the default Anthropic and OpenAI models.
+    #[clap(
+        long = "llm-prices",
+        env = "LLM_PRICES",
+        value_delimiter = ',',
+        default_value = DEFAULT_LLM_PRICES
+    )]
+    pub llm_prices: Vec<ModelPrice>,
+}
+
+const DEFAULT_LLM_PRICES: &str =
+    "anthropic/claude-3-haiku-20240307=0.25:1.25,openai/gpt-4o-mini=0.15:0.6";
+
+impl Default for PricingConfig {
+    fn default() -> Self {
+        Self {
+            transcription_per_minute: 0.0043,
+            llm_prices: DEFAULT_LLM_PRICES
+                .split(',')
+                .map(|price| price.parse().unwrap())
+                .collect(),
+        }
+    }
+}
+
+/// The price of a language model, in US dollars per million tokens
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelPrice {
+    /// The provider's name, such as "anthropic" or "openai"
+    pub provider: String,
+    pub model: String,
+    pub input_per_million: f64,
+    pub output_per_million: f64,
+}
+
+impl FromStr for ModelPrice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = || {
+            let (name, prices) = s.split_once('=')?;
+            let (provider, model) = name.split_once('/')?;
+            let (input, output) = prices.split_once(':')?;
+            Some(ModelPrice {
+                provider: provider.trim().to_string(),
+                model: model.trim().to_string(),
+                input_per_million: input.trim().parse().ok()?,
+                output_per_million: output.trim().parse().ok()?,
+            })
+        };
+
+        parse().ok_or_else(|| format!("{s} should look like provider/model=input:output"))
+    }
+}
+
+impl PricingConfig {
+    /// The usage of a transcription request, read from the provider's response
+    pub fn transcription_usage(
+        &self,
+        provider: &dyn TranscriptionProvider,
+        response: &serde_json::Value,
+    ) -> StageUsage {
+        let usage = provider.usage(response);
+        let cost = match usage.audio_seconds {
+            _ if !usage.billed => Some(0.0),
+            Some(seconds) => Some(seconds / 60.0 * self.transcription_per_minute),
+            None => None,
+        };
+
+        StageUsage {
+            stage: Stage::Transcribe,
+            provider: provider.name().to_string(),
+            model: usage.model,
+            request_id: usage.request_id,
+            requests: 1,
+            audio_seconds: usage.audio_seconds,
+            input_tokens: None,
+            output_tokens: None,
+            cost,
+            recorded_at: chrono::Utc::now(),
+        }
+    }
+
+    /// The usage of the requests that a stage sent to `model` on a language model provider. The
+    /// cost is unknown if no price is configured for the model.
+    pub fn llm_usage(
+        &self,
+        stage: Stage,
+        provider: &dyn LlmProvider,
+        model: &str,
+        tokens: TokenUsage,
+    ) -> StageUsage {
+        let cost = if provider.billed() {
+            self.llm_prices
+                .iter()
+                .find(|price| price.provider == provider.name() && price.model == model)
+                .map(|price| {
+                    (tokens.input_tokens as f64 * price.input_per_million
+                        + tokens.output_tokens as f64 * price.output_per_million)
+                        / 1_000_000.0
+                })
+        } else {
+            Some(0.0)
+        };
+
+        StageUsage {
+            stage,
+            provider: provider.name().to_string(),
+            model: tokens.model.or_else(|| Some(model.to_string())),
+            request_id: None,
+            requests: tokens.requests,
+            audio_seconds: None,
+            input_tokens: Some(tokens.input_tokens),
+            output_tokens: Some(tokens.output_tokens),
+            cost,
+            recorded_at: chrono::Utc::now(),
+        }
+    }
+}
+
+/// The tokens used by a series of language model requests
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TokenUsage {
+    pub requests: u32,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    /// The model that generated the responses, as reported by the provider
+    pub model: Option<String>,
+}
+
+impl TokenUsage {
+    pub fn add(&mut self, response: &CompletionResponse) {
+        self.requests += 1;
+        self.input_tokens += response.input_tokens.unwrap_or(0) as u64;
+        self.output_tokens += response.output_tokens.unwrap_or(0) as u64;
+        self.model = Some(response.model.clone());
+    }
+
+    /// Count a request that failed, and so returned no token counts.
+    pub fn add_failed(&mut self) {
+        self.requests += 1;
+    }
+}
+
+/// The length of time that the usage report groups usage by
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, schemars::JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum UsagePeriod {
+    #[default]
+    Day,
+    Month,
+}
+
+impl UsagePeriod {
+    /// The name of the period for Postgres's `date_trunc`
+    fn as_str(&self) -> &'static str {
+        match self {
+            UsagePeriod::Day => "day",
+            UsagePeriod::Month => "month",
+        }
+    }
+}
+
+/// The usage of a stage over one day or month
+#[derive(Serialize, Debug, Clone, PartialEq, schemars::JsonSchema)]
+pub struct UsageReportRow {
+    /// The first day of the period
+    pub period_start: chrono::NaiveDate,
+    pub stage: Stage,
+    pub requests: i64,
+    pub audio_seconds: f64,
+    pub input_tokens: i64,
+    pub output_tokens: i64,
+    /// The estimated cost in US dollars, leaving out the requests with an unknown cost
+    pub cost: f64,
+    /// The number of requests to models that have no configured price
+    pub unpriced_requests: i64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, schemars::JsonSchema)]
+pub struct UsageReport {
+    pub period: UsagePeriod,
+    pub rows: Vec<UsageReportRow>,
+    /// The estimated cost of all the rows in US dollars
+    pub total_cost: f64,
+}
+
+/// Sum the organization's usage for each stage over each day or month. `start` and `end` are
+/// inclusive, and all dates are in UTC.
+pub async fn report(
+    state: &ServerState,
+    auth: &Authed,
+    period: UsagePeriod,
+    start: Option<chrono::NaiveDate>,
+    end: Option<chrono::NaiveDate>,
+) -> Result<UsageReport, Report<Error>> {
+    let rows = sqlx::query!(
+        r#"WITH usage AS (
+            SELECT u.value AS u, (u.value->>'recorded_at')::timestamptz AT TIME ZONE 'UTC' AS recorded_at
+            FROM videos, jsonb_array_elements(COALESCE(metadata->'usage', '[]'::jsonb)) u
+            WHERE organization_id = $1
+        )
+        SELECT
+            date_trunc($2, recorded_at)::date AS "period_start!",
+            u->>'stage' AS "stage!",
+            sum(COALESCE((u->>'requests')::bigint, 0))::bigint AS "requests!",
+            sum(COALESCE((u->>'audio_seconds')::float8, 0))::float8 AS "audio_seconds!",
+            sum(COALESCE((u->>'input_tokens')::bigint, 0))::bigint AS "input_tokens!",
+            sum(COALESCE((u->>'output_tokens')::bigint, 0))::bigint AS "output_tokens!",
+            sum(COALESCE((u->>'cost')::float8, 0))::float8 AS "cost!",
+            sum(CASE WHEN u->>'cost' IS NULL THEN COALESCE((u->>'requests')::bigint, 0) ELSE 0 END)::bigint
+                AS "unpriced_requests!"
+        FROM usage
+        WHERE ($3::date IS NULL OR recorded_at >= $3::date)
+            AND ($4::date IS NULL OR recorded_at < $4::date + 1)
+        GROUP BY 1, 2
+        ORDER BY 1, 2"#,
+        auth.organization_id.as_uuid(),
+        period.as_str(),
+        start,
+        end,
+    )
+    .fetch_all(&state.db)
+    .await
+    .change_context(Error::Db)?;
+
+    let rows = rows
+        .into_iter()
+        .filter_map(|row| {
+            Some(UsageReportRow {
+                period_start: row.period_start,
+                stage: Stage::from_name(&row.stage)?,
+                requests: row.requests,
+                audio_seconds: row.audio_seconds,
+                input_tokens: row.input_tokens,
+                output_tokens: row.output_tokens,
+                cost: row.cost,
+                unpriced_requests: row.unpriced_requests,
+            })
+        })
+        .collect::<Vec<_>>();
+    let total_cost = rows.iter().map(|row| row.cost).sum();
+
+    Ok(UsageReport {
+        period,
+        rows,
+        total_cost,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn token_usage() {
+        let mut tokens = TokenUsage::default();
+        for (input_tokens, output_tokens) in [(Some(20_000), Some(500)), (Some(1_500), None)] {
+            tokens.add(&CompletionResponse {
+                text: String::new(),
+                model: "claude-3-haiku-20240307".to_string(),
+                input_tokens,
+                output_tokens,
+            });
+        }
+
+        assert_eq!(
+            tokens,
+            TokenUsage {
+                requests: 2,
+                input_tokens: 21_500,
+                output_tokens: 500,
+                model: Some("claude-3-haiku-20240307".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn llm_prices() {
+        let pricing = PricingConfig {
+            llm_prices: vec!["openai/gpt-4o-mini=0.15:0.6".parse().unwrap()],
+            ..Default::default()
+        };
+        let tokens = TokenUsage {
+            requests: 2,
+            input_tokens: 1_000_000,
+            output_tokens: 500_000,
+            model: None,
+        };
+
+        let client = reqwest::Client::new();
+        let openai = crate::llm::OpenAiCompatible::new(client.clone(), String::new(), None);
+        let priced = pricing.llm_usage(Stage::Summarize, &openai, "gpt-4o-mini", tokens.clone());
+        assert_eq!(priced.cost, Some(0.45));
+        assert_eq!(priced.model.as_deref(), Some("gpt-4o-mini"));
+
+        let unpriced = pricing.llm_usage(Stage::Summarize, &openai, "gpt-4o", tokens.clone());
+        assert_eq!(unpriced.cost, None);
+
+        let ollama = crate::llm::Ollama::new(client, String::new());
+        let local = pricing.llm_usage(Stage::Summarize, &ollama, "llama3", tokens);
+        assert_eq!(local.cost, Some(0.0));
+
+        assert!("gpt-4o=1:2".parse::<ModelPrice>().is_err());
+    }
+}
@@ -1,13 +1,16 @@
 #![allow(unused_imports, unused_variables, dead_code)]
-use std::{borrow::Cow, str::FromStr};
+use std::{borrow::Cow, collections::BTreeMap, str::FromStr};
 
 use axum::{
-    extract::{Path, State},
+    extract::{DefaultBodyLimit, Path, State},
     http::StatusCode,
     response::{IntoResponse, Response},
     routing,
 };
-use axum_extra::{extract::Query, headers::ContentType};
+use axum_extra::{
+    extract::{Multipart, Query},
+    headers::ContentType,
+};
 use axum_jsonschema::Json;
 use error_stack::{Report, ResultExt};
 use filigree::{
@@ -91,27 +94,31 @@ async fn delete(
     auth: Authed,
     Path(id): Path<VideoId>,
 ) -> Result<impl IntoResponse, Error> {
-    let mut tx = state.db.begin().await.change_context(Error::Db)?;
-
-    let deleted = queries::delete(&mut *tx, &auth, &id).await?;
+    let deleted = super::delete(&state, &auth, id).await?;
 
     if !deleted {
         return Ok(StatusCode::NOT_FOUND);
     }
 
-    tx.commit().await.change_context(Error::Db)?;
-
     Ok(StatusCode::OK)
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, JsonSchema)]
 pub struct CreateViaUrlPayload {
     pub url: String,
+    /// Settings for downloading this video, which override the server's defaults
+    #[serde(default)]
+    pub download: DownloadOptions,
+    /// Add the video again even if the organization already has it
+    #[serde(default)]
+    pub allow_duplicate: bool,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, JsonSchema)]
 pub struct CreateViaUrlResponse {
     pub id: VideoId,
+    /// True if the video had already been added, in which case `id` is the existing video.
+    pub existing: bool,
 }
 
 async fn create_via_url(
@@ -119,14 +126,46 @@ async fn create_via_url(
     auth: Authed,
     FormOrJson(payload): FormOrJson<CreateViaUrlPayload>,
 ) -> Result<impl IntoResponse, Error> {
-    let id = super::create_via_url(&state, &auth, &payload.url).await?;
-    let output = CreateViaUrlResponse { id };
+    let added = super::create_via_url(
+        &state,
+        &auth,
+        &payload.url,
+        payload.download,
+        payload.allow_duplicate,
+    )
+    .await?;
+    let output = CreateViaUrlResponse {
+        id: added.id,
+        existing: added.existing,
+    };
+    Ok(Json(output))
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, JsonSchema)]
+pub struct UploadVideoResponse {
+    pub id: VideoId,
+}
+
+async fn upload_video(
+    State(state): State<ServerState>,
+    auth: Authed,
+    multipart: Multipart,
+) -> Result<impl IntoResponse, Error> {
+    let id = super::create_via_upload(&state, &auth, multipart).await?;
+    let output = UploadVideoResponse { id };
     Ok(Json(output))
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, JsonSchema)]
 pub struct RerunStagePayload {}
 
+#[derive(serde::Deserialize, serde::Serialize, Debug, JsonSchema)]
+pub struct RerunStageQuery {
+    /// Run the stages that depend on this one again afterwards
+    #[serde(default)]
+    pub cascade: bool,
+}
+
 #[derive(serde::Deserialize, serde::Serialize, Debug, JsonSchema)]
 pub struct RerunStageResponse {
     pub job_id: uuid::Uuid,
@@ -136,13 +175,108 @@ async fn rerun_stage(
     State(state): State<ServerState>,
     auth: Authed,
     Path((id, stage)): Path<(VideoId, String)>,
+    Query(qs): Query<RerunStageQuery>,
 ) -> Result<impl IntoResponse, Error> {
-    let job_id = super::rerun_stage(&state, &auth, id, &stage).await?;
+    let job_id = super::rerun_stage(&state, &auth, id, &stage, qs.cascade).await?;
     let output = RerunStageResponse { job_id };
 
     Ok(Json(output))
 }
 
+#[derive(serde::Deserialize, serde::Serialize, Debug, JsonSchema)]
+pub struct CancelResponse {}
+
+async fn cancel(
+    State(state): State<ServerState>,
+    auth: Authed,
+    Path(id): Path<VideoId>,
+) -> Result<impl IntoResponse, Error> {
+    super::cancel(&state, &auth, id).await?;
+    let output = CancelResponse {};
+
+    Ok(Json(output))
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, JsonSchema)]
+pub struct ResumeResponse {
+    pub job_ids: Vec<uuid::Uuid>,
+}
+
+async fn resume(
+    State(state): State<ServerState>,
+    auth: Authed,
+    Path(id): Path<VideoId>,
+) -> Result<impl IntoResponse, Error> {
+    let job_ids = super::resume(&state, &auth, id).await?;
+    let output = ResumeResponse { job_ids };
+
+    Ok(Json(output))
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, JsonSchema)]
+pub struct SearchQuery {
+    pub q: String,
+}
+
+async fn search(
+    State(state): State<ServerState>,
+    auth: Authed,
+    Query(qs): Query<SearchQuery>,
+) -> Result<impl IntoResponse, Error> {
+    let results = super::search(&state, &auth, &qs.q).await?;
+
+    Ok(Json(results))
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, JsonSchema)]
+pub struct SetSpeakerNamesPayload {
+    /// The name for each speaker number. Speakers that are left out or have a blank name are shown
+    /// as "Speaker N".
+    pub speaker_names: BTreeMap<u32, String>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, JsonSchema)]
+pub struct SetSpeakerNamesResponse {
+    pub speaker_names: BTreeMap<u32, String>,
+}
+
+async fn set_speaker_names(
+    State(state): State<ServerState>,
+    auth: Authed,
+    Path(id): Path<VideoId>,
+    FormOrJson(payload): FormOrJson<SetSpeakerNamesPayload>,
+) -> Result<impl IntoResponse, Error> {
+    let speaker_names = super::set_speaker_names(&state, &auth, id, payload.speaker_names).await?;
+    let output = SetSpeakerNamesResponse { speaker_names };
+
+    Ok(Json(output))
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, JsonSchema)]
+pub struct SetSimilarityThresholdPayload {
+    /// Images at least this similar to the last kept image are hidden, from 0 to 1
+    pub threshold: f64,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, JsonSchema)]
+pub struct SetSimilarityThresholdResponse {
+    pub removed: Vec<u32>,
+}
+
+async fn set_similarity_threshold(
+    State(state): State<ServerState>,
+    auth: Authed,
+    Path(id): Path<VideoId>,
+    FormOrJson(payload): FormOrJson<SetSimilarityThresholdPayload>,
+) -> Result<impl IntoResponse, Error> {
+    let images = super::set_similarity_threshold(&state, &auth, id, payload.threshold).await?;
+    let output = SetSimilarityThresholdResponse {
+        removed: images.removed,
+    };
+
+    Ok(Json(output))
+}
+
 #[derive(serde::Deserialize, serde::Serialize, Debug, JsonSchema)]
 pub struct MarkReadPayload {
     pub read: bool,
@@ -227,6 +361,11 @@ pub fn create_routes() -> axum::Router<ServerState> {
             "/videos",
             routing::get(list).route_layer(has_any_permission(vec![READ_PERMISSION, "org_admin"])),
         )
+        .route(
+            "/videos/search",
+            routing::get(search)
+                .route_layer(has_any_permission(vec![READ_PERMISSION, "org_admin"])),
+        )
         .route(
             "/videos/:id",
             routing::get(get).route_layer(has_any_permission(vec![READ_PERMISSION, "org_admin"])),
@@ -249,11 +388,37 @@ pub fn create_routes() -> axum::Router<ServerState> {
             routing::post(create_via_url)
                 .route_layer(has_any_permission(vec![CREATE_PERMISSION, "org_admin"])),
         )
+        .route(
+            "/videos/upload",
+            routing::post(upload_video)
+                .layer(DefaultBodyLimit::disable())
+                .route_layer(has_any_permission(vec![CREATE_PERMISSION, "org_admin"])),
+        )
         .route(
             "/videos/:id/rerun/:stage",
             routing::post(rerun_stage)
                 .route_layer(has_any_permission(vec![OWNER_PERMISSION, "org_admin"])),
         )
+        .route(
+            "/videos/:id/cancel",
+            routing::post(cancel)
+                .route_layer(has_any_permission(vec![OWNER_PERMISSION, "org_admin"])),
+        )
+        .route(
+            "/videos/:id/resume",
+            routing::post(resume)
+                .route_layer(has_any_permission(vec![OWNER_PERMISSION, "org_admin"])),
+        )
+        .route(
+            "/videos/:id/speaker_names",
+            routing::put(set_speaker_names)
+                .route_layer(has_any_permission(vec![OWNER_PERMISSION, "org_admin"])),
+        )
+        .route(
+            "/videos/:id/similarity_threshold",
+            routing::post(set_similarity_threshold)
+                .route_layer(has_any_permission(vec![OWNER_PERMISSION, "org_admin"])),
+        )
         .route(
             "/videos/:id/mark_read",
             routing::post(mark_read).route_layer(has_any_permission(vec![
@@ -444,11 +609,31 @@ mod test {
                 serde_json::to_value(&added.summary).unwrap(),
                 "field summary"
             );
+            assert_eq!(
+                result["summary_sections"],
+                serde_json::to_value(&added.summary_sections).unwrap(),
+                "field summary_sections"
+            );
             assert_eq!(
                 result["processed_path"],
                 serde_json::to_value(&added.processed_path).unwrap(),
                 "field processed_path"
             );
+            assert_eq!(
+                result["collection_id"],
+                serde_json::to_value(&added.collection_id).unwrap(),
+                "field collection_id"
+            );
+            assert_eq!(
+                result["extractor"],
+                serde_json::to_value(&added.extractor).unwrap(),
+                "field extractor"
+            );
+            assert_eq!(
+                result["extractor_id"],
+                serde_json::to_value(&added.extractor_id).unwrap(),
+                "field extractor_id"
+            );
 
             assert_eq!(result["_permission"], "owner");
         }
@@ -551,11 +736,31 @@ mod test {
                 serde_json::to_value(&added.summary).unwrap(),
                 "list result field summary"
             );
+            assert_eq!(
+                result["summary_sections"],
+                serde_json::to_value(&added.summary_sections).unwrap(),
+                "list result field summary_sections"
+            );
             assert_eq!(
                 result["processed_path"],
                 serde_json::to_value(&added.processed_path).unwrap(),
                 "list result field processed_path"
             );
+            assert_eq!(
+                result["collection_id"],
+                serde_json::to_value(&added.collection_id).unwrap(),
+                "list result field collection_id"
+            );
+            assert_eq!(
+                result["extractor"],
+                serde_json::to_value(&added.extractor).unwrap(),
+                "list result field extractor"
+            );
+            assert_eq!(
+                result["extractor_id"],
+                serde_json::to_value(&added.extractor_id).unwrap(),
+                "list result field extractor_id"
+            );
             assert_eq!(result["_permission"], "write");
         }
 
@@ -728,11 +933,31 @@ mod test {
             serde_json::to_value(&added.summary).unwrap(),
             "get result field summary"
         );
+        assert_eq!(
+            result["summary_sections"],
+            serde_json::to_value(&added.summary_sections).unwrap(),
+            "get result field summary_sections"
+        );
         assert_eq!(
             result["processed_path"],
             serde_json::to_value(&added.processed_path).unwrap(),
             "get result field processed_path"
         );
+        assert_eq!(
+            result["collection_id"],
+            serde_json::to_value(&added.collection_id).unwrap(),
+            "get result field collection_id"
+        );
+        assert_eq!(
+            result["extractor"],
+            serde_json::to_value(&added.extractor).unwrap(),
+            "get result field extractor"
+        );
+        assert_eq!(
+            result["extractor_id"],
+            serde_json::to_value(&added.extractor_id).unwrap(),
+            "get result field extractor_id"
+        );
 
         assert_eq!(result["_permission"], "owner");
 
@@ -830,11 +1055,31 @@ mod test {
             serde_json::to_value(&added.summary).unwrap(),
             "get result field summary"
         );
+        assert_eq!(
+            result["summary_sections"],
+            serde_json::to_value(&added.summary_sections).unwrap(),
+            "get result field summary_sections"
+        );
         assert_eq!(
             result["processed_path"],
             serde_json::to_value(&added.processed_path).unwrap(),
             "get result field processed_path"
         );
+        assert_eq!(
+            result["collection_id"],
+            serde_json::to_value(&added.collection_id).unwrap(),
+            "get result field collection_id"
+        );
+        assert_eq!(
+            result["extractor"],
+            serde_json::to_value(&added.extractor).unwrap(),
+            "get result field extractor"
+        );
+        assert_eq!(
+            result["extractor_id"],
+            serde_json::to_value(&added.extractor_id).unwrap(),
+            "get result field extractor_id"
+        );
         assert_eq!(result["_permission"], "write");
 
         let response = no_roles_user
@@ -1000,11 +1245,31 @@ mod test {
             serde_json::to_value(&added_objects[0].1.summary).unwrap(),
             "field summary"
         );
+        assert_eq!(
+            non_updated["summary_sections"],
+            serde_json::to_value(&added_objects[0].1.summary_sections).unwrap(),
+            "field summary_sections"
+        );
         assert_eq!(
             non_updated["processed_path"],
             serde_json::to_value(&added_objects[0].1.processed_path).unwrap(),
             "field processed_path"
         );
+        assert_eq!(
+            non_updated["collection_id"],
+            serde_json::to_value(&added_objects[0].1.collection_id).unwrap(),
+            "field collection_id"
+        );
+        assert_eq!(
+            non_updated["extractor"],
+            serde_json::to_value(&added_objects[0].1.extractor).unwrap(),
+            "field extractor"
+        );
+        assert_eq!(
+            non_updated["extractor_id"],
+            serde_json::to_value(&added_objects[0].1.extractor_id).unwrap(),
+            "field extractor_id"
+        );
         assert_eq!(non_updated["_permission"], "owner");
 
         let response = no_roles_user
@@ -1018,6 +1283,75 @@ mod test {
         assert_eq!(response.status(), reqwest::StatusCode::FORBIDDEN);
     }
 
+    #[sqlx::test]
+    async fn rerun_cancelled_video(pool: sqlx::PgPool) {
+        let (
+            _app,
+            BootstrappedData {
+                organization,
+                admin_user,
+                ..
+            },
+        ) = start_app(pool.clone()).await;
+
+        let id = VideoId::new();
+        sqlx::query(
+            "INSERT INTO videos (id, organization_id, processing_state, metadata)
+            VALUES ($1, $2, 'cancelled', $3)",
+        )
+        .bind(id.as_uuid())
+        .bind(organization.id.as_uuid())
+        .bind(serde_json::json!({
+            "finished_stages": ["download", "extract", "analyze", "ocr", "transcribe"]
+        }))
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let state = || async {
+            sqlx::query_scalar::<_, String>("SELECT processing_state FROM videos WHERE id = $1")
+                .bind(id.as_uuid())
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+        };
+
+        // A stage that can't run leaves the video as it was.
+        let response = admin_user
+            .client
+            .post(format!("videos/{id}/rerun/summarize"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+        assert_eq!(state().await, "cancelled");
+
+        sqlx::query("UPDATE videos SET transcript = $2 WHERE id = $1")
+            .bind(id.as_uuid())
+            .bind(serde_json::json!({
+                "source_format": "test",
+                "language": null,
+                "confidence": null,
+                "paragraphs": [],
+            }))
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        // The new state is saved before the job is enqueued, so the job never sees the video as
+        // cancelled.
+        admin_user
+            .client
+            .post(format!("videos/{id}/rerun/summarize"))
+            .send()
+            .await
+            .unwrap()
+            .log_error()
+            .await
+            .unwrap();
+        assert_eq!(state().await, "processing");
+    }
+
     #[sqlx::test]
     async fn delete_object(pool: sqlx::PgPool) {
         let (
@@ -25,7 +25,11 @@ RETURNING
   read,
   progress,
   images AS "images: crate::models::video::VideoImages",
-  transcript,
+  transcript AS "transcript: crate::models::video::VideoTranscript",
   summary,
+  summary_sections AS "summary_sections: crate::models::video::VideoSummarySections",
   processed_path,
+  collection_id AS "collection_id: crate::models::collection::CollectionId",
+  extractor,
+  extractor_id,
   'owner' AS "_permission!: filigree::auth::ObjectPermission"
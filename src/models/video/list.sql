@@ -14,6 +14,9 @@ SELECT
   progress,
   summary,
   processed_path,
+  collection_id,
+  extractor,
+  extractor_id,
   perm._permission
 FROM
   public.videos tb
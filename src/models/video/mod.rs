@@ -1,19 +1,30 @@
 pub mod endpoints;
 pub mod queries;
+pub mod source;
 #[cfg(test)]
 pub mod testing;
+pub mod transcript;
 pub mod types;
 
+use std::{collections::BTreeMap, ops::RangeInclusive};
+
+use axum_extra::extract::Multipart;
 use error_stack::{Report, ResultExt};
+use filigree::{
+    storage::StorageError,
+    uploads::{UploadInspector, UploadSize},
+};
+use futures::TryStreamExt;
+use source::VideoSource;
+use tracing::{event, Level};
+pub use transcript::*;
 pub use types::*;
 use uuid::Uuid;
 
 use crate::{
     auth::Authed,
-    jobs::{
-        analyze::AnalyzeJobPayload, download::DownloadJobPayload, extract::ExtractJobPayload,
-        summarize::SummarizeJobPayload, transcribe::TranscribeJobPayload,
-    },
+    jobs::{delete_media::DeleteMediaJobPayload, extract::ExtractJobPayload, pipeline::Stage},
+    models::{collection::CollectionId, organization::OrganizationId},
     server::ServerState,
     Error,
 };
@@ -40,19 +51,96 @@ pub const THUMBNAIL_FILENAME: &str = "thumbnail.webp";
 /// The output template used for ffmpeg when extracting images. This should be kept in sync with [image_filename]
 pub const VIDEO_IMAGE_TEMPLATE: &str = "image-%05d.webp";
 
+/// A video that was added from a URL
+#[derive(Debug, Clone, Copy)]
+pub struct AddedVideo {
+    pub id: VideoId,
+    /// True if the organization already had the video, and so nothing new was added.
+    pub existing: bool,
+}
+
+/// Add a video and start downloading it. If the organization already has the video, under this
+/// URL or any other that points to it, the existing video is returned instead, unless
+/// `allow_duplicate` is set. Only URLs from sites that [VideoSource::from_url] recognizes can be
+/// matched this way, and the rest are checked by the download job once yt-dlp has identified them.
 pub async fn create_via_url(
     state: &ServerState,
     auth: &Authed,
     url: &str,
-) -> Result<VideoId, Report<Error>> {
+    download: DownloadOptions,
+    allow_duplicate: bool,
+) -> Result<AddedVideo, Report<Error>> {
+    add_from_url(
+        state,
+        auth.organization_id,
+        url,
+        None,
+        download,
+        allow_duplicate,
+    )
+    .await
+}
+
+/// Add a video to an organization and start downloading it, or return the existing video if the
+/// organization already has it. This is used by background jobs which add videos on behalf of an
+/// organization, and so don't have an [Authed].
+pub async fn create_for_url(
+    state: &ServerState,
+    organization_id: OrganizationId,
+    url: &str,
+    collection_id: Option<CollectionId>,
+) -> Result<AddedVideo, Report<Error>> {
+    add_from_url(
+        state,
+        organization_id,
+        url,
+        collection_id,
+        DownloadOptions::default(),
+        false,
+    )
+    .await
+}
+
+async fn add_from_url(
+    state: &ServerState,
+    organization_id: OrganizationId,
+    url: &str,
+    collection_id: Option<CollectionId>,
+    download: DownloadOptions,
+    allow_duplicate: bool,
+) -> Result<AddedVideo, Report<Error>> {
+    let source = VideoSource::from_url(url);
+
+    if !allow_duplicate {
+        if let Some(id) = find_existing(state, organization_id, url, &source).await? {
+            if let Some(collection_id) = collection_id {
+                sqlx::query!(
+                    "UPDATE videos SET collection_id = COALESCE(collection_id, $2) WHERE id = $1",
+                    id.as_uuid(),
+                    collection_id.as_uuid(),
+                )
+                .execute(&state.db)
+                .await
+                .change_context(Error::Db)?;
+            }
+
+            return Ok(AddedVideo { id, existing: true });
+        }
+    }
+
     let id = VideoId::new();
     sqlx::query!(
-        "INSERT INTO videos (id, organization_id, processing_state, url, metadata) VALUES
-        ($1, $2, $3, $4, '{}'::jsonb)",
+        "INSERT INTO videos (id, organization_id, processing_state, url, extractor, extractor_id,
+            collection_id, metadata)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
         id.as_uuid(),
-        auth.organization_id.as_uuid(),
+        organization_id.as_uuid(),
         VideoProcessingState::Queued as _,
-        url
+        &source.url,
+        source.extractor.as_deref(),
+        source.extractor_id.as_deref(),
+        collection_id.as_ref().map(|id| id.as_uuid()),
+        serde_json::json!({ "download_options": download }),
     )
     .execute(&state.db)
     .await
@@ -63,97 +151,610 @@ pub async fn create_via_url(
         id,
         &crate::jobs::download::DownloadJobPayload {
             id,
-            download_url: url.to_string(),
+            download_url: source.url,
             storage_prefix: id.to_string(),
+            options: download,
+            check_duplicate: !allow_duplicate,
         },
     )
     .await
     .change_context(Error::TaskQueue)
     .attach_printable("Failed to enqueue download job")?;
 
-    Ok(id)
+    Ok(AddedVideo {
+        id,
+        existing: false,
+    })
+}
+
+/// Find the oldest video in the organization with the same site and ID, or with the same URL.
+/// The URL as it was given is checked too, for videos added before URLs were canonicalized.
+async fn find_existing(
+    state: &ServerState,
+    organization_id: OrganizationId,
+    url: &str,
+    source: &VideoSource,
+) -> Result<Option<VideoId>, Report<Error>> {
+    let urls = [source.url.clone(), url.trim().to_string()];
+    sqlx::query_scalar!(
+        r#"SELECT id AS "id: VideoId" FROM videos
+        WHERE organization_id = $1
+            AND (url = ANY($2) OR (extractor = $3 AND extractor_id = $4))
+        ORDER BY created_at
+        LIMIT 1"#,
+        organization_id.as_uuid(),
+        &urls[..],
+        source.extractor.as_deref(),
+        source.extractor_id.as_deref(),
+    )
+    .fetch_optional(&state.db)
+    .await
+    .change_context(Error::Db)
+}
+
+/// The largest media file that can be uploaded directly
+pub const MAX_UPLOAD_SIZE: usize = 10 * 1024 * 1024 * 1024;
+
+/// Create a video from a multipart form containing the media in a `file` field, and optional
+/// `title`, `author`, and `date` fields. The file is streamed into storage and processing starts
+/// at the extract stage, since there is nothing to download.
+pub async fn create_via_upload(
+    state: &ServerState,
+    auth: &Authed,
+    multipart: Multipart,
+) -> Result<VideoId, Error> {
+    let id = VideoId::new();
+    let mut storage_path = None;
+    let result = save_upload(state, auth, id, multipart, &mut storage_path).await;
+
+    // Don't leave the file behind if the video couldn't be created.
+    if let (Err(_), Some(path)) = (&result, storage_path) {
+        match state
+            .storage
+            .uploads
+            .delete(&path)
+            .await
+            .map_err(StorageError::from)
+        {
+            Ok(()) | Err(StorageError::NotFound(_)) => {}
+            Err(e) => {
+                event!(Level::ERROR, %id, path, "Failed to delete uploaded file: {e:?}");
+            }
+        }
+    }
+
+    result.map(|_| id)
+}
+
+/// Read the upload form and create the video. `storage_path` is set before the file is saved,
+/// so that the caller can remove it if this fails.
+async fn save_upload(
+    state: &ServerState,
+    auth: &Authed,
+    id: VideoId,
+    mut multipart: Multipart,
+    storage_path: &mut Option<String>,
+) -> Result<(), Error> {
+    let start = tokio::time::Instant::now();
+
+    let mut title = None;
+    let mut author = None;
+    let mut date = None;
+    let mut upload = None;
+    while let Some(field) = multipart.next_field().await.change_context(Error::Upload)? {
+        match field.name().unwrap_or_default() {
+            "title" => title = non_empty(field.text().await.change_context(Error::Upload)?),
+            "author" => author = non_empty(field.text().await.change_context(Error::Upload)?),
+            "date" => {
+                date = non_empty(field.text().await.change_context(Error::Upload)?)
+                    .map(|d| chrono::NaiveDate::parse_from_str(&d, "%Y-%m-%d"))
+                    .transpose()
+                    .map_err(|_| Error::InvalidUpload("date must be in YYYY-MM-DD format"))?;
+            }
+            "file" => {
+                if storage_path.is_some() {
+                    return Err(Error::InvalidUpload("only one file can be uploaded"));
+                }
+
+                let original_name = field.file_name().unwrap_or_default().to_string();
+                let path = std::path::Path::new(&original_name);
+                let ext = path
+                    .extension()
+                    .and_then(|e| e.to_str())
+                    .filter(|e| e.chars().all(|c| c.is_ascii_alphanumeric()))
+                    .unwrap_or("mp4")
+                    .to_ascii_lowercase();
+                let stem = path.file_stem().and_then(|s| s.to_str()).map(String::from);
+
+                let video_filename = format!("video.{ext}");
+                let path = storage_path.insert(format!("{id}/{video_filename}"));
+                let mut size = UploadSize::new(Some(MAX_UPLOAD_SIZE));
+                state
+                    .storage
+                    .uploads
+                    .save_and_inspect_request_body(
+                        path,
+                        field.map_err(StorageError::from),
+                        |chunk| size.inspect(chunk),
+                    )
+                    .await
+                    .change_context(Error::Upload)
+                    .attach_printable_lazy(|| path.clone())?;
+
+                upload = Some((video_filename, stem));
+            }
+            _ => {}
+        }
+    }
+
+    let (video_filename, stem) = upload.ok_or(Error::InvalidUpload("missing file"))?;
+
+    sqlx::query!(
+        "INSERT INTO videos (id, organization_id, processing_state, title, author, date, metadata)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        id.as_uuid(),
+        auth.organization_id.as_uuid(),
+        VideoProcessingState::Downloaded as _,
+        title.or(stem),
+        author,
+        date,
+        serde_json::json!({
+            "download": {
+                "duration": start.elapsed().as_secs(),
+                "filename": video_filename,
+            },
+            "finished_stages": [Stage::Download],
+        }),
+    )
+    .execute(&state.db)
+    .await
+    .change_context(Error::Db)?;
+
+    let enqueued = crate::jobs::extract::enqueue(
+        state,
+        id,
+        &ExtractJobPayload {
+            id,
+            storage_prefix: id.to_string(),
+            video_filename,
+        },
+    )
+    .await;
+
+    if let Err(e) = enqueued {
+        // The file is about to be removed, so remove the video that refers to it too.
+        if let Err(db_err) = sqlx::query!("DELETE FROM videos WHERE id = $1", id.as_uuid())
+            .execute(&state.db)
+            .await
+        {
+            event!(Level::ERROR, %id, "Failed to remove video after upload failed: {db_err:?}");
+        }
+
+        return Err(Report::new(e)
+            .change_context(Error::TaskQueue)
+            .attach_printable("Failed to enqueue extract job")
+            .into());
+    }
+
+    Ok(())
+}
+
+fn non_empty(s: String) -> Option<String> {
+    let s = s.trim();
+    (!s.is_empty()).then(|| s.to_string())
 }
 
+/// Delete a video. Any processing still running for it is stopped, and its stored media is
+/// removed in the background. Returns false if the video didn't exist or the user can't delete it.
+pub async fn delete(state: &ServerState, auth: &Authed, id: VideoId) -> Result<bool, Error> {
+    let video = sqlx::query!(
+        r#"SELECT
+            metadata AS "metadata: VideoMetadata",
+            images AS "images: VideoImages"
+        FROM videos WHERE id = $1 AND organization_id = $2"#,
+        id.as_uuid(),
+        auth.organization_id.as_uuid(),
+    )
+    .fetch_optional(&state.db)
+    .await
+    .change_context(Error::Db)?;
+
+    let Some(video) = video else {
+        return Ok(false);
+    };
+
+    if !queries::delete(&state.db, auth, &id).await? {
+        return Ok(false);
+    }
+
+    // Stop the jobs first so that they don't write more files after the media is deleted.
+    crate::jobs::cancel::cancel_video_jobs(state, id).await?;
+
+    let payload = DeleteMediaJobPayload::new(id, video.metadata.as_ref(), video.images.as_ref());
+    crate::jobs::delete_media::enqueue(state, format!("delete-{id}"), &payload)
+        .await
+        .change_context(Error::TaskQueue)
+        .attach_printable("Failed to enqueue delete_media job")?;
+
+    Ok(true)
+}
+
+/// Stop processing a video. Jobs that haven't started are cancelled and running jobs are
+/// stopped. Processing can continue later with [resume] or [rerun_stage].
+pub async fn cancel(state: &ServerState, auth: &Authed, id: VideoId) -> Result<(), Error> {
+    queries::get(&state.db, auth, &id).await?;
+
+    // Jobs check for this state when they start, so it has to be set before stopping them.
+    let updated = sqlx::query!(
+        "UPDATE videos SET
+        processing_state = $2,
+        metadata = COALESCE(metadata, '{}'::jsonb) - 'stage_progress'
+        WHERE id = $1 AND processing_state NOT IN ($2, $3)",
+        id.as_uuid(),
+        VideoProcessingState::Cancelled as _,
+        VideoProcessingState::Ready as _,
+    )
+    .execute(&state.db)
+    .await
+    .change_context(Error::Db)?
+    .rows_affected();
+
+    if updated == 0 {
+        return Err(Error::WrongProcessingState("processing"));
+    }
+
+    crate::jobs::cancel::cancel_video_jobs(state, id).await?;
+    Ok(())
+}
+
+/// Continue processing a cancelled video, starting the stages that hadn't finished.
+pub async fn resume(state: &ServerState, auth: &Authed, id: VideoId) -> Result<Vec<Uuid>, Error> {
+    queries::get(&state.db, auth, &id).await?;
+
+    let updated = sqlx::query!(
+        "UPDATE videos SET processing_state = $2 WHERE id = $1 AND processing_state = $3",
+        id.as_uuid(),
+        VideoProcessingState::Processing as _,
+        VideoProcessingState::Cancelled as _,
+    )
+    .execute(&state.db)
+    .await
+    .change_context(Error::Db)?
+    .rows_affected();
+
+    if updated == 0 {
+        return Err(Error::WrongProcessingState("cancelled"));
+    }
+
+    let job_ids = crate::jobs::pipeline::resume(state, id).await?;
+    Ok(job_ids)
+}
+
+/// Run a processing stage again. With `cascade`, every stage that depends on it runs again
+/// afterwards too. Otherwise only the stages that haven't finished yet will follow it.
 pub async fn rerun_stage(
     state: &ServerState,
     auth: &Authed,
     id: VideoId,
     stage: &str,
+    cascade: bool,
 ) -> Result<Uuid, Error> {
+    let stage = Stage::from_name(stage).ok_or(Error::NotFound("Unknown stage"))?;
     let video = queries::get(&state.db, auth, &id).await?;
-    let storage_prefix = video.id.to_string();
-
-    let result = match stage {
-        "download" => {
-            let url = video
-                .url
-                .ok_or(Error::NotFound("Video URL"))
-                .attach_printable("Missing URL")?;
-            crate::jobs::download::enqueue(
-                &state,
-                id,
-                &DownloadJobPayload {
-                    id: video.id,
-                    storage_prefix,
-                    download_url: url,
-                },
-            )
-            .await
-        }
-        "extract" => {
-            let video_filename = video
-                .metadata
-                .as_ref()
-                .and_then(|m| m.download.as_ref())
-                .and_then(|d| d.filename.as_ref())
-                .map(|s| s.to_string())
-                .ok_or(Error::NotFound("Video not downloaded yet"))?;
-            crate::jobs::extract::enqueue(
-                &state,
-                id,
-                &ExtractJobPayload {
-                    id,
-                    storage_prefix,
-                    video_filename,
-                },
+
+    let audio_only = video.metadata.as_ref().is_some_and(|m| m.audio_only);
+    if audio_only && matches!(stage, Stage::Analyze | Stage::Ocr) {
+        return Err(Error::StageCanNotRun("audio-only videos have no images"));
+    }
+
+    let downstream = stage.downstream();
+    let mut rerun = vec![stage];
+    if cascade {
+        rerun.extend_from_slice(&downstream);
+    }
+
+    // Videos processed before stages were tracked have no list of finished stages, so assume
+    // everything before this stage finished and run the rest again.
+    let assumed_finished = Stage::ALL
+        .into_iter()
+        .filter(|s| *s != stage && !downstream.contains(s))
+        .collect::<Vec<_>>();
+
+    // Check that the stage can run before changing anything.
+    let job = crate::jobs::pipeline::prepare_stage(state, id, stage).await?;
+
+    // Remove the stages from the finished list and commit that before starting the job, so that
+    // the job sees the video as processing and can't finish before the stages are removed. This
+    // also clears any previous failures now that the stages are going to run again.
+    sqlx::query!(
+        r#"UPDATE videos SET
+        processing_state = $2,
+        metadata = (COALESCE(metadata, '{}'::jsonb) - 'failure') || jsonb_build_object(
+            'finished_stages',
+            (SELECT COALESCE(jsonb_agg(s), '[]'::jsonb)
+                FROM jsonb_array_elements(COALESCE(metadata->'finished_stages', $3)) s
+                WHERE NOT s <@ $4),
+            'optional_failures',
+            (SELECT COALESCE(jsonb_agg(f), '[]'::jsonb)
+                FROM jsonb_array_elements(COALESCE(metadata->'optional_failures', '[]'::jsonb)) f
+                WHERE NOT (f->'stage') <@ $4)
+        )
+        WHERE id = $1"#,
+        id.as_uuid(),
+        VideoProcessingState::Processing as _,
+        serde_json::json!(assumed_finished),
+        serde_json::json!(rerun),
+    )
+    .execute(&state.db)
+    .await
+    .change_context(Error::Db)?;
+
+    match job.enqueue(state, id).await {
+        Ok(job_id) => Ok(job_id),
+        Err(e) => {
+            // Nothing is going to run, so put the video back the way it was.
+            let restored = sqlx::query!(
+                "UPDATE videos SET processing_state = $2, metadata = $3 WHERE id = $1",
+                id.as_uuid(),
+                video.processing_state as _,
+                video.metadata.as_ref().map(|m| serde_json::json!(m)),
             )
-            .await
+            .execute(&state.db)
+            .await;
+            if let Err(db_err) = restored {
+                event!(Level::ERROR, video_id=%id, err=?db_err, "Failed to restore video after enqueue failed");
+            }
+
+            Err(e.into())
         }
-        "analyze" => {
-            let max_index = video
-                .images
-                .map(|i| i.max_index)
-                .ok_or(Error::NotFound("Video not extracted yet"))?;
-            crate::jobs::analyze::enqueue(
-                &state,
-                id,
-                &AnalyzeJobPayload {
-                    id,
-                    storage_prefix,
-                    max_index,
-                },
-            )
-            .await
+    }
+}
+
+/// Find videos whose title, summary, or slide text contains `query`.
+pub async fn search(
+    state: &ServerState,
+    auth: &Authed,
+    query: &str,
+) -> Result<Vec<VideoSearchResult>, Error> {
+    let pattern = format!(
+        "%{}%",
+        query
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+    );
+
+    let results = sqlx::query_as!(
+        VideoSearchResult,
+        r#"SELECT id AS "id: VideoId", title, COALESCE(matches.images, '{}') AS "images!"
+        FROM videos
+        LEFT JOIN LATERAL (
+            SELECT array_agg(t.key::int ORDER BY t.key::int) AS images
+            FROM jsonb_each_text(
+                CASE WHEN jsonb_typeof(images->'text') = 'object' THEN images->'text'
+                ELSE '{}'::jsonb END
+            ) t
+            WHERE t.value ILIKE $2
+                AND NOT COALESCE(images->'removed', '[]'::jsonb) @> to_jsonb(t.key::int)
+        ) matches ON true
+        WHERE organization_id = $1
+            AND (title ILIKE $2 OR summary ILIKE $2 OR matches.images IS NOT NULL)
+        ORDER BY created_at DESC
+        LIMIT 50"#,
+        auth.organization_id.as_uuid(),
+        pattern
+    )
+    .fetch_all(&state.db)
+    .await
+    .change_context(Error::Db)?;
+
+    Ok(results)
+}
+
+/// Set the names shown for the speakers in a video's transcript. Blank names are removed, so
+/// that the speaker goes back to being shown by number.
+pub async fn set_speaker_names(
+    state: &ServerState,
+    auth: &Authed,
+    id: VideoId,
+    names: BTreeMap<u32, String>,
+) -> Result<BTreeMap<u32, String>, Error> {
+    // Make sure the video exists and the user can see it.
+    queries::get(&state.db, auth, &id).await?;
+
+    let names = names
+        .into_iter()
+        .map(|(speaker, name)| (speaker, name.trim().to_string()))
+        .filter(|(_, name)| !name.is_empty())
+        .collect::<BTreeMap<_, _>>();
+
+    sqlx::query!(
+        "UPDATE videos
+        SET metadata = metadata || $2
+        WHERE id = $1",
+        id.as_uuid(),
+        serde_json::json!({ "speaker_names": names })
+    )
+    .execute(&state.db)
+    .await
+    .change_context(Error::Db)?;
+
+    Ok(names)
+}
+
+/// Recalculate which images are hidden for a video, using the similarity scores saved when it
+/// was analyzed.
+pub async fn set_similarity_threshold(
+    state: &ServerState,
+    auth: &Authed,
+    id: VideoId,
+    threshold: f64,
+) -> Result<VideoImages, Error> {
+    if !(0.0..=1.0).contains(&threshold) {
+        return Err(Error::InvalidSettings(
+            "The similarity threshold must be between 0 and 1".to_string(),
+        ));
+    }
+
+    let video = queries::get(&state.db, auth, &id).await?;
+    let mut images = video
+        .images
+        .filter(|i| !i.similarity.is_empty())
+        .ok_or(Error::NotFound("Video image similarity scores"))?;
+
+    images.removed = images.removed_images(threshold);
+    images.similarity_threshold = Some(threshold);
+
+    sqlx::query!(
+        "UPDATE videos
+        SET images = images || $2
+        WHERE id = $1",
+        id.as_uuid(),
+        serde_json::json!({
+            "removed": images.removed,
+            "similarity_threshold": threshold,
+        })
+    )
+    .execute(&state.db)
+    .await
+    .change_context(Error::Db)?;
+
+    Ok(images)
+}
+
+impl VideoMetadata {
+    /// The name to show for a speaker in the transcript
+    pub fn speaker_name(&self, speaker: u32) -> String {
+        self.speaker_names
+            .get(&speaker)
+            .cloned()
+            .unwrap_or_else(|| format!("Speaker {speaker}"))
+    }
+}
+
+impl VideoImages {
+    /// The recognized text of the kept images shown between `start` and `end`, in seconds.
+    pub fn text_between(&self, start: f64, end: f64) -> Vec<&str> {
+        let Some(text) = &self.text else {
+            return Vec::new();
+        };
+
+        let range = self.images_between(start, end);
+        text.range(*range.start() as u32..=*range.end() as u32)
+            .filter(|(index, _)| !self.removed.contains(index))
+            .map(|(_, text)| text.as_str())
+            .collect()
+    }
+
+    /// Find the images that should be hidden at the given similarity threshold, when the
+    /// threshold is changed after the video was analyzed.
+    ///
+    /// The analyze job removes an image when it is too similar to the last kept image, but only
+    /// the similarity between neighboring images is stored. This approximates that rule by adding
+    /// up the differences between neighbors, so it can keep a different set of images than the
+    /// analysis did at the same threshold.
+    ///
+    /// An image is kept once the changes between it and the last kept image add up to more than
+    /// the threshold allows, so a slide that is built up a little at a time still gets an image
+    /// once it is different enough from where it started.
+    pub fn removed_images(&self, threshold: f64) -> Vec<u32> {
+        let max_change = 1.0 - threshold;
+        let mut change = 0.0;
+        let mut removed = Vec::new();
+
+        // The first image is always kept.
+        for (i, score) in self.similarity.iter().enumerate().skip(1) {
+            change += 1.0 - score;
+            if change <= max_change {
+                removed.push(i as u32 + 1);
+            } else {
+                change = 0.0;
+            }
         }
-        "transcribe" => {
-            crate::jobs::transcribe::enqueue(
-                &state,
-                id,
-                &TranscribeJobPayload {
-                    id,
-                    audio_path: format!("{storage_prefix}/audio.mp4"),
-                    storage_prefix,
-                },
-            )
-            .await
+
+        removed
+    }
+
+    /// The range of image indexes to show alongside the part of the video between `start` and
+    /// `end`, in seconds.
+    pub fn images_between(&self, start: f64, end: f64) -> RangeInclusive<usize> {
+        let max_index = self.max_index.max(1);
+
+        if self.timestamps.is_empty() {
+            // Older videos only have the interval to go on.
+            let interval = self.interval.max(1) as f64;
+            let first = ((start / interval).ceil() as usize).clamp(1, max_index);
+            let last = ((end / interval).floor() as usize).clamp(1, max_index);
+            return first..=last;
         }
-        "summarize" => {
-            crate::jobs::summarize::enqueue(&state, id, &SummarizeJobPayload { id }).await
+
+        let timestamps = &self.timestamps[..self.timestamps.len().min(max_index)];
+        // Indexes are 1-based, so the index of the first image at or after `start` is the number
+        // of images before it, plus one.
+        let first = timestamps.partition_point(|&t| t < start) + 1;
+        let last = timestamps.partition_point(|&t| t < end);
+
+        if first > last {
+            // No image starts within this range, so show the one that was on screen at the start.
+            let on_screen = timestamps.partition_point(|&t| t <= start).max(1);
+            on_screen..=on_screen
+        } else {
+            first..=last
         }
-        _ => return Err(Error::NotFound("Unknown stage")),
-    };
+    }
+}
 
-    let job_id = result.change_context(Error::TaskQueue)?;
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn images_between_with_timestamps() {
+        let images = VideoImages {
+            max_index: 4,
+            timestamps: vec![0.0, 4.5, 30.0, 31.0],
+            ..Default::default()
+        };
+
+        assert_eq!(images.images_between(0.0, 10.0), 1..=2);
+        assert_eq!(images.images_between(10.0, 30.5), 3..=3);
+        assert_eq!(images.images_between(12.0, 20.0), 2..=2);
+        assert_eq!(images.images_between(31.0, 60.0), 4..=4);
+    }
+
+    #[test]
+    fn speaker_names() {
+        let metadata = VideoMetadata {
+            speaker_names: BTreeMap::from([(1, "Alex".to_string())]),
+            ..Default::default()
+        };
+
+        assert_eq!(metadata.speaker_name(0), "Speaker 0");
+        assert_eq!(metadata.speaker_name(1), "Alex");
+    }
+
+    #[test]
+    fn removed_images() {
+        let images = VideoImages {
+            max_index: 6,
+            similarity: vec![0.0, 0.95, 0.93, 0.5, 0.99, 0.8],
+            ..Default::default()
+        };
+
+        assert_eq!(images.removed_images(0.9), vec![2, 5]);
+        assert_eq!(images.removed_images(0.75), vec![2, 3, 5, 6]);
+        assert_eq!(images.removed_images(1.0), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn images_between_with_interval() {
+        let images = VideoImages {
+            max_index: 5,
+            interval: 10,
+            ..Default::default()
+        };
 
-    Ok(job_id)
+        assert_eq!(images.images_between(0.0, 25.0), 1..=2);
+        assert_eq!(images.images_between(25.0, 100.0), 3..=5);
+    }
 }
@@ -103,6 +103,8 @@ pub struct ListQueryFilters {
     pub id: Vec<VideoId>,
     #[serde(default)]
     pub read: Option<bool>,
+    #[serde(default)]
+    pub collection_id: Option<crate::models::collection::CollectionId>,
     pub updated_at_lte: Option<chrono::DateTime<chrono::Utc>>,
     pub updated_at_gte: Option<chrono::DateTime<chrono::Utc>>,
     pub created_at_lte: Option<chrono::DateTime<chrono::Utc>>,
@@ -121,6 +123,10 @@ impl ListQueryFilters {
             bindings.add_option("read", &self.read, BindingOperator::Eq);
         }
 
+        if self.collection_id.is_some() {
+            bindings.add_option("collection_id", &self.collection_id, BindingOperator::Eq);
+        }
+
         if self.updated_at_lte.is_some() {
             bindings.add_option("updated_at", &self.updated_at_lte, BindingOperator::Lte);
         }
@@ -164,6 +170,11 @@ impl ListQueryFilters {
             query = query.bind(&self.read);
         }
 
+        if self.collection_id.is_some() {
+            event!(Level::DEBUG, collection_id = ?self.collection_id);
+            query = query.bind(&self.collection_id);
+        }
+
         if self.updated_at_lte.is_some() {
             event!(Level::DEBUG, updated_at_lte = ?self.updated_at_lte);
             query = query.bind(&self.updated_at_lte);
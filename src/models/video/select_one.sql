@@ -13,9 +13,13 @@ SELECT
   read,
   progress,
   images AS "images: crate::models::video::VideoImages",
-  transcript,
+  transcript AS "transcript: crate::models::video::VideoTranscript",
   summary,
+  summary_sections AS "summary_sections: crate::models::video::VideoSummarySections",
   processed_path,
+  collection_id AS "collection_id: crate::models::collection::CollectionId",
+  extractor,
+  extractor_id,
   _permission AS "_permission!: filigree::auth::ObjectPermission"
 FROM
   public.videos tb
@@ -0,0 +1,169 @@
+//! Identifying the video that a URL points to
+//!
+//! The same video can be added through many different URLs, such as `youtu.be/X`,
+//! `youtube.com/watch?v=X&t=30`, and `m.youtube.com/watch?v=X`. URLs from known sites are
+//! canonicalized when the video is added, so that a video which was already added can be found
+//! right away. Videos from other sites are identified by the download job, which checks for
+//! duplicates again before the video is processed.
+
+use url::Url;
+
+/// The name that yt-dlp uses for its YouTube extractor
+const YOUTUBE_EXTRACTOR: &str = "Youtube";
+
+/// Query parameters that only track where a link was shared from
+const TRACKING_PARAMS: &[&str] = &["fbclid", "gclid", "igshid", "mc_cid", "mc_eid"];
+
+/// The canonical URL of a video, along with the site and ID that identify it when they are known
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VideoSource {
+    pub url: String,
+    /// The yt-dlp extractor for the video's site
+    pub extractor: Option<String>,
+    /// The ID of the video on its site
+    pub extractor_id: Option<String>,
+}
+
+impl VideoSource {
+    /// Canonicalize a URL without any network access. YouTube URLs are identified from the URL
+    /// itself, and other URLs only have their fragment and tracking parameters removed.
+    pub fn from_url(url: &str) -> Self {
+        let url = url.trim();
+        let parsed = Url::parse(url)
+            .ok()
+            .filter(|u| matches!(u.scheme(), "http" | "https"));
+        let Some(parsed) = parsed else {
+            return Self {
+                url: url.to_string(),
+                extractor: None,
+                extractor_id: None,
+            };
+        };
+
+        if let Some(id) = youtube_id(&parsed) {
+            return Self::youtube(&id);
+        }
+
+        Self {
+            url: strip_url(parsed),
+            extractor: None,
+            extractor_id: None,
+        }
+    }
+
+    /// Create the source for a video from the information that yt-dlp returned for it.
+    pub fn from_info(webpage_url: &str, extractor: String, extractor_id: String) -> Self {
+        Self {
+            url: Self::from_url(webpage_url).url,
+            extractor: Some(extractor),
+            extractor_id: Some(extractor_id),
+        }
+    }
+
+    fn youtube(id: &str) -> Self {
+        Self {
+            url: format!("https://www.youtube.com/watch?v={id}"),
+            extractor: Some(YOUTUBE_EXTRACTOR.to_string()),
+            extractor_id: Some(id.to_string()),
+        }
+    }
+}
+
+/// Read the video ID from any of the forms that YouTube uses for links to a single video.
+fn youtube_id(url: &Url) -> Option<String> {
+    let host = url.host_str()?;
+    let host = host.strip_prefix("www.").unwrap_or(host);
+
+    let id = match host {
+        "youtu.be" => url.path_segments()?.next()?.to_string(),
+        "youtube.com" | "m.youtube.com" | "music.youtube.com" | "youtube-nocookie.com" => {
+            let mut segments = url.path_segments()?;
+            match segments.next()? {
+                "watch" => url
+                    .query_pairs()
+                    .find(|(key, _)| key == "v")
+                    .map(|(_, value)| value.into_owned())?,
+                "shorts" | "live" | "embed" | "v" => segments.next()?.to_string(),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+
+    is_youtube_id(&id).then_some(id)
+}
+
+/// YouTube video IDs are 11 characters from the URL-safe base64 alphabet.
+fn is_youtube_id(id: &str) -> bool {
+    id.len() == 11
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Remove the parts of a URL that don't change which video it points to.
+fn strip_url(mut url: Url) -> String {
+    url.set_fragment(None);
+
+    let params = url
+        .query_pairs()
+        .filter(|(key, _)| !key.starts_with("utm_") && !TRACKING_PARAMS.contains(&key.as_ref()))
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect::<Vec<_>>();
+    if params.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(params);
+    }
+
+    url.to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn youtube_urls() {
+        let expected = VideoSource::youtube("dQw4w9WgXcQ");
+        for url in [
+            "https://youtu.be/dQw4w9WgXcQ",
+            "https://youtu.be/dQw4w9WgXcQ?si=abcdef",
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=30",
+            "https://youtube.com/watch?feature=shared&v=dQw4w9WgXcQ",
+            "https://m.youtube.com/watch?v=dQw4w9WgXcQ",
+            "https://music.youtube.com/watch?v=dQw4w9WgXcQ&list=RDdQw4w9WgXcQ",
+            "https://www.youtube.com/shorts/dQw4w9WgXcQ",
+            "https://www.youtube-nocookie.com/embed/dQw4w9WgXcQ",
+            "  https://www.youtube.com/watch?v=dQw4w9WgXcQ#comments ",
+        ] {
+            assert_eq!(VideoSource::from_url(url), expected, "{url}");
+        }
+    }
+
+    #[test]
+    fn youtube_non_video_urls() {
+        for url in [
+            "https://www.youtube.com/playlist?list=PL12345",
+            "https://www.youtube.com/@channel",
+            "https://www.youtube.com/watch?v=tooshort",
+        ] {
+            let source = VideoSource::from_url(url);
+            assert_eq!(source.extractor, None, "{url}");
+            assert_eq!(source.url, url, "{url}");
+        }
+    }
+
+    #[test]
+    fn other_urls() {
+        assert_eq!(
+            VideoSource::from_url("https://Vimeo.com/12345?utm_source=feed&fbclid=x#t=10").url,
+            "https://vimeo.com/12345"
+        );
+        assert_eq!(
+            VideoSource::from_url("https://example.com/talk?id=5&utm_medium=email").url,
+            "https://example.com/talk?id=5"
+        );
+        assert_eq!(VideoSource::from_url("not a url").url, "not a url");
+    }
+}
@@ -0,0 +1,650 @@
+//! A provider-neutral representation of a video transcript.
+//!
+//! Transcription providers each return their own format. Those responses are converted into a
+//! [VideoTranscript] before being stored, so that the rest of the application doesn't need to
+//! know which provider created a transcript. Rows stored before this type existed contain the raw
+//! provider response, tagged with a `_provider_format` field. These are converted when they are
+//! read, and the `upgrade_transcripts` job writes the converted transcripts back.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use sqlx_transparent_json_decode::sqlx_json_decode;
+use tracing::{event, Level};
+
+use crate::llm::estimate_tokens;
+
+/// Start a new paragraph when there is a pause in speech at least this long, in seconds.
+const PARAGRAPH_PAUSE: f64 = 2.0;
+/// Start a new paragraph after this many sentences, even if there was no pause.
+const MAX_PARAGRAPH_SENTENCES: usize = 6;
+/// Subtitle cues are joined into sentences at punctuation, but automatic captions have none, so
+/// a sentence also ends once it is this long, in seconds.
+const MAX_SUBTITLE_SENTENCE: f64 = 15.0;
+
+#[derive(thiserror::Error, Debug)]
+pub enum TranscriptFormatError {
+    #[error("Unknown transcript format {0}")]
+    UnknownFormat(String),
+    #[error("Transcript was missing {0}")]
+    Missing(&'static str),
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, schemars::JsonSchema)]
+pub struct TranscriptWord {
+    pub word: String,
+    pub start: f64,
+    pub end: f64,
+    pub confidence: Option<f64>,
+    pub speaker: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, schemars::JsonSchema)]
+pub struct TranscriptSentence {
+    pub text: String,
+    pub start: f64,
+    pub end: f64,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, schemars::JsonSchema)]
+pub struct TranscriptParagraph {
+    pub start: f64,
+    pub end: f64,
+    pub speaker: Option<u32>,
+    pub sentences: Vec<TranscriptSentence>,
+}
+
+impl TranscriptParagraph {
+    /// The text of the paragraph
+    pub fn text(&self) -> String {
+        self.sentences
+            .iter()
+            .map(|s| s.text.trim())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// A contiguous range of paragraphs from a transcript
+#[derive(Debug, Clone, PartialEq)]
+pub struct TranscriptChunk {
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, schemars::JsonSchema)]
+#[serde(remote = "Self")]
+pub struct VideoTranscript {
+    /// The provider format that this transcript was converted from
+    pub source_format: String,
+    pub language: Option<String>,
+    /// Overall confidence in the transcript, if the provider reports it
+    pub confidence: Option<f64>,
+    /// The speakers detected in the audio, if the provider supports diarization
+    #[serde(default)]
+    pub speakers: Vec<u32>,
+    pub paragraphs: Vec<TranscriptParagraph>,
+    #[serde(default)]
+    pub words: Vec<TranscriptWord>,
+}
+
+sqlx_json_decode!(VideoTranscript);
+
+impl Serialize for VideoTranscript {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        VideoTranscript::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for VideoTranscript {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        if let Some(format) = value.get("_provider_format") {
+            // This is a raw provider response from before transcripts were normalized. If it
+            // can't be converted, return an empty transcript so that the rest of the video can
+            // still be read.
+            let transcript = VideoTranscript::from_provider_response(&value).unwrap_or_else(|e| {
+                event!(Level::ERROR, err=%e, "Failed to convert stored transcript");
+                VideoTranscript {
+                    source_format: format.as_str().unwrap_or_default().to_string(),
+                    ..Default::default()
+                }
+            });
+            Ok(transcript)
+        } else {
+            VideoTranscript::deserialize(value).map_err(serde::de::Error::custom)
+        }
+    }
+}
+
+impl VideoTranscript {
+    /// Convert a response from a transcription provider, tagged with its `_provider_format`.
+    pub fn from_provider_response(value: &Value) -> Result<Self, TranscriptFormatError> {
+        let format = value["_provider_format"]
+            .as_str()
+            .ok_or(TranscriptFormatError::Missing("_provider_format"))?;
+
+        match format {
+            "deepgram_v1" => Self::from_deepgram(value),
+            "openai_verbose_v1" => Self::from_openai_verbose(value),
+            "whisper_cpp_v1" => Self::from_whisper_cpp(value),
+            _ => Err(TranscriptFormatError::UnknownFormat(format.to_string())),
+        }
+    }
+
+    /// Convert a WebVTT or SRT subtitle file. Automatic captions repeat each line in the next
+    /// cue as they scroll, so repeated lines are removed.
+    pub fn from_subtitles(
+        text: &str,
+        language: Option<&str>,
+    ) -> Result<Self, TranscriptFormatError> {
+        let text = text.replace("\r\n", "\n");
+        let mut cues: Vec<TranscriptSentence> = Vec::new();
+        let mut last_line = String::new();
+
+        for block in text.split("\n\n") {
+            let mut lines = block.lines().skip_while(|line| !line.contains("-->"));
+            let Some((start, end)) = lines.next().and_then(parse_cue_timing) else {
+                // The header, a comment, or a style block
+                continue;
+            };
+
+            let mut cue_text = Vec::new();
+            for line in lines {
+                let line = strip_subtitle_markup(line);
+                if line.is_empty() || line == last_line {
+                    continue;
+                }
+
+                cue_text.push(line.clone());
+                last_line = line;
+            }
+
+            if !cue_text.is_empty() {
+                cues.push(TranscriptSentence {
+                    text: cue_text.join(" "),
+                    start,
+                    end,
+                });
+            }
+        }
+
+        if cues.is_empty() {
+            return Err(TranscriptFormatError::Missing("subtitle cues"));
+        }
+
+        Ok(Self {
+            source_format: "subtitles".to_string(),
+            language: language.map(String::from),
+            confidence: None,
+            speakers: Vec::new(),
+            paragraphs: group_into_paragraphs(join_cues_into_sentences(cues)),
+            words: Vec::new(),
+        })
+    }
+
+    /// The full text of the transcript, with paragraphs separated by blank lines.
+    pub fn text(&self) -> String {
+        self.paragraphs
+            .iter()
+            .map(|p| p.text())
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+
+    /// The part of the transcript made up of the paragraphs that start at or after `start` and
+    /// before `end`, in seconds.
+    pub fn excerpt(&self, start: f64, end: f64) -> VideoTranscript {
+        VideoTranscript {
+            paragraphs: self
+                .paragraphs
+                .iter()
+                .filter(|p| p.start >= start && p.start < end)
+                .cloned()
+                .collect(),
+            words: self
+                .words
+                .iter()
+                .filter(|w| w.start >= start && w.start < end)
+                .cloned()
+                .collect(),
+            ..self.clone_metadata()
+        }
+    }
+
+    /// A copy of the transcript without any of the text
+    fn clone_metadata(&self) -> VideoTranscript {
+        VideoTranscript {
+            source_format: self.source_format.clone(),
+            language: self.language.clone(),
+            confidence: self.confidence,
+            speakers: self.speakers.clone(),
+            paragraphs: Vec::new(),
+            words: Vec::new(),
+        }
+    }
+
+    /// Split the transcript into chunks of roughly `max_tokens` or less, breaking only between
+    /// paragraphs. A chunk will also end at one of the `boundaries`, such as the start of a
+    /// chapter, if it is already at least half full. A single paragraph longer than `max_tokens`
+    /// becomes its own chunk.
+    pub fn chunks(&self, max_tokens: usize, boundaries: &[f64]) -> Vec<TranscriptChunk> {
+        let mut chunks = Vec::new();
+        let mut current: Option<(TranscriptChunk, usize)> = None;
+        let mut last_start = f64::NEG_INFINITY;
+
+        for paragraph in &self.paragraphs {
+            let text = paragraph.text();
+            let tokens = estimate_tokens(&text);
+            let previous_start = std::mem::replace(&mut last_start, paragraph.start);
+
+            if let Some((chunk, chunk_tokens)) = current.as_mut() {
+                // Whether a boundary falls between the previous paragraph and this one
+                let at_boundary = boundaries
+                    .iter()
+                    .any(|&b| b > previous_start && b <= paragraph.start);
+                let full = *chunk_tokens + tokens > max_tokens
+                    || (at_boundary && *chunk_tokens >= max_tokens / 2);
+
+                if !full {
+                    chunk.end = paragraph.end;
+                    chunk.text.push_str("\n\n");
+                    chunk.text.push_str(&text);
+                    *chunk_tokens += tokens;
+                    continue;
+                }
+
+                chunks.extend(current.take().map(|(chunk, _)| chunk));
+            }
+
+            current = Some((
+                TranscriptChunk {
+                    start: paragraph.start,
+                    end: paragraph.end,
+                    text,
+                },
+                tokens,
+            ));
+        }
+
+        chunks.extend(current.map(|(chunk, _)| chunk));
+        chunks
+    }
+
+    fn from_deepgram(value: &Value) -> Result<Self, TranscriptFormatError> {
+        let channel = &value["results"]["channels"][0];
+        let alternative = &channel["alternatives"][0];
+        let paragraphs = alternative["paragraphs"]["paragraphs"]
+            .as_array()
+            .ok_or(TranscriptFormatError::Missing("paragraphs"))?;
+
+        let paragraphs = paragraphs
+            .iter()
+            .map(|p| TranscriptParagraph {
+                start: p["start"].as_f64().unwrap_or(0.0),
+                end: p["end"].as_f64().unwrap_or(0.0),
+                speaker: p["speaker"].as_u64().map(|s| s as u32),
+                sentences: p["sentences"]
+                    .as_array()
+                    .map(|sentences| sentences.iter().map(sentence_from_json).collect())
+                    .unwrap_or_default(),
+            })
+            .collect::<Vec<_>>();
+
+        let words = alternative["words"]
+            .as_array()
+            .map(|words| {
+                words
+                    .iter()
+                    .map(|w| TranscriptWord {
+                        word: w["punctuated_word"]
+                            .as_str()
+                            .or_else(|| w["word"].as_str())
+                            .unwrap_or_default()
+                            .to_string(),
+                        start: w["start"].as_f64().unwrap_or(0.0),
+                        end: w["end"].as_f64().unwrap_or(0.0),
+                        confidence: w["confidence"].as_f64(),
+                        speaker: w["speaker"].as_u64().map(|s| s as u32),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(Self {
+            source_format: "deepgram_v1".to_string(),
+            language: channel["detected_language"].as_str().map(String::from),
+            confidence: alternative["confidence"].as_f64(),
+            speakers: collect_speakers(&paragraphs),
+            paragraphs,
+            words,
+        })
+    }
+
+    /// The verbose JSON format from the OpenAI transcription API, which is also written by the
+    /// Python whisper CLI.
+    fn from_openai_verbose(value: &Value) -> Result<Self, TranscriptFormatError> {
+        let segments = value["segments"]
+            .as_array()
+            .ok_or(TranscriptFormatError::Missing("segments"))?;
+        let sentences = segments.iter().map(sentence_from_json).collect::<Vec<_>>();
+
+        // The API returns words at the top level, and the CLI nests them in each segment.
+        let words = match value["words"].as_array() {
+            Some(words) => words.iter().collect::<Vec<_>>(),
+            None => segments
+                .iter()
+                .filter_map(|s| s["words"].as_array())
+                .flatten()
+                .collect(),
+        };
+        let words = words
+            .into_iter()
+            .map(|w| TranscriptWord {
+                word: w["word"].as_str().unwrap_or_default().trim().to_string(),
+                start: w["start"].as_f64().unwrap_or(0.0),
+                end: w["end"].as_f64().unwrap_or(0.0),
+                confidence: w["probability"].as_f64(),
+                speaker: None,
+            })
+            .collect();
+
+        Ok(Self {
+            source_format: "openai_verbose_v1".to_string(),
+            language: value["language"].as_str().map(String::from),
+            confidence: None,
+            speakers: Vec::new(),
+            paragraphs: group_into_paragraphs(sentences),
+            words,
+        })
+    }
+
+    fn from_whisper_cpp(value: &Value) -> Result<Self, TranscriptFormatError> {
+        let segments = value["transcription"]
+            .as_array()
+            .ok_or(TranscriptFormatError::Missing("transcription"))?;
+
+        // whisper.cpp reports offsets in milliseconds
+        let sentences = segments
+            .iter()
+            .map(|s| TranscriptSentence {
+                text: s["text"].as_str().unwrap_or_default().trim().to_string(),
+                start: s["offsets"]["from"].as_f64().unwrap_or(0.0) / 1000.0,
+                end: s["offsets"]["to"].as_f64().unwrap_or(0.0) / 1000.0,
+            })
+            .collect();
+
+        Ok(Self {
+            source_format: "whisper_cpp_v1".to_string(),
+            language: value["result"]["language"].as_str().map(String::from),
+            confidence: None,
+            speakers: Vec::new(),
+            paragraphs: group_into_paragraphs(sentences),
+            words: Vec::new(),
+        })
+    }
+}
+
+fn sentence_from_json(s: &Value) -> TranscriptSentence {
+    TranscriptSentence {
+        text: s["text"].as_str().unwrap_or_default().trim().to_string(),
+        start: s["start"].as_f64().unwrap_or(0.0),
+        end: s["end"].as_f64().unwrap_or(0.0),
+    }
+}
+
+/// Parse a cue timing line like `00:01:02.500 --> 00:01:04.000 align:start`. SRT uses a comma
+/// before the milliseconds, and WebVTT allows the hours to be left out.
+fn parse_cue_timing(line: &str) -> Option<(f64, f64)> {
+    let (start, rest) = line.split_once("-->")?;
+    let end = rest.split_whitespace().next()?;
+
+    let parse = |time: &str| {
+        time.trim()
+            .replace(',', ".")
+            .split(':')
+            .try_fold(0.0, |total, part| {
+                Some(total * 60.0 + part.parse::<f64>().ok()?)
+            })
+    };
+
+    Some((parse(start)?, parse(end)?))
+}
+
+/// Remove tags such as `<c>` and `<00:00:01.500>` from a subtitle line, and decode the common
+/// HTML entities.
+fn strip_subtitle_markup(line: &str) -> String {
+    let mut output = String::with_capacity(line.len());
+    let mut in_tag = false;
+    for c in line.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => output.push(c),
+            _ => {}
+        }
+    }
+
+    output
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+        .trim()
+        .to_string()
+}
+
+/// Join subtitle cues, which usually break in the middle of a sentence, into sentences.
+fn join_cues_into_sentences(cues: Vec<TranscriptSentence>) -> Vec<TranscriptSentence> {
+    let mut sentences: Vec<TranscriptSentence> = Vec::new();
+    let mut open = false;
+
+    for cue in cues {
+        match sentences.last_mut() {
+            Some(s)
+                if open
+                    && cue.start - s.end < PARAGRAPH_PAUSE
+                    && cue.end - s.start <= MAX_SUBTITLE_SENTENCE =>
+            {
+                s.text.push(' ');
+                s.text.push_str(&cue.text);
+                s.end = cue.end;
+            }
+            _ => sentences.push(cue),
+        }
+
+        let text = sentences
+            .last()
+            .map(|s| s.text.trim_end_matches(['"', '\'', ')']));
+        open = !text.is_some_and(|t| t.ends_with(['.', '?', '!']));
+    }
+
+    sentences
+}
+
+fn collect_speakers(paragraphs: &[TranscriptParagraph]) -> Vec<u32> {
+    let mut speakers = paragraphs
+        .iter()
+        .filter_map(|p| p.speaker)
+        .collect::<Vec<_>>();
+    speakers.sort_unstable();
+    speakers.dedup();
+    speakers
+}
+
+/// Group sentences into paragraphs, for providers that don't do it themselves. A new paragraph
+/// starts after a pause in speech or when the current paragraph gets too long.
+fn group_into_paragraphs(sentences: Vec<TranscriptSentence>) -> Vec<TranscriptParagraph> {
+    let mut paragraphs: Vec<TranscriptParagraph> = Vec::new();
+
+    for sentence in sentences.into_iter().filter(|s| !s.text.is_empty()) {
+        match paragraphs.last_mut() {
+            Some(p)
+                if sentence.start - p.end < PARAGRAPH_PAUSE
+                    && p.sentences.len() < MAX_PARAGRAPH_SENTENCES =>
+            {
+                p.end = sentence.end;
+                p.sentences.push(sentence);
+            }
+            _ => paragraphs.push(TranscriptParagraph {
+                start: sentence.start,
+                end: sentence.end,
+                speaker: None,
+                sentences: vec![sentence],
+            }),
+        }
+    }
+
+    paragraphs
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn upgrade_stored_deepgram_response() {
+        let stored = json!({
+            "_provider_format": "deepgram_v1",
+            "results": { "channels": [{ "alternatives": [{
+                "confidence": 0.98,
+                "words": [
+                    { "word": "hello", "punctuated_word": "Hello.", "start": 0.5, "end": 0.9, "confidence": 0.99, "speaker": 0 },
+                ],
+                "paragraphs": { "paragraphs": [
+                    { "start": 0.5, "end": 3.0, "speaker": 0, "sentences": [
+                        { "text": "Hello.", "start": 0.5, "end": 0.9 },
+                        { "text": "Welcome to the talk.", "start": 1.0, "end": 3.0 },
+                    ]},
+                    { "start": 4.0, "end": 5.0, "speaker": 1, "sentences": [
+                        { "text": "Thanks.", "start": 4.0, "end": 5.0 },
+                    ]},
+                ]},
+            }]}]},
+        });
+
+        let transcript: VideoTranscript = serde_json::from_value(stored).unwrap();
+        assert_eq!(transcript.source_format, "deepgram_v1");
+        assert_eq!(transcript.speakers, vec![0, 1]);
+        assert_eq!(transcript.words[0].word, "Hello.");
+        assert_eq!(transcript.text(), "Hello. Welcome to the talk.\n\nThanks.");
+
+        // Once normalized, it should round trip without being treated as a provider response.
+        let normalized = serde_json::to_value(&transcript).unwrap();
+        assert!(normalized.get("_provider_format").is_none());
+        let reread: VideoTranscript = serde_json::from_value(normalized).unwrap();
+        assert_eq!(reread, transcript);
+    }
+
+    #[test]
+    fn unknown_stored_format() {
+        let stored = json!({ "_provider_format": "assemblyai_v1", "text": "Hello." });
+
+        let transcript: VideoTranscript = serde_json::from_value(stored).unwrap();
+        assert_eq!(transcript.source_format, "assemblyai_v1");
+        assert!(transcript.paragraphs.is_empty());
+    }
+
+    fn paragraph(start: f64, text: &str) -> TranscriptParagraph {
+        TranscriptParagraph {
+            start,
+            end: start + 10.0,
+            speaker: None,
+            sentences: vec![TranscriptSentence {
+                text: text.to_string(),
+                start,
+                end: start + 10.0,
+            }],
+        }
+    }
+
+    #[test]
+    fn chunk_on_token_budget_and_boundaries() {
+        // Each paragraph is 10 tokens
+        let text = "a".repeat(40);
+        let transcript = VideoTranscript {
+            paragraphs: (0..6).map(|i| paragraph(i as f64 * 10.0, &text)).collect(),
+            ..Default::default()
+        };
+
+        let chunks = transcript.chunks(30, &[]);
+        assert_eq!(
+            chunks.iter().map(|c| (c.start, c.end)).collect::<Vec<_>>(),
+            vec![(0.0, 30.0), (30.0, 60.0)]
+        );
+
+        // The boundary at 20 ends the first chunk early since it is more than half full.
+        // The boundary at 30 is ignored since the second chunk is too small.
+        let chunks = transcript.chunks(30, &[20.0, 30.0]);
+        assert_eq!(
+            chunks.iter().map(|c| (c.start, c.end)).collect::<Vec<_>>(),
+            vec![(0.0, 20.0), (20.0, 50.0), (50.0, 60.0)]
+        );
+
+        assert_eq!(transcript.chunks(1000, &[]).len(), 1);
+    }
+
+    #[test]
+    fn group_segments_on_pauses() {
+        let response = json!({
+            "_provider_format": "openai_verbose_v1",
+            "language": "english",
+            "segments": [
+                { "text": " First.", "start": 0.0, "end": 1.0 },
+                { "text": " Second.", "start": 1.5, "end": 2.0 },
+                { "text": " After a pause.", "start": 5.0, "end": 6.0 },
+            ],
+        });
+
+        let transcript = VideoTranscript::from_provider_response(&response).unwrap();
+        assert_eq!(transcript.paragraphs.len(), 2);
+        assert_eq!(transcript.paragraphs[0].text(), "First. Second.");
+        assert_eq!(transcript.paragraphs[0].end, 2.0);
+        assert_eq!(transcript.paragraphs[1].text(), "After a pause.");
+    }
+
+    #[test]
+    fn automatic_captions() {
+        let vtt = "WEBVTT\nKind: captions\nLanguage: en\n\n\
+00:00:00.000 --> 00:00:02.000 align:start position:0%\n\
+so<00:00:00.500><c> today</c><00:00:01.000><c> we're</c>\n\n\
+00:00:02.000 --> 00:00:02.010 align:start position:0%\n\
+so today we're\n\n\
+00:00:02.010 --> 00:00:04.000 align:start position:0%\n\
+so today we're\n\
+talking about rust\n\n\
+00:00:10.000 --> 00:00:12.000\n\
+&gt;&gt; questions\n";
+
+        let transcript = VideoTranscript::from_subtitles(vtt, Some("en")).unwrap();
+        assert_eq!(transcript.source_format, "subtitles");
+        assert_eq!(transcript.language.as_deref(), Some("en"));
+        assert_eq!(transcript.paragraphs.len(), 2);
+        assert_eq!(
+            transcript.paragraphs[0].text(),
+            "so today we're talking about rust"
+        );
+        assert_eq!(transcript.paragraphs[0].start, 0.0);
+        assert_eq!(transcript.paragraphs[0].end, 4.0);
+        assert_eq!(transcript.paragraphs[1].text(), ">> questions");
+    }
+
+    #[test]
+    fn srt_subtitles() {
+        let srt = "1\r\n00:00:01,000 --> 00:00:02,500\r\nHello, and welcome\r\n\r\n\
+2\r\n00:00:02,500 --> 00:00:04,000\r\nto the talk.\r\n\r\n\
+3\r\n00:00:04,000 --> 00:00:05,000\r\n<i>Let's begin.</i>\r\n";
+
+        let transcript = VideoTranscript::from_subtitles(srt, None).unwrap();
+        assert_eq!(transcript.paragraphs.len(), 1);
+        let sentences = &transcript.paragraphs[0].sentences;
+        assert_eq!(sentences.len(), 2);
+        assert_eq!(sentences[0].text, "Hello, and welcome to the talk.");
+        assert_eq!((sentences[0].start, sentences[0].end), (1.0, 4.0));
+        assert_eq!(sentences[1].text, "Let's begin.");
+
+        assert!(VideoTranscript::from_subtitles("WEBVTT\n\n", None).is_err());
+    }
+}
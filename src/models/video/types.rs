@@ -1,4 +1,6 @@
 #![allow(unused_imports, dead_code)]
+use std::collections::BTreeMap;
+
 use filigree::auth::ObjectPermission;
 use serde::{
     ser::{SerializeStruct, Serializer},
@@ -7,7 +9,7 @@ use serde::{
 use sqlx_transparent_json_decode::sqlx_json_decode;
 
 use super::VideoId;
-use crate::models::organization::OrganizationId;
+use crate::{jobs::pipeline::Stage, models::organization::OrganizationId};
 
 #[derive(
     Serialize,
@@ -30,6 +32,10 @@ pub enum VideoProcessingState {
     Downloaded,
     Processing,
     Ready,
+    /// A processing stage failed and ran out of retries. Details are in [VideoMetadata::failure].
+    Failed,
+    /// Processing was stopped by the user. It can be started again by rerunning a stage.
+    Cancelled,
 }
 
 impl std::fmt::Display for VideoProcessingState {
@@ -40,46 +46,245 @@ impl std::fmt::Display for VideoProcessingState {
             VideoProcessingState::Downloaded => write!(f, "Downloaded"),
             VideoProcessingState::Processing => write!(f, "Processing"),
             VideoProcessingState::Ready => write!(f, "Ready"),
+            VideoProcessingState::Failed => write!(f, "Failed"),
+            VideoProcessingState::Cancelled => write!(f, "Cancelled"),
         }
     }
 }
 
 #[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, schemars::JsonSchema)]
 pub struct VideoChapter {
-    start_time: f32,
-    end_time: f32,
-    title: String,
+    pub start_time: f32,
+    pub end_time: f32,
+    pub title: String,
 }
 
 #[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, schemars::JsonSchema)]
 pub struct StageStats {
     pub duration: usize,
     pub filename: Option<String>,
+    /// The format that was chosen, as described by the downloader
+    pub format: Option<String>,
+    /// The size of the file in bytes
+    pub size: Option<u64>,
+}
+
+/// What a run of a processing stage used from a paid service, and what it cost
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, schemars::JsonSchema)]
+pub struct StageUsage {
+    pub stage: Stage,
+    /// The service that was used, such as "deepgram" or "anthropic"
+    pub provider: String,
+    pub model: Option<String>,
+    /// The provider's ID for the request, for matching against its billing records
+    pub request_id: Option<String>,
+    /// The number of requests sent to the provider
+    pub requests: u32,
+    /// The length of the transcribed audio in seconds
+    pub audio_seconds: Option<f64>,
+    pub input_tokens: Option<u64>,
+    pub output_tokens: Option<u64>,
+    /// The estimated cost in US dollars, from the configured prices. This is `None` when no
+    /// price is configured for the model.
+    pub cost: Option<f64>,
+    pub recorded_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// A container format to prefer when downloading a video
+#[derive(
+    clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, schemars::JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum VideoContainer {
+    Mp4,
+    Webm,
+    Mkv,
+}
+
+impl VideoContainer {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            VideoContainer::Mp4 => "mp4",
+            VideoContainer::Webm => "webm",
+            VideoContainer::Mkv => "mkv",
+        }
+    }
+}
+
+/// Download settings for a single video. Settings that aren't set use the server's defaults.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, schemars::JsonSchema)]
+pub struct DownloadOptions {
+    /// The largest video height to download, such as 720. Videos with no format that small are
+    /// downloaded at the smallest size available.
+    pub max_height: Option<u32>,
+    /// The container to prefer
+    pub container: Option<VideoContainer>,
+    /// Download only the audio, which skips the image stages
+    pub audio_only: Option<bool>,
+}
+
+impl DownloadOptions {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+/// Information about a processing stage that failed
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, schemars::JsonSchema)]
+pub struct StageFailure {
+    /// The name of the stage that failed, as passed to `rerun_stage`
+    pub stage: String,
+    /// The formatted error report, including any command output attached to it
+    pub error: String,
+    /// How many times the stage was attempted before giving up
+    pub attempts: i32,
+    pub failed_at: chrono::DateTime<chrono::Utc>,
 }
 
 #[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, schemars::JsonSchema)]
 pub struct VideoMetadata {
     pub download: Option<StageStats>,
+    /// The download settings that the video was added with
+    #[serde(default)]
+    pub download_options: DownloadOptions,
     pub audio_extraction: Option<StageStats>,
     pub image_extraction: Option<StageStats>,
+    /// The media has no video stream, so image extraction and analysis are skipped.
+    #[serde(default)]
+    pub audio_only: bool,
 
     pub chapters: Option<Vec<VideoChapter>>,
+    /// The description of the video from its source
+    pub description: Option<String>,
+
+    /// Set when the transcript came from the video's subtitles instead of speech recognition
+    pub subtitles: Option<SubtitleSource>,
+
+    /// Set when the video is in the [VideoProcessingState::Failed] state
+    pub failure: Option<StageFailure>,
+    /// Optional stages that failed. The video was processed without their results.
+    #[serde(default)]
+    pub optional_failures: Vec<StageFailure>,
+    /// The processing stages that have finished, including the ones that were skipped
+    #[serde(default)]
+    pub finished_stages: Vec<Stage>,
+    /// How far along the current download or extract stage is
+    pub stage_progress: Option<StageProgress>,
+    /// The usage of each run of a stage that used a paid service. Stages that are run again
+    /// add another entry, since each run is billed.
+    #[serde(default)]
+    pub usage: Vec<StageUsage>,
+
+    /// Names for the speakers in the transcript, which are otherwise shown as "Speaker N"
+    #[serde(default)]
+    pub speaker_names: BTreeMap<u32, String>,
 }
 
 sqlx_json_decode!(VideoMetadata);
 
-#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq, schemars::JsonSchema)]
+/// The progress of a stage that is running
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, schemars::JsonSchema)]
+pub struct StageProgress {
+    pub stage: Stage,
+    /// From 0 to 100
+    pub percent: f32,
+    /// The estimated number of seconds until the stage finishes
+    pub eta: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, schemars::JsonSchema)]
+pub struct SubtitleSource {
+    pub language: String,
+    /// The subtitles were generated automatically by the video site
+    pub automatic: bool,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, schemars::JsonSchema)]
 pub struct VideoImages {
     pub max_index: usize,
+    /// The number of seconds between images, when they were extracted at a fixed interval. This is
+    /// 0 when images were extracted at scene changes.
     pub interval: usize,
+    /// The time of each image in seconds, where the first entry is image 1. This is empty for
+    /// videos that were processed before timestamps were recorded.
+    #[serde(default)]
+    pub timestamps: Vec<f64>,
     #[serde(default)]
     pub thumbnail_widths: Vec<u32>,
     #[serde(default)]
     pub removed: Vec<u32>,
+    /// How similar each image is to the one before it, from 0 to 1, where the first entry is
+    /// image 1. This lets [VideoImages::removed_images] recalculate `removed` without
+    /// reprocessing the images.
+    #[serde(default)]
+    pub similarity: Vec<f64>,
+    /// The threshold that was used to calculate `removed`
+    #[serde(default)]
+    pub similarity_threshold: Option<f64>,
+    /// The perceptual hash of each image, where the first entry is image 1. This is only set
+    /// when images were deduplicated by hash.
+    #[serde(default)]
+    pub hashes: Vec<u64>,
+    /// For removed images that repeat an earlier image, the index of the image they repeat
+    #[serde(default)]
+    pub duplicate_of: BTreeMap<u32, u32>,
+    /// The text recognized in each image that had any, keyed by image index. This includes the
+    /// removed images. This is `None` until OCR has run.
+    #[serde(default)]
+    pub text: Option<BTreeMap<u32, String>>,
 }
 
 sqlx_json_decode!(VideoImages);
 
+/// A video that matched a search
+#[derive(Serialize, Deserialize, Debug, Clone, schemars::JsonSchema)]
+pub struct VideoSearchResult {
+    pub id: VideoId,
+    pub title: Option<String>,
+    /// The images whose text matched the search
+    pub images: Vec<i32>,
+}
+
+/// A summary of one section of a video
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, schemars::JsonSchema)]
+pub struct SectionSummary {
+    /// The start time of the section, in seconds
+    pub start: f64,
+    /// The end time of the section, in seconds
+    pub end: f64,
+    pub summary: String,
+}
+
+/// A summary of one chapter of a video
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, schemars::JsonSchema)]
+pub struct ChapterSummary {
+    pub title: String,
+    /// The start time of the chapter, in seconds
+    pub start: f64,
+    /// The end time of the chapter, in seconds
+    pub end: f64,
+    pub summary: String,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, schemars::JsonSchema)]
+pub struct VideoSummarySections {
+    /// The summaries of each transcript chunk, when the transcript or a chapter was too long to
+    /// summarize at once
+    #[serde(default)]
+    pub chunks: Vec<SectionSummary>,
+    /// The summaries of each chapter, for videos with chapters
+    #[serde(default)]
+    pub chapters: Vec<ChapterSummary>,
+}
+
+impl VideoSummarySections {
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty() && self.chapters.is_empty()
+    }
+}
+
+sqlx_json_decode!(VideoSummarySections);
+
 #[derive(Deserialize, Debug, Clone, schemars::JsonSchema, sqlx::FromRow)]
 
 pub struct Video {
@@ -97,9 +302,13 @@ pub struct Video {
     pub read: bool,
     pub progress: i32,
     pub images: Option<crate::models::video::VideoImages>,
-    pub transcript: Option<serde_json::Value>,
+    pub transcript: Option<crate::models::video::VideoTranscript>,
     pub summary: Option<String>,
+    pub summary_sections: Option<crate::models::video::VideoSummarySections>,
     pub processed_path: Option<String>,
+    pub collection_id: Option<crate::models::collection::CollectionId>,
+    pub extractor: Option<String>,
+    pub extractor_id: Option<String>,
     pub _permission: ObjectPermission,
 }
 
@@ -167,7 +376,7 @@ impl Video {
         None
     }
 
-    pub fn default_transcript() -> Option<serde_json::Value> {
+    pub fn default_transcript() -> Option<crate::models::video::VideoTranscript> {
         None
     }
 
@@ -175,9 +384,25 @@ impl Video {
         None
     }
 
+    pub fn default_summary_sections() -> Option<crate::models::video::VideoSummarySections> {
+        None
+    }
+
     pub fn default_processed_path() -> Option<String> {
         None
     }
+
+    pub fn default_collection_id() -> Option<crate::models::collection::CollectionId> {
+        None
+    }
+
+    pub fn default_extractor() -> Option<String> {
+        None
+    }
+
+    pub fn default_extractor_id() -> Option<String> {
+        None
+    }
 }
 
 sqlx_json_decode!(Video);
@@ -201,7 +426,11 @@ impl Default for Video {
             images: Self::default_images(),
             transcript: Self::default_transcript(),
             summary: Self::default_summary(),
+            summary_sections: Self::default_summary_sections(),
             processed_path: Self::default_processed_path(),
+            collection_id: Self::default_collection_id(),
+            extractor: Self::default_extractor(),
+            extractor_id: Self::default_extractor_id(),
             _permission: ObjectPermission::Owner,
         }
     }
@@ -212,7 +441,7 @@ impl Serialize for Video {
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("Video", 18)?;
+        let mut state = serializer.serialize_struct("Video", 22)?;
         state.serialize_field("id", &self.id)?;
         state.serialize_field("organization_id", &self.organization_id)?;
         state.serialize_field("updated_at", &self.updated_at)?;
@@ -229,7 +458,11 @@ impl Serialize for Video {
         state.serialize_field("images", &self.images)?;
         state.serialize_field("transcript", &self.transcript)?;
         state.serialize_field("summary", &self.summary)?;
+        state.serialize_field("summary_sections", &self.summary_sections)?;
         state.serialize_field("processed_path", &self.processed_path)?;
+        state.serialize_field("collection_id", &self.collection_id)?;
+        state.serialize_field("extractor", &self.extractor)?;
+        state.serialize_field("extractor_id", &self.extractor_id)?;
         state.serialize_field("_permission", &self._permission)?;
         state.end()
     }
@@ -298,6 +531,9 @@ pub struct VideoListResult {
     pub progress: i32,
     pub summary: Option<String>,
     pub processed_path: Option<String>,
+    pub collection_id: Option<crate::models::collection::CollectionId>,
+    pub extractor: Option<String>,
+    pub extractor_id: Option<String>,
     pub _permission: ObjectPermission,
 }
 
@@ -366,6 +602,18 @@ impl VideoListResult {
     pub fn default_processed_path() -> Option<String> {
         None
     }
+
+    pub fn default_collection_id() -> Option<crate::models::collection::CollectionId> {
+        None
+    }
+
+    pub fn default_extractor() -> Option<String> {
+        None
+    }
+
+    pub fn default_extractor_id() -> Option<String> {
+        None
+    }
 }
 
 sqlx_json_decode!(VideoListResult);
@@ -388,6 +636,9 @@ impl Default for VideoListResult {
             progress: Self::default_progress(),
             summary: Self::default_summary(),
             processed_path: Self::default_processed_path(),
+            collection_id: Self::default_collection_id(),
+            extractor: Self::default_extractor(),
+            extractor_id: Self::default_extractor_id(),
             _permission: ObjectPermission::Owner,
         }
     }
@@ -398,7 +649,7 @@ impl Serialize for VideoListResult {
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("VideoListResult", 16)?;
+        let mut state = serializer.serialize_struct("VideoListResult", 19)?;
         state.serialize_field("id", &self.id)?;
         state.serialize_field("organization_id", &self.organization_id)?;
         state.serialize_field("updated_at", &self.updated_at)?;
@@ -414,6 +665,9 @@ impl Serialize for VideoListResult {
         state.serialize_field("progress", &self.progress)?;
         state.serialize_field("summary", &self.summary)?;
         state.serialize_field("processed_path", &self.processed_path)?;
+        state.serialize_field("collection_id", &self.collection_id)?;
+        state.serialize_field("extractor", &self.extractor)?;
+        state.serialize_field("extractor_id", &self.extractor_id)?;
         state.serialize_field("_permission", &self._permission)?;
         state.end()
     }
@@ -1,5 +1,5 @@
 #![allow(unused_imports)]
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 use axum::{
     extract::{Path, State},
@@ -15,8 +15,11 @@ use schemars::JsonSchema;
 
 use crate::{
     auth::{has_any_permission, Authed},
-    models::video::{Video, VideoId},
-    pages::{auth::WebAuthed, error::HtmlError, layout::root_layout_page},
+    models::video::{
+        ChapterSummary, Video, VideoChapter, VideoId, VideoImages, VideoMetadata,
+        VideoProcessingState,
+    },
+    pages::{auth::WebAuthed, error::HtmlError, layout::root_layout_page, VideoDuration},
     server::ServerState,
     Error,
 };
@@ -58,8 +61,71 @@ async fn mark_read_action(
     Ok(body)
 }
 
+async fn rerun_stage_action(
+    State(state): State<ServerState>,
+    auth: Authed,
+    Path((doc_id, stage)): Path<(crate::models::video::VideoId, String)>,
+) -> Result<impl IntoResponse, Error> {
+    crate::models::video::rerun_stage(&state, &auth, doc_id, &stage, false).await?;
+
+    Ok(html! {
+        section #failure .alert.alert-info."max-w-[90ch]" {
+            p { "Retrying " (stage) "..." }
+        }
+    })
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, JsonSchema)]
+pub struct SimilarityThresholdActionPayload {
+    pub threshold: f64,
+}
+
+async fn similarity_threshold_action(
+    State(state): State<ServerState>,
+    auth: Authed,
+    Path(doc_id): Path<crate::models::video::VideoId>,
+    form: Form<SimilarityThresholdActionPayload>,
+) -> Result<impl IntoResponse, Error> {
+    crate::models::video::set_similarity_threshold(&state, &auth, doc_id, form.threshold).await?;
+
+    // The threshold changes which images are removed, so render the images again.
+    let video = crate::models::video::queries::get(&state.db, &auth, &doc_id).await?;
+    Ok(sections_fragment(doc_id, &video))
+}
+
+/// Save the speaker names from the form, where each field is named `speaker-N`.
+async fn speaker_names_action(
+    State(state): State<ServerState>,
+    auth: Authed,
+    Path(doc_id): Path<crate::models::video::VideoId>,
+    form: Form<HashMap<String, String>>,
+) -> Result<impl IntoResponse, Error> {
+    let names = form
+        .0
+        .into_iter()
+        .filter_map(|(field, name)| {
+            let speaker = field.strip_prefix("speaker-")?.parse::<u32>().ok()?;
+            Some((speaker, name))
+        })
+        .collect();
+    crate::models::video::set_speaker_names(&state, &auth, doc_id, names).await?;
+
+    // The names appear throughout the transcript, so just reload the page.
+    Ok([("HX-Refresh", "true")])
+}
+
+/// The Alpine state for the page
+fn page_data(images: &VideoImages) -> String {
+    format!(
+        "{{ large_image: null, max_index: {max_index}, show_removed: false }}",
+        max_index = images.max_index,
+    )
+}
+
 struct ImageChunk {
     text: String,
+    speaker: Option<u32>,
+    start_time: f64,
     start_image_idx: u64,
     end_image_idx: u64,
 }
@@ -69,59 +135,201 @@ fn align(video: &Video) -> Vec<ImageChunk> {
         return vec![];
     };
 
-    let Some(paragraphs) = transcript["results"]["channels"][0]["alternatives"][0]["paragraphs"]
-        ["paragraphs"]
-        .as_array()
-    else {
-        return vec![];
-    };
-
-    let interval = images.interval as f64;
-    let output = paragraphs
-        .into_iter()
-        .filter_map(|p| {
-            let text = p["sentences"]
-                .as_array()?
-                .iter()
-                .filter_map(|s| s["text"].as_str())
-                .join(" ");
-
-            let start_time = p["start"].as_f64().unwrap_or(0.0);
-            let end_time = p["end"].as_f64().unwrap_or(0.0);
+    // Speaker labels are only useful when there is more than one speaker.
+    let show_speakers = transcript.speakers.len() > 1;
 
-            let start_image_idx = ((start_time / interval).ceil() as u64)
-                .max(1)
-                .min(images.max_index as u64);
-            let end_image_idx = ((end_time / interval).floor() as u64)
-                .max(1)
-                .min(images.max_index as u64);
+    let output = transcript
+        .paragraphs
+        .iter()
+        .map(|p| {
+            let text = p.text();
+            let start_time = p.start;
+            let image_range = images.images_between(p.start, p.end);
+            let start_image_idx = *image_range.start() as u64;
+            let end_image_idx = *image_range.end() as u64;
 
-            Some(ImageChunk {
+            ImageChunk {
                 text,
+                speaker: p.speaker.filter(|_| show_speakers),
+                start_time,
                 start_image_idx,
                 end_image_idx,
-            })
+            }
         })
         .collect();
 
     output
 }
 
+/// The aligned chunks belonging to a chapter of the video
+struct ChapterSection<'a> {
+    chapter: Option<&'a VideoChapter>,
+    summary: Option<&'a ChapterSummary>,
+    chunks: Vec<ImageChunk>,
+}
+
+/// Group the aligned chunks by chapter. Videos without chapters get a single section.
+fn chapter_sections<'a>(
+    chapters: &'a [VideoChapter],
+    summaries: &'a [ChapterSummary],
+    aligned: Vec<ImageChunk>,
+) -> Vec<ChapterSection<'a>> {
+    if chapters.is_empty() {
+        return vec![ChapterSection {
+            chapter: None,
+            summary: None,
+            chunks: aligned,
+        }];
+    }
+
+    let mut sections = chapters
+        .iter()
+        .map(|chapter| ChapterSection {
+            chapter: Some(chapter),
+            summary: summaries
+                .iter()
+                .find(|s| s.start == chapter.start_time as f64),
+            chunks: vec![],
+        })
+        .collect::<Vec<_>>();
+
+    for chunk in aligned {
+        let idx = chapters
+            .iter()
+            .rposition(|c| c.start_time as f64 <= chunk.start_time)
+            .unwrap_or(0);
+        sections[idx].chunks.push(chunk);
+    }
+
+    sections
+}
+
+fn aligned_chunks_fragment(
+    doc_id: VideoId,
+    chunks: &[ImageChunk],
+    images: &VideoImages,
+    removed: &HashSet<&u32>,
+    metadata: &VideoMetadata,
+) -> Markup {
+    let duplicate_of = &images.duplicate_of;
+    let text = images.text.as_ref();
+    // Audio-only media has no images, so the transcript is shown as a single column.
+    let layout = if metadata.audio_only {
+        "flex flex-col gap-y-2 mt-8 font-serif text-xl leading-relaxed"
+    } else {
+        "grid lg:grid-cols-[auto_auto] grid-cols-1 gap-x-4 gap-y-2 mt-8 font-serif text-xl leading-relaxed"
+    };
+
+    html! {
+        div class=(layout) {
+            @for (i, chunk) in chunks.iter().enumerate() {
+                // Label the start of each speaker's turn.
+                @let turn = chunk
+                    .speaker
+                    .filter(|&s| i == 0 || chunks[i - 1].speaker != Some(s));
+                div ."max-w-[65ch]" {
+                    @if let Some(speaker) = turn {
+                        p .font-sans.text-base.font-bold.mt-4 { (metadata.speaker_name(speaker)) }
+                    }
+                    (chunk.text)
+                }
+                @if !metadata.audio_only {
+                    div .flex.flex-col.gap-2.max-w-lg {
+                        @for idx in chunk.start_image_idx..=chunk.end_image_idx {
+                            @let removed = removed.contains(&(idx as u32));
+                            @let original = duplicate_of.get(&(idx as u32));
+                            @let image_text = text.and_then(|t| t.get(&(idx as u32)));
+                            button
+                                type="button"
+                                title=[original.map(|o| format!("Repeat of image {o}"))]
+                                x-cloak[removed]
+                                x-show=[removed.then_some("show_removed")]
+                                "@click"={"large_image = " (idx)}
+                            {
+                                img .object-cover.aspect-video.border .border-red-500[removed]
+                                    width="512"
+                                    src=(format_args!("/api/videos/{doc_id}/image/{idx}"))
+                                    alt=(image_text.cloned().unwrap_or_else(|| format!("Image {idx}")))
+                                    loading="lazy";
+                            }
+                        }
+                }
+                }
+
+            }
+
+        }
+    }
+}
+
+/// The transcript and images of each chapter. This is rendered again when the similarity
+/// threshold changes, since that changes which images are removed.
+fn sections_fragment(doc_id: VideoId, video: &Video) -> Markup {
+    let aligned = align(video);
+    let images = video.images.clone().unwrap_or_default();
+    let removed = images.removed.iter().collect::<HashSet<_>>();
+    let metadata = video.metadata.clone().unwrap_or_default();
+    let chapters = metadata.chapters.as_deref().unwrap_or_default();
+    let chapter_summaries = video
+        .summary_sections
+        .as_ref()
+        .map(|s| s.chapters.as_slice())
+        .unwrap_or_default();
+    let sections = chapter_sections(chapters, chapter_summaries, aligned);
+
+    html! {
+        div #sections .contents {
+            @for (i, section) in sections.iter().enumerate() {
+                section .flex.flex-col.items-center {
+                    @if let Some(chapter) = section.chapter {
+                        h2 id={"chapter-" (i)} .text-2xl.mt-12.scroll-mt-32.w-full."max-w-[90ch]" {
+                            (chapter.title)
+                        }
+                    }
+
+                    @if let Some(summary) = section.summary {
+                        details .mt-2.w-full."max-w-[90ch]" {
+                            summary .cursor-pointer { "Chapter Summary" }
+                            p.whitespace-pre-wrap.font-serif.text-xl.leading-relaxed {
+                                (summary.summary)
+                            }
+                        }
+                    }
+
+                    (aligned_chunks_fragment(doc_id, &section.chunks, &images, &removed, &metadata))
+                }
+            }
+        }
+    }
+}
+
 async fn docs_page(
     State(state): State<ServerState>,
     auth: WebAuthed,
     Path(doc_id): Path<crate::models::video::VideoId>,
 ) -> Result<impl IntoResponse, HtmlError> {
     let video = crate::models::video::queries::get(&state.db, &auth, &doc_id).await?;
-    let aligned = align(&video);
-    let images = video.images.unwrap_or_default();
-    let removed = images.removed.iter().collect::<HashSet<_>>();
+    let images = video.images.clone().unwrap_or_default();
+    let threshold = images.similarity_threshold.unwrap_or(state.ssim_threshold);
 
     let next_read = !video.read;
+    let failure = video
+        .metadata
+        .as_ref()
+        .and_then(|m| m.failure.as_ref())
+        .filter(|_| video.processing_state == VideoProcessingState::Failed);
+
+    let metadata = video.metadata.clone().unwrap_or_default();
+    let chapters = metadata.chapters.as_deref().unwrap_or_default();
+    let speakers = video
+        .transcript
+        .as_ref()
+        .map(|t| t.speakers.as_slice())
+        .unwrap_or_default();
 
     let body = html! {
         div .relative.w-full.overflow-y-auto
-            x-data=(format_args!(r##"{{ large_image: null, max_index:{max_index}, show_removed: false }}"##, max_index=images.max_index)) {
+            x-data=(page_data(&images)) {
 
             nav .sticky.top-0.w-full.bg-neutral.text-neutral-content.p-4 {
                 header .flex.gap-4.w-full
@@ -141,13 +349,64 @@ async fn docs_page(
                     }
                 }
 
-                label .flex.items-center.gap-2 {
-                    input .checkbox type="checkbox" x-model="show_removed";
-                    "Show removed images"
+                @if !metadata.audio_only {
+                    div .flex.flex-wrap.items-center.gap-x-8.gap-y-2 {
+                        label .flex.items-center.gap-2 {
+                            input .checkbox type="checkbox" x-model="show_removed";
+                            "Show removed images"
+                        }
+
+                        @if !images.similarity.is_empty() {
+                            form .flex.items-center.gap-2
+                                hx-post={"/docs/" (doc_id) "/_action/similarity_threshold"}
+                                hx-trigger="change"
+                                hx-target="#sections"
+                                hx-swap="outerHTML"
+                            {
+                                label .flex.items-center.gap-2 {
+                                    "Image density"
+                                    input .range.range-sm.w-48
+                                        type="range"
+                                        name="threshold"
+                                        min="0.5"
+                                        max="1"
+                                        step="0.01"
+                                        value=(threshold);
+                                }
+                            }
+                        }
+                }
                 }
             }
 
             main flex.flex-col.items-center.p-4 {
+                @if let Some(failure) = failure {
+                    section #failure .alert.alert-error.flex.justify-between."max-w-[90ch]" {
+                        (crate::pages::stage_failure_fragment(failure))
+                        button .btn.btn-outline
+                            type="button"
+                            hx-post={"/docs/" (doc_id) "/_action/rerun/" (failure.stage)}
+                            hx-target="#failure"
+                            hx-swap="outerHTML"
+                        {
+                            "Retry " (failure.stage)
+                        }
+                    }
+                }
+
+                @for failure in &metadata.optional_failures {
+                    section .alert.alert-warning.flex.justify-between."max-w-[90ch]" {
+                        (crate::pages::stage_failure_fragment(failure))
+                        button .btn.btn-outline
+                            type="button"
+                            hx-post={"/docs/" (doc_id) "/_action/rerun/" (failure.stage)}
+                            hx-target="closest section"
+                            hx-swap="outerHTML"
+                        {
+                            "Retry " (failure.stage)
+                        }
+                    }
+                }
 
                 @if let Some(summary) = &video.summary {
                     section {
@@ -158,30 +417,47 @@ async fn docs_page(
                     }
                 }
 
-                div class="grid lg:grid-cols-[auto_auto] grid-cols-1 gap-x-4 gap-y-2 mt-8 font-serif text-xl leading-relaxed" {
-                    @for chunk in aligned {
-                        div ."max-w-[65ch]" { (chunk.text) }
-                        div .flex.flex-col.gap-2.max-w-lg {
-                            @for idx in chunk.start_image_idx..=chunk.end_image_idx {
-                                @let removed = removed.contains(&(idx as u32));
-                                button
-                                    type="button"
-                                    x-cloak[removed] x-show=[removed.then_some("show_removed")]
-                                    "@click"={"large_image = " (idx)}
-                                {
-                                    img .object-cover.aspect-video.border .border-red-500[removed]
-                                        width="512"
-                                        src=(format_args!("/api/videos/{doc_id}/image/{idx}"))
-                                        alt={ "Image " (idx)}
-                                        loading="lazy";
+                @if speakers.len() > 1 {
+                    details .mt-8.w-full."max-w-[90ch]" {
+                        summary .cursor-pointer.text-2xl { "Speakers" }
+                        form .flex.flex-col.gap-2.mt-2
+                            hx-post={"/docs/" (doc_id) "/_action/speaker_names"}
+                        {
+                            @for speaker in speakers {
+                                label .flex.items-center.gap-4 {
+                                    span .w-24 { "Speaker " (speaker) }
+                                    input .input.input-bordered.input-sm
+                                        type="text"
+                                        name={"speaker-" (speaker)}
+                                        placeholder={"Speaker " (speaker)}
+                                        value=[metadata.speaker_names.get(speaker)];
                                 }
                             }
+                            div {
+                                button .btn.btn-sm.btn-outline type="submit" { "Save" }
+                            }
                         }
-
                     }
+                }
 
+                @if !chapters.is_empty() {
+                    nav #toc .mt-8.w-full."max-w-[90ch]" {
+                        p.text-2xl { "Chapters" }
+                        ol .list-decimal.list-inside {
+                            @for (i, chapter) in chapters.iter().enumerate() {
+                                li {
+                                    a .link href={"#chapter-" (i)} { (chapter.title) }
+                                    " "
+                                    span .text-sm.opacity-70 {
+                                        (VideoDuration(Some(chapter.start_time as i32)))
+                                    }
+                                }
+                            }
+                        }
+                    }
                 }
 
+                (sections_fragment(doc_id, &video))
             }
 
             template x-if="large_image" {
@@ -217,4 +493,19 @@ pub fn create_routes() -> axum::Router<ServerState> {
             routing::post(mark_read_action)
                 .route_layer(has_any_permission(vec!["Video:write", "org_admin"])),
         )
+        .route(
+            "/docs/:doc_id/_action/speaker_names",
+            routing::post(speaker_names_action)
+                .route_layer(has_any_permission(vec!["Video:owner", "org_admin"])),
+        )
+        .route(
+            "/docs/:doc_id/_action/similarity_threshold",
+            routing::post(similarity_threshold_action)
+                .route_layer(has_any_permission(vec!["Video:owner", "org_admin"])),
+        )
+        .route(
+            "/docs/:doc_id/_action/rerun/:stage",
+            routing::post(rerun_stage_action)
+                .route_layer(has_any_permission(vec!["Video:owner", "org_admin"])),
+        )
 }
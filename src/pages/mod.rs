@@ -3,12 +3,12 @@ use std::fmt::Write;
 use axum::response::Redirect;
 #[allow(unused_imports)]
 use axum::{
-    extract::{Path, State},
+    extract::{DefaultBodyLimit, Path, State},
     http::StatusCode,
     response::IntoResponse,
     routing,
 };
-use axum_extra::extract::{Form, Query};
+use axum_extra::extract::{Form, Multipart, Query};
 use axum_htmx::HxTrigger;
 use error_stack::Report;
 use filigree::html::Svg;
@@ -17,7 +17,13 @@ use schemars::JsonSchema;
 
 use crate::{
     auth::{has_any_permission, Authed},
-    models::video::{self, VideoId, VideoListResult, VideoProcessingState},
+    models::{
+        collection::CollectionId,
+        subscription::{Subscription, SubscriptionFilters, SubscriptionId},
+        video::{
+            self, DownloadOptions, StageFailure, VideoId, VideoListResult, VideoProcessingState,
+        },
+    },
     pages::{auth::WebAuthed, error::HtmlError},
     server::ServerState,
     Error,
@@ -41,6 +47,12 @@ pub use not_found::*;
 #[derive(serde::Deserialize, serde::Serialize, Debug, JsonSchema)]
 pub struct AddVideoActionPayload {
     pub url: String,
+    /// "audio" for only the audio, or the largest video height to download. Empty uses the
+    /// server's default.
+    #[serde(default)]
+    pub quality: String,
+    #[serde(default)]
+    pub allow_duplicate: bool,
 }
 
 async fn add_video_action(
@@ -48,12 +60,57 @@ async fn add_video_action(
     auth: Authed,
     form: Form<AddVideoActionPayload>,
 ) -> Result<impl IntoResponse, Error> {
-    let id = crate::models::video::create_via_url(&state, &auth, &form.url).await?;
+    let download = match form.quality.trim() {
+        "" => DownloadOptions::default(),
+        "audio" => DownloadOptions {
+            audio_only: Some(true),
+            ..Default::default()
+        },
+        height => DownloadOptions {
+            max_height: Some(height.parse().map_err(|_| {
+                Error::InvalidSettings("The video quality is not valid".to_string())
+            })?),
+            audio_only: Some(false),
+            ..Default::default()
+        },
+    };
+
+    let added = crate::models::video::create_via_url(
+        &state,
+        &auth,
+        &form.url,
+        download,
+        form.allow_duplicate,
+    )
+    .await?;
+    let row = new_video_row(&state, &auth, added.id).await?;
+
+    if !added.existing {
+        return Ok(row);
+    }
+
+    // The video was already added, so move its row to the top of the list instead of adding
+    // another one.
+    Ok(html! {
+        li id={"row-" (added.id)} hx-swap-oob="delete" {}
+        (row)
+    })
+}
+
+async fn upload_video_action(
+    State(state): State<ServerState>,
+    auth: Authed,
+    multipart: Multipart,
+) -> Result<impl IntoResponse, Error> {
+    let id = crate::models::video::create_via_upload(&state, &auth, multipart).await?;
+    new_video_row(&state, &auth, id).await
+}
 
+async fn new_video_row(state: &ServerState, auth: &Authed, id: VideoId) -> Result<Markup, Error> {
     // Hack until filigree supports better model fetching and conversion between types
     let mut videos = crate::models::video::queries::list(
         &state.db,
-        &auth,
+        auth,
         &video::queries::ListQueryFilters {
             id: vec![id],
             ..Default::default()
@@ -66,6 +123,149 @@ async fn add_video_action(
     Ok(body)
 }
 
+#[derive(serde::Deserialize, serde::Serialize, Debug, JsonSchema)]
+pub struct AddCollectionActionPayload {
+    pub url: String,
+    pub name: Option<String>,
+}
+
+async fn add_collection_action(
+    State(state): State<ServerState>,
+    auth: Authed,
+    form: Form<AddCollectionActionPayload>,
+) -> Result<impl IntoResponse, Error> {
+    crate::models::collection::create_via_url(&state, &auth, &form.url, form.name.as_deref())
+        .await?;
+
+    // The videos are added in the background, so reload to show the new collection.
+    Ok([("HX-Refresh", "true")])
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, JsonSchema)]
+pub struct AddSubscriptionActionPayload {
+    pub url: String,
+    #[serde(default)]
+    pub name: String,
+    /// The minimum duration in minutes
+    #[serde(default)]
+    pub min_minutes: String,
+    /// The maximum duration in minutes
+    #[serde(default)]
+    pub max_minutes: String,
+    /// Comma-separated keywords
+    #[serde(default)]
+    pub include: String,
+    /// Comma-separated keywords
+    #[serde(default)]
+    pub exclude: String,
+}
+
+async fn add_subscription_action(
+    State(state): State<ServerState>,
+    auth: Authed,
+    form: Form<AddSubscriptionActionPayload>,
+) -> Result<impl IntoResponse, Error> {
+    let minutes = |value: &str| {
+        let value = value.trim();
+        if value.is_empty() {
+            return Ok(None);
+        }
+
+        value
+            .parse::<u32>()
+            .ok()
+            .and_then(|m| m.checked_mul(60))
+            .map(Some)
+            .ok_or_else(|| {
+                Error::InvalidSettings("Durations must be a whole number of minutes".to_string())
+            })
+    };
+    let keywords = |value: &str| {
+        value
+            .split(',')
+            .map(|k| k.trim().to_string())
+            .filter(|k| !k.is_empty())
+            .collect()
+    };
+
+    let filters = SubscriptionFilters {
+        min_duration: minutes(&form.min_minutes)?,
+        max_duration: minutes(&form.max_minutes)?,
+        include: keywords(&form.include),
+        exclude: keywords(&form.exclude),
+    };
+    crate::models::subscription::create(&state, &auth, &form.url, Some(&form.name), filters)
+        .await?;
+
+    Ok([("HX-Refresh", "true")])
+}
+
+async fn delete_subscription_action(
+    State(state): State<ServerState>,
+    auth: Authed,
+    Path(id): Path<SubscriptionId>,
+) -> Result<impl IntoResponse, Error> {
+    crate::models::subscription::delete(&state, &auth, id).await?;
+    Ok("")
+}
+
+fn subscriptions_fragment(subscriptions: &[Subscription]) -> Markup {
+    html! {
+        details .rounded-lg.border.border-neutral.p-4 {
+            summary .cursor-pointer {
+                "Subscriptions"
+                @if !subscriptions.is_empty() {
+                    " (" (subscriptions.len()) ")"
+                }
+            }
+
+            ul .flex.flex-col.gap-2.mt-2 {
+                @for subscription in subscriptions {
+                    li #{"subscription-" (subscription.id)} .flex.items-center.justify-between.gap-4 {
+                        div .flex.flex-col {
+                            a .link href=(subscription.url) target="_blank" { (subscription.display_name()) }
+                            span .text-sm.opacity-70 {
+                                @if let Some(checked) = subscription.last_checked_at {
+                                    "Checked " (checked.format("%Y-%m-%d %H:%M"))
+                                } @else {
+                                    "Not checked yet"
+                                }
+                                @if let Some(error) = &subscription.last_error {
+                                    span .text-error title=(error) { ", the last check failed" }
+                                }
+                            }
+                        }
+                        button .btn.btn-sm.btn-outline
+                            hx-delete={"/_action/subscriptions/" (subscription.id)}
+                            hx-confirm={"Unsubscribe from '" (subscription.display_name()) "'?"}
+                            hx-target={"#subscription-" (subscription.id)}
+                            hx-swap="delete"
+                        {
+                            "Unsubscribe"
+                        }
+                    }
+                }
+            }
+
+            form .flex.flex-col.gap-2.mt-4 hx-post="/_action/subscriptions" {
+                div .flex.flex-wrap.gap-4 {
+                    input .flex-1.input.input-bordered type="text" name="url" placeholder="Channel, playlist, or feed URL" autocomplete="off" required;
+                    input .input.input-bordered type="text" name="name" placeholder="Name (optional)" autocomplete="off";
+                }
+                div .flex.flex-wrap.gap-4 {
+                    input .input.input-bordered.w-36 type="number" min="0" name="min_minutes" placeholder="Min minutes";
+                    input .input.input-bordered.w-36 type="number" min="0" name="max_minutes" placeholder="Max minutes";
+                    input .flex-1.input.input-bordered type="text" name="include" placeholder="Only titles with (comma-separated)" autocomplete="off";
+                    input .flex-1.input.input-bordered type="text" name="exclude" placeholder="Skip titles with (comma-separated)" autocomplete="off";
+                }
+                div {
+                    button .btn.btn-outline type="submit" { "Subscribe" }
+                }
+            }
+        }
+    }
+}
+
 async fn video_status_action(
     State(state): State<ServerState>,
     auth: Authed,
@@ -80,7 +280,12 @@ async fn video_status_action(
         },
     )
     .await?;
-    let video = videos.pop().ok_or(crate::Error::NotFound("new video"))?;
+
+    // The video may have been deleted since the row was shown, such as when its download found
+    // that it was a duplicate, so remove the row.
+    let Some(video) = videos.pop() else {
+        return Ok(html! {});
+    };
     let body = video_row_fragment(&video, false);
 
     Ok(body)
@@ -91,7 +296,7 @@ async fn delete_video_action(
     auth: Authed,
     Path(id): Path<VideoId>,
 ) -> Result<impl IntoResponse, Error> {
-    video::queries::delete(&state.db, &auth, &id).await?;
+    video::delete(&state, &auth, id).await?;
     Ok("")
 }
 
@@ -99,8 +304,27 @@ async fn rerun_stage_action(
     State(state): State<ServerState>,
     auth: Authed,
     Path((id, stage)): Path<(crate::models::video::VideoId, String)>,
+    Query(qs): Query<crate::models::video::endpoints::RerunStageQuery>,
+) -> Result<impl IntoResponse, Error> {
+    crate::models::video::rerun_stage(&state, &auth, id, &stage, qs.cascade).await?;
+    Ok(Redirect::to(&format!("/_action/videos/{id}")))
+}
+
+async fn cancel_video_action(
+    State(state): State<ServerState>,
+    auth: Authed,
+    Path(id): Path<VideoId>,
 ) -> Result<impl IntoResponse, Error> {
-    crate::models::video::rerun_stage(&state, &auth, id, &stage).await?;
+    crate::models::video::cancel(&state, &auth, id).await?;
+    Ok(Redirect::to(&format!("/_action/videos/{id}")))
+}
+
+async fn resume_video_action(
+    State(state): State<ServerState>,
+    auth: Authed,
+    Path(id): Path<VideoId>,
+) -> Result<impl IntoResponse, Error> {
+    crate::models::video::resume(&state, &auth, id).await?;
     Ok(Redirect::to(&format!("/_action/videos/{id}")))
 }
 
@@ -157,7 +381,7 @@ async fn mark_read_action(
     Ok(body)
 }
 
-struct VideoDuration(Option<i32>);
+pub(crate) struct VideoDuration(pub Option<i32>);
 
 impl Render for VideoDuration {
     fn render_to(&self, buffer: &mut String) {
@@ -178,7 +402,7 @@ fn reprocess_button(id: VideoId, label: &str, stage: &str) -> Markup {
         li {
             button flex.gap-2.justify-start
                 type="button"
-                hx-post={"/_action/videos/" (id) "/rerun/" (stage)}
+                hx-post={"/_action/videos/" (id) "/rerun/" (stage) "?cascade=true"}
                 hx-target={"#row-" (id)}
                 hx-swap="outerHTML"
                 "@click"="open = false"
@@ -190,11 +414,89 @@ fn reprocess_button(id: VideoId, label: &str, stage: &str) -> Markup {
     }
 }
 
+/// Describe a failed processing stage, with the full error report available on demand.
+pub(crate) fn stage_failure_fragment(failure: &StageFailure) -> Markup {
+    html! {
+        div .flex.flex-col.gap-1 {
+            p .text-error {
+                "Failed during " (failure.stage) " after " (failure.attempts)
+                @if failure.attempts == 1 { " attempt" } @else { " attempts" }
+            }
+            details {
+                summary .cursor-pointer { "Error details" }
+                pre .whitespace-pre-wrap.text-xs.max-h-96.overflow-y-auto { (failure.error) }
+            }
+        }
+    }
+}
+
+fn video_menu_fragment(video: &VideoListResult) -> Markup {
+    html! {
+        div .relative x-data="{ open: false }" {
+            button .btn.btn-circle.btn-outline
+                aria-label="Menu"
+                "@click"="open = !open"
+            {
+                (Svg::new(md_icons::outlined::ICON_SETTINGS))
+            }
+
+            ul .menu.absolute.right-0.mt-1.bg-base-200.text-base-content.z-50.rounded-lg
+                x-show="open"
+                x-cloak
+                x-transition
+                "@click.outside"="open = false"
+            {
+                p.menu-title { "Reprocess" }
+                (reprocess_button(video.id, "Download", "download"))
+                (reprocess_button(video.id, "Extract", "extract"))
+                (reprocess_button(video.id, "Analyze", "analyze"))
+                (reprocess_button(video.id, "Transcribe", "transcribe"))
+                (reprocess_button(video.id, "Summarize", "summarize"))
+
+                li {
+                    button flex.gap-2.justify-start
+                        type="button"
+                        hx-delete={"/_action/videos/" (video.id)}
+                        hx-confirm={"Are you sure you want to delete '" (video.title.as_deref().unwrap_or_default()) "'?"}
+                        hx-target={"#row-" (video.id)}
+                        hx-swap="delete"
+                        "@click"="open = false"
+                    {
+                        (Svg::new(md_icons::outlined::ICON_DELETE))
+                        "Delete"
+                    }
+                }
+            }
+        }
+    }
+}
+
 fn video_row_fragment(video: &VideoListResult, unread_only: bool) -> Markup {
     let ready = video.processing_state == VideoProcessingState::Ready;
+    let failure = video
+        .metadata
+        .as_ref()
+        .and_then(|m| m.failure.as_ref())
+        .filter(|_| video.processing_state == VideoProcessingState::Failed);
     let read = video.read;
-
-    let trigger = if ready { "none" } else { "load delay:5s" };
+    let progress = video
+        .metadata
+        .as_ref()
+        .and_then(|m| m.stage_progress.as_ref())
+        .filter(|_| {
+            matches!(
+                video.processing_state,
+                VideoProcessingState::Downloading | VideoProcessingState::Processing
+            )
+        });
+
+    let cancelled = video.processing_state == VideoProcessingState::Cancelled;
+
+    let trigger = if ready || failure.is_some() || cancelled {
+        "none"
+    } else {
+        "load delay:5s"
+    };
 
     html! {
         li id={"row-" (video.id)}
@@ -209,49 +511,60 @@ fn video_row_fragment(video: &VideoListResult, unread_only: bool) -> Markup {
                     span { (VideoDuration(video.duration)) }
                 } @else {
                     p { (video.title.as_deref().or(video.url.as_deref()).unwrap_or_default()) }
-                    p { (video.processing_state) }
+                    @if let Some(failure) = failure {
+                        (stage_failure_fragment(failure))
+                    } @else {
+                        p { (video.processing_state) }
+                        @if let Some(progress) = progress {
+                            .flex.items-center.gap-2 {
+                                progress.progress.w-56 value=(progress.percent) max="100" {}
+                                span.text-sm {
+                                    (progress.percent.round()) "%"
+                                    @if let Some(eta) = progress.eta {
+                                        ", " (VideoDuration(Some(eta as i32))) " left"
+                                    }
+                                }
+                            }
+                        }
+                    }
                 }
             }
 
             .flex.gap-2.items-center {
                 @if ready {
                     (mark_read_action_fragment(video.id, read, unread_only))
-
-                    div .relative x-data="{ open: false }" {
-                        button .btn.btn-circle.btn-outline
-                            aria-label="Menu"
-                            "@click"="open = !open"
-                        {
-                            (Svg::new(md_icons::outlined::ICON_SETTINGS))
-                        }
-
-                        ul .menu.absolute.right-0.mt-1.bg-base-200.text-base-content.z-50.rounded-lg
-                            x-show="open"
-                            x-cloak
-                            x-transition
-                            "@click.outside"="open = false"
-                        {
-                            p.menu-title { "Reprocess" }
-                            (reprocess_button(video.id, "Download", "download"))
-                            (reprocess_button(video.id, "Extract", "extract"))
-                            (reprocess_button(video.id, "Analyze", "analyze"))
-                            (reprocess_button(video.id, "Transcribe", "transcribe"))
-                            (reprocess_button(video.id, "Summarize", "summarize"))
-
-                            li {
-                                button flex.gap-2.justify-start
-                                    type="button"
-                                    hx-delete={"/_action/videos/" (video.id)}
-                                    hx-confirm={"Are you sure you want to delete '" (video.title.as_deref().unwrap_or_default()) "'?"}
-                                    hx-target={"#row-" (video.id)}
-                                    hx-swap="delete"
-                                    "@click"="open = false"
-                                {
-                                    (Svg::new(md_icons::outlined::ICON_DELETE))
-                                    "Delete"
-                                }
-                            }
-                        }
+                    (video_menu_fragment(video))
+                } @else if let Some(failure) = failure {
+                    button .btn.btn-outline
+                        type="button"
+                        hx-post={"/_action/videos/" (video.id) "/rerun/" (failure.stage)}
+                        hx-target={"#row-" (video.id)}
+                        hx-swap="outerHTML"
+                    {
+                        (Svg::new(md_icons::outlined::ICON_REFRESH))
+                        "Retry " (failure.stage)
+                    }
+                    (video_menu_fragment(video))
+                } @else if cancelled {
+                    button .btn.btn-outline
+                        type="button"
+                        hx-post={"/_action/videos/" (video.id) "/resume"}
+                        hx-target={"#row-" (video.id)}
+                        hx-swap="outerHTML"
+                    {
+                        (Svg::new(md_icons::outlined::ICON_PLAY_ARROW))
+                        "Resume"
+                    }
+                    (video_menu_fragment(video))
+                } @else {
+                    button .btn.btn-outline
+                        type="button"
+                        hx-post={"/_action/videos/" (video.id) "/cancel"}
+                        hx-target={"#row-" (video.id)}
+                        hx-swap="outerHTML"
+                    {
+                        (Svg::new(md_icons::outlined::ICON_STOP))
+                        "Cancel"
                     }
                 }
             }
@@ -259,10 +572,20 @@ fn video_row_fragment(video: &VideoListResult, unread_only: bool) -> Markup {
     }
 }
 
+/// The query string for the home page
+fn home_query(unread_only: bool, collection: Option<CollectionId>) -> String {
+    let mut query = format!("?unread_only={unread_only}");
+    if let Some(collection) = collection {
+        write!(query, "&collection={collection}").ok();
+    }
+    query
+}
+
 async fn video_list(
     state: &ServerState,
     auth: &WebAuthed,
     unread_only: bool,
+    collection: Option<CollectionId>,
 ) -> Result<Markup, Report<Error>> {
     let videos = crate::models::video::queries::list(
         &state.db,
@@ -271,6 +594,7 @@ async fn video_list(
             per_page: Some(50),
             order_by: Some("-created_at".to_string()),
             read: unread_only.then_some(false),
+            collection_id: collection,
             ..Default::default()
         },
     )
@@ -279,10 +603,10 @@ async fn video_list(
     Ok(html! {
         div .flex.justify-end.gap-2 {
             a #unread-only .flex.items-center.gap-2
-                href={"?unread_only=" (!unread_only)}
+                href=(home_query(!unread_only, collection))
                 hx-target="#video-list"
                 hx-push-url="true"
-                hx-get={"/?unread_only=" (!unread_only)}
+                hx-get={"/" (home_query(!unread_only, collection))}
             {
                 label.label.gap-2 {
                     input
@@ -307,6 +631,8 @@ async fn video_list(
 #[derive(serde::Deserialize, serde::Serialize, Debug, JsonSchema)]
 pub struct HomeQuery {
     pub unread_only: Option<bool>,
+    /// Only show the videos in this collection
+    pub collection: Option<CollectionId>,
 }
 
 async fn home_page(
@@ -319,13 +645,16 @@ async fn home_page(
 
     match trigger.as_deref() {
         Some("unread-only") => {
-            return video_list(&state, &auth, unread_only)
+            return video_list(&state, &auth, unread_only, qs.collection)
                 .await
                 .map_err(HtmlError::from)
         }
         _ => {}
     }
 
+    let collections = crate::models::collection::list(&state, &auth.0).await?;
+    let subscriptions = crate::models::subscription::list(&state, &auth.0).await?;
+
     let body = html! {
     main .relative.p-4.flex.flex-col.gap-4 {
         form .flex.flex-col.gap-2.rounded-lg.border.border-neutral.p-4
@@ -339,12 +668,83 @@ async fn home_page(
             label .label-text.flex.gap-2.flex-1.text-base for="path" { "Add a new video" }
             div .flex.gap-4 {
                 input #path .flex-1.input.input-bordered type="text" name="url" autocomplete="off";
+                select .select.select-bordered name="quality" aria-label="Download quality" {
+                    option value="" selected { "Default quality" }
+                    option value="audio" { "Audio only" }
+                    @for height in [360, 480, 720, 1080] {
+                        option value=(height) { "Up to " (height) "p" }
+                    }
+                }
                 button .btn.btn-outline type="submit" { "Add" }
             }
+            label .label.cursor-pointer.justify-start.gap-2 {
+                input .checkbox.checkbox-sm type="checkbox" name="allow_duplicate" value="true";
+                span .label-text { "Add again if the video was already added" }
+            }
+        }
+
+        details .rounded-lg.border.border-neutral.p-4 {
+            summary .cursor-pointer { "Upload a file" }
+            form .flex.flex-col.gap-2.mt-2
+                hx-post="/_action/upload_video"
+                hx-encoding="multipart/form-data"
+                hx-target="#videos"
+                hx-swap="afterbegin"
+                "hx-on:htmx:after-on-load"="this.reset()"
+            {
+                input .file-input.file-input-bordered type="file" name="file" accept="video/*,audio/*" required;
+                div .flex.flex-wrap.gap-4 {
+                    input .flex-1.input.input-bordered type="text" name="title" placeholder="Title" autocomplete="off";
+                    input .flex-1.input.input-bordered type="text" name="author" placeholder="Author" autocomplete="off";
+                    input .input.input-bordered type="date" name="date";
+                }
+                div .flex.items-center.gap-4 {
+                    button .btn.btn-outline type="submit" { "Upload" }
+                    progress .progress.w-56.htmx-indicator value="0" max="100"
+                        "hx-on:htmx:xhr:progress"="this.value = event.detail.loaded / event.detail.total * 100" {}
+                }
+            }
+        }
+
+        details .rounded-lg.border.border-neutral.p-4 {
+            summary .cursor-pointer { "Add a playlist or channel" }
+            form .flex.flex-wrap.gap-4.mt-2 hx-post="/_action/add_collection" {
+                input .flex-1.input.input-bordered type="text" name="url" placeholder="Playlist or channel URL" autocomplete="off" required;
+                input .input.input-bordered type="text" name="name" placeholder="Name (optional)" autocomplete="off";
+                button .btn.btn-outline type="submit" { "Add All" }
+            }
+        }
+
+        (subscriptions_fragment(&subscriptions))
+
+        @if !collections.is_empty() {
+            nav .flex.flex-wrap.items-center.gap-2 {
+                span .font-bold { "Collections" }
+                a .badge.badge-lg.badge-outline.badge-primary[qs.collection.is_none()]
+                    href=(home_query(unread_only, None)) { "All videos" }
+                @for collection in &collections {
+                    @let active = qs.collection == Some(collection.id);
+                    a .badge.badge-lg.badge-outline.gap-2.badge-primary[active]
+                        href=(home_query(unread_only, Some(collection.id)))
+                        title=[collection.url.as_deref()]
+                    {
+                        (collection.display_name())
+                        span .opacity-70 {
+                            (collection.ready) "/" (collection.videos) " ready"
+                            @if collection.failed > 0 {
+                                ", " (collection.failed) " failed"
+                            }
+                            @if let Some(error) = &collection.last_error {
+                                span .text-error title=(error) { ", reading the playlist failed" }
+                            }
+                        }
+                    }
+                }
+            }
         }
 
         section #video-list .flex.flex-col.gap-4 {
-            (video_list(&state, &auth, unread_only).await?)
+            (video_list(&state, &auth, unread_only, qs.collection).await?)
         }
     }
     };
@@ -360,6 +760,27 @@ pub fn create_routes() -> axum::Router<ServerState> {
             routing::post(add_video_action)
                 .route_layer(has_any_permission(vec!["Video:write", "org_admin"])),
         )
+        .route(
+            "/_action/add_collection",
+            routing::post(add_collection_action)
+                .route_layer(has_any_permission(vec!["Video:write", "org_admin"])),
+        )
+        .route(
+            "/_action/subscriptions",
+            routing::post(add_subscription_action)
+                .route_layer(has_any_permission(vec!["Video:write", "org_admin"])),
+        )
+        .route(
+            "/_action/subscriptions/:id",
+            routing::delete(delete_subscription_action)
+                .route_layer(has_any_permission(vec!["Video:write", "org_admin"])),
+        )
+        .route(
+            "/_action/upload_video",
+            routing::post(upload_video_action)
+                .layer(DefaultBodyLimit::disable())
+                .route_layer(has_any_permission(vec!["Video:write", "org_admin"])),
+        )
         .route(
             "/_action/videos/:id",
             routing::get(video_status_action)
@@ -375,6 +796,16 @@ pub fn create_routes() -> axum::Router<ServerState> {
             routing::post(rerun_stage_action)
                 .route_layer(has_any_permission(vec!["Video:owner", "org_admin"])),
         )
+        .route(
+            "/_action/videos/:id/cancel",
+            routing::post(cancel_video_action)
+                .route_layer(has_any_permission(vec!["Video:owner", "org_admin"])),
+        )
+        .route(
+            "/_action/videos/:id/resume",
+            routing::post(resume_video_action)
+                .route_layer(has_any_permission(vec!["Video:owner", "org_admin"])),
+        )
         .route(
             "/_action/mark_read/:id",
             routing::post(mark_read_action)
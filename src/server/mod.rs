@@ -22,13 +22,25 @@ use tower::ServiceBuilder;
 use tower_http::{
     compression::CompressionLayer,
     cors::CorsLayer,
-    timeout::TimeoutLayer,
     trace::{DefaultOnFailure, DefaultOnRequest, TraceLayer},
     ServiceBuilderExt,
 };
 use tracing::{event, Level, Span};
 
-use crate::{error::Error, storage};
+use crate::{
+    error::Error,
+    jobs::{
+        analyze::AnalyzeConfig,
+        cancel::RunningJobs,
+        download::{DownloadConfig, SubtitlesConfig},
+        extract::ImageExtractionConfig,
+        ocr::OcrConfig,
+    },
+    llm::{Llm, LlmConfig},
+    models::usage::PricingConfig,
+    storage,
+    transcription::{TranscriptionConfig, TranscriptionProvider},
+};
 
 mod health;
 mod meta;
@@ -51,8 +63,26 @@ pub struct ServerStateInner {
     pub queue: effectum::Queue,
     /// Object storage providers
     pub storage: storage::AppStorage,
-    /// Threshold for similar image detection. Values about this threshold will be considered similar
-    /// Defaults to 0.9
+    /// The speech recognition service
+    pub transcription: Box<dyn TranscriptionProvider>,
+    /// The language models used for summarization
+    pub llm: Llm,
+    /// The default quality and format of downloaded videos
+    pub download: DownloadConfig,
+    /// The prices used to estimate what processing a video cost
+    pub pricing: PricingConfig,
+    /// Whether to use subtitles as the transcript
+    pub subtitles: SubtitlesConfig,
+    /// How to extract images from videos
+    pub image_extraction: ImageExtractionConfig,
+    /// How to analyze the extracted images
+    pub analyze: AnalyzeConfig,
+    /// Text recognition for the extracted images
+    pub ocr: OcrConfig,
+    /// The video processing jobs running in this process, so that they can be cancelled
+    pub running_jobs: RunningJobs,
+    /// Threshold for similar image detection. When a video is analyzed, an image whose SSIM score
+    /// against the last kept image is at least this much is removed. Defaults to 0.9
     pub ssim_threshold: f64,
 }
 
@@ -113,30 +143,34 @@ impl FromRef<ServerState> for SessionBackend {
 }
 
 pub struct Secrets {
-    pub anthropic: String,
-    pub deepgram: String,
+    pub anthropic: Option<String>,
+    pub deepgram: Option<String>,
+    /// The API key for an OpenAI-compatible language model server, if it needs one
+    pub llm_api_key: Option<String>,
+    /// The API key for an OpenAI-compatible transcription server, if it needs one
+    pub transcription_api_key: Option<String>,
 }
 
 impl Secrets {
     /// Load the secrets from the environment
     pub fn from_env() -> Result<Secrets, Report<Error>> {
         Ok(Self {
-            anthropic: std::env::var("ANTHROPIC_API_KEY")
-                .change_context(Error::Config)
-                .attach_printable("Missing environment variable ANTHROPIC_API_KEY")?,
-            deepgram: std::env::var("DEEPGRAM_API_KEY")
-                .change_context(Error::Config)
-                .attach_printable("Missing environment variable DEEPGRAM_API_KEY")?,
+            anthropic: std::env::var("ANTHROPIC_API_KEY").ok(),
+            deepgram: std::env::var("DEEPGRAM_API_KEY").ok(),
+            llm_api_key: std::env::var("LLM_API_KEY").ok(),
+            transcription_api_key: std::env::var("TRANSCRIPTION_API_KEY").ok(),
         })
     }
 
     #[cfg(test)]
-    /// Create a new Secrets struct with all the strings empty, for testing where we don't need any
+    /// Create a new Secrets struct with no secrets set, for testing where we don't need any
     /// secrets.
     pub fn empty() -> Secrets {
         Secrets {
-            anthropic: String::new(),
-            deepgram: String::new(),
+            anthropic: None,
+            deepgram: None,
+            llm_api_key: None,
+            transcription_api_key: None,
         }
     }
 }
@@ -238,6 +272,8 @@ pub struct Config {
     pub insecure: bool,
     /// How long to wait before timing out a request
     pub request_timeout: std::time::Duration,
+    /// How long to wait before timing out a media file upload
+    pub upload_timeout: std::time::Duration,
     pub pg_pool: PgPool,
 
     pub cookie_configuration: SessionCookieBuilder,
@@ -274,6 +310,45 @@ pub struct Config {
     pub init_recurring_jobs: bool,
 
     pub storage: storage::AppStorageConfig,
+    /// Which speech recognition service to use
+    pub transcription: TranscriptionConfig,
+    /// Which language model to use for summarization
+    pub llm: LlmConfig,
+    /// The default quality and format of downloaded videos
+    pub download: DownloadConfig,
+    /// The prices used to estimate what processing a video cost
+    pub pricing: PricingConfig,
+    /// Whether to use subtitles as the transcript
+    pub subtitles: SubtitlesConfig,
+    /// How to extract images from videos
+    pub image_extraction: ImageExtractionConfig,
+    /// How to analyze the extracted images
+    pub analyze: AnalyzeConfig,
+    /// Text recognition for the extracted images
+    pub ocr: OcrConfig,
+}
+
+/// Paths that receive media files, which can take much longer than other requests to send
+const UPLOAD_PATHS: &[&str] = &["/api/videos/upload", "/_action/upload_video"];
+
+/// Respond with a timeout error if the request takes longer than `request_timeout`, or
+/// `upload_timeout` for media uploads.
+async fn timeout_request(
+    request_timeout: Duration,
+    upload_timeout: Duration,
+    req: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let timeout = if UPLOAD_PATHS.contains(&req.uri().path()) {
+        upload_timeout
+    } else {
+        request_timeout
+    };
+
+    match tokio::time::timeout(timeout, next.run(req)).await {
+        Ok(response) => response,
+        Err(_) => axum::response::IntoResponse::into_response(http::StatusCode::REQUEST_TIMEOUT),
+    }
 }
 
 /// Create the server and return it, ready to run.
@@ -296,6 +371,13 @@ pub async fn create_server(config: Config) -> Result<Server, Report<Error>> {
         .build()
         .unwrap();
 
+    let transcription = crate::transcription::create_provider(
+        &config.transcription,
+        &config.secrets,
+        http_client.clone(),
+    )?;
+    let llm = Llm::new(config.llm, &config.secrets, http_client.clone())?;
+
     let queue = crate::jobs::create_queue(&config.queue_path)
         .await
         .change_context(Error::ServerStart)?;
@@ -327,6 +409,15 @@ pub async fn create_server(config: Config) -> Result<Server, Report<Error>> {
         secrets: config.secrets,
         queue,
         storage: storage::AppStorage::new(config.storage).change_context(Error::ServerStart)?,
+        transcription,
+        llm,
+        download: config.download,
+        pricing: config.pricing,
+        subtitles: config.subtitles,
+        image_extraction: config.image_extraction,
+        analyze: config.analyze,
+        ocr: config.ocr,
+        running_jobs: RunningJobs::default(),
         ssim_threshold: std::env::var("SSIM_THRESHOLD")
             .ok()
             .and_then(|v| v.parse::<f64>().ok())
@@ -358,6 +449,7 @@ pub async fn create_server(config: Config) -> Result<Server, Report<Error>> {
         .merge(filigree::auth::oauth::create_routes())
         .merge(crate::models::create_routes())
         .merge(crate::users::users::create_routes())
+        .merge(crate::llm::endpoints::create_routes())
         .merge(crate::auth::create_routes())
         // Return not found here so we don't run the other non-API fallbacks
         .fallback(|| async { Error::NotFound("Route") });
@@ -476,7 +568,9 @@ pub async fn create_server(config: Config) -> Result<Server, Report<Error>> {
                     .on_request(DefaultOnRequest::new().level(Level::INFO))
                     .on_failure(DefaultOnFailure::new().level(Level::ERROR)),
             )
-            .layer(TimeoutLayer::new(config.request_timeout))
+            .layer(axum::middleware::from_fn(move |req, next| {
+                timeout_request(config.request_timeout, config.upload_timeout, req, next)
+            }))
             .layer(api_cors_layer)
             .layer(tower_cookies::CookieManagerLayer::new())
             .propagate_x_request_id()
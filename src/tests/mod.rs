@@ -100,6 +100,7 @@ pub async fn start_app_with_options(
         },
         insecure: true,
         request_timeout: std::time::Duration::from_secs(30),
+        upload_timeout: std::time::Duration::from_secs(30),
         pg_pool: pg_pool.clone(),
         api_cors: filigree::auth::CorsSetting::default(),
         hosts: vec![],
@@ -108,7 +109,12 @@ pub async fn start_app_with_options(
             tower_cookies::cookie::SameSite::Strict,
         ),
         obfuscate_errors: options.obfuscate_errors,
-        secrets: crate::server::Secrets::empty(),
+        // The providers are never called in tests, but the default ones need an API key.
+        secrets: crate::server::Secrets {
+            anthropic: Some("test-key".to_string()),
+            deepgram: Some("test-key".to_string()),
+            ..crate::server::Secrets::empty()
+        },
         session_expiry: ExpiryStyle::AfterIdle(std::time::Duration::from_secs(24 * 60 * 60)),
         oauth_redirect_url_base: base_url.clone(),
         oauth_providers: Some(vec![]),
@@ -126,6 +132,14 @@ pub async fn start_app_with_options(
         queue_path,
         init_recurring_jobs: false,
         storage: crate::storage::AppStorageConfig::new_in_memory(),
+        transcription: crate::transcription::TranscriptionConfig::default(),
+        llm: crate::llm::LlmConfig::default(),
+        download: crate::jobs::download::DownloadConfig::default(),
+        pricing: crate::models::usage::PricingConfig::default(),
+        subtitles: crate::jobs::download::SubtitlesConfig::default(),
+        image_extraction: crate::jobs::extract::ImageExtractionConfig::default(),
+        analyze: crate::jobs::analyze::AnalyzeConfig::default(),
+        ocr: crate::jobs::ocr::OcrConfig::default(),
     };
 
     let server = crate::server::create_server(config)
@@ -0,0 +1,128 @@
+use async_trait::async_trait;
+use error_stack::{Report, ResultExt};
+
+use super::{
+    check_response_status, TranscriptionError, TranscriptionProvider, TranscriptionRequest,
+    TranscriptionUsage,
+};
+
+/// Transcription using the Deepgram API
+pub struct Deepgram {
+    http_client: reqwest::Client,
+    api_key: String,
+    model: String,
+}
+
+impl Deepgram {
+    pub fn new(http_client: reqwest::Client, api_key: String, model: String) -> Self {
+        Self {
+            http_client,
+            api_key,
+            model,
+        }
+    }
+}
+
+#[async_trait]
+impl TranscriptionProvider for Deepgram {
+    fn name(&self) -> &'static str {
+        "deepgram"
+    }
+
+    /// Deepgram bills by the duration of the audio, which it returns in the response metadata.
+    fn usage(&self, response: &serde_json::Value) -> TranscriptionUsage {
+        let metadata = &response["metadata"];
+        let model = metadata["model_info"]
+            .as_object()
+            .and_then(|models| models.values().find_map(|m| m["name"].as_str()))
+            .unwrap_or(&self.model);
+
+        TranscriptionUsage {
+            billed: true,
+            audio_seconds: metadata["duration"].as_f64(),
+            model: Some(model.to_string()),
+            request_id: metadata["request_id"].as_str().map(|id| id.to_string()),
+        }
+    }
+
+    async fn transcribe(
+        &self,
+        request: TranscriptionRequest<'_>,
+    ) -> Result<serde_json::Value, Report<TranscriptionError>> {
+        // get audio stream from storage
+        let audio = request
+            .storage
+            .get(request.audio_path)
+            .await
+            .change_context(TranscriptionError::ReadAudio)?;
+        let body = reqwest::Body::wrap_stream(audio.into_stream());
+
+        let id = request.id.to_string();
+        let response = self
+            .http_client
+            .post("https://api.deepgram.com/v1/listen")
+            .header("Authorization", format!("Token {}", self.api_key))
+            .header("Content-Type", "audio/mpeg")
+            .query(&[
+                ("model", self.model.as_str()),
+                ("paragraphs", "true"),
+                ("punctuate", "true"),
+                ("utterances", "true"),
+                ("diarize", "true"),
+                ("smart_format", "true"),
+                ("tag", &id),
+            ])
+            // It comes back pretty quick even with long videos so just wait inline
+            .timeout(std::time::Duration::from_secs(300))
+            .body(body)
+            .send()
+            .await
+            .change_context(TranscriptionError::Request)?;
+
+        let mut result = check_response_status(response)
+            .await?
+            .json::<serde_json::Value>()
+            .await
+            .change_context(TranscriptionError::Response)?;
+
+        // Tag it with the format so that we can more easily use the transcript elsewhere
+        result["_provider_format"] = "deepgram_v1".into();
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn usage_from_response() {
+        let provider = Deepgram::new(
+            reqwest::Client::new(),
+            "key".to_string(),
+            "nova-2".to_string(),
+        );
+        let response = json!({
+            "metadata": {
+                "request_id": "a1b2",
+                "duration": 1834.5,
+                "channels": 1,
+                "models": ["c3d4"],
+                "model_info": { "c3d4": { "name": "2-general-nova", "version": "2024-01-09" } }
+            },
+            "results": {}
+        });
+
+        assert_eq!(
+            provider.usage(&response),
+            TranscriptionUsage {
+                billed: true,
+                audio_seconds: Some(1834.5),
+                model: Some("2-general-nova".to_string()),
+                request_id: Some("a1b2".to_string()),
+            }
+        );
+    }
+}
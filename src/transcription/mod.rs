@@ -0,0 +1,204 @@
+//! Speech recognition providers
+
+mod deepgram;
+mod openai;
+mod whisper_local;
+
+use async_trait::async_trait;
+use error_stack::{Report, ResultExt};
+use filigree::storage::Storage;
+use reqwest::StatusCode;
+
+pub use self::{deepgram::Deepgram, openai::OpenAiCompatible, whisper_local::WhisperLocal};
+use crate::{models::video::VideoId, server::Secrets, Error};
+
+#[derive(thiserror::Error, Debug)]
+pub enum TranscriptionError {
+    #[error("Failed to read audio")]
+    ReadAudio,
+    #[error("Audio is {size} bytes, over the service's limit of {limit} bytes")]
+    AudioTooLarge { size: u64, limit: u64 },
+    #[error("Failed to send transcription request")]
+    Request,
+    #[error("Transcription service returned status {0}")]
+    Status(StatusCode),
+    #[error("Failed to read transcription response")]
+    Response,
+    #[error("Failed to create temporary directory")]
+    TempDir,
+    #[error("Failed to run ffmpeg")]
+    Ffmpeg,
+    #[error("Failed to run whisper")]
+    Whisper,
+}
+
+impl TranscriptionError {
+    /// Whether the request might succeed if tried again.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            TranscriptionError::Status(status) => {
+                status.is_server_error()
+                    || *status == StatusCode::TOO_MANY_REQUESTS
+                    || *status == StatusCode::REQUEST_TIMEOUT
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The audio to transcribe
+pub struct TranscriptionRequest<'a> {
+    pub id: VideoId,
+    /// The storage bucket holding the audio
+    pub storage: &'a Storage,
+    /// The path to the audio file within the bucket
+    pub audio_path: &'a str,
+}
+
+/// What a transcription request used, as reported in the provider's response
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TranscriptionUsage {
+    /// Whether the provider charges for transcription. Local models are free to run.
+    pub billed: bool,
+    /// The length of the transcribed audio in seconds
+    pub audio_seconds: Option<f64>,
+    pub model: Option<String>,
+    /// The provider's ID for the request, for matching against its billing records
+    pub request_id: Option<String>,
+}
+
+#[async_trait]
+pub trait TranscriptionProvider: Send + Sync {
+    /// A short name for the provider, for logging and stats
+    fn name(&self) -> &'static str;
+
+    /// Read the usage from a response returned by [TranscriptionProvider::transcribe].
+    fn usage(&self, _response: &serde_json::Value) -> TranscriptionUsage {
+        TranscriptionUsage::default()
+    }
+
+    /// Transcribe the audio. The result is the provider's raw response, tagged with a
+    /// `_provider_format` field which describes its structure.
+    async fn transcribe(
+        &self,
+        request: TranscriptionRequest<'_>,
+    ) -> Result<serde_json::Value, Report<TranscriptionError>>;
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TranscriptionProviderKind {
+    /// The Deepgram API
+    Deepgram,
+    /// A server implementing the OpenAI `/audio/transcriptions` API
+    #[value(name = "openai")]
+    OpenAi,
+    /// A whisper command run on this machine
+    WhisperLocal,
+}
+
+/// The command-line interface used by the local whisper command
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WhisperFlavor {
+    /// whisper.cpp's `whisper-cli` or `main`
+    WhisperCpp,
+    /// The OpenAI `whisper` Python CLI, or a compatible one such as `whisper-ctranslate2` from
+    /// faster-whisper
+    OpenaiWhisper,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct TranscriptionConfig {
+    /// The speech recognition service to use
+    #[clap(long = "transcription-provider", env = "TRANSCRIPTION_PROVIDER", value_enum, default_value_t = TranscriptionProviderKind::Deepgram)]
+    pub provider: TranscriptionProviderKind,
+
+    /// The model to use. Defaults to "nova-2" for Deepgram and "whisper-1" for OpenAI-compatible
+    /// servers. For local whisper this is passed as the model argument, and is required for
+    /// whisper.cpp.
+    #[clap(long = "transcription-model", env = "TRANSCRIPTION_MODEL")]
+    pub model: Option<String>,
+
+    /// The base URL of the OpenAI-compatible transcription API
+    #[clap(long = "transcription-api-url", env = "TRANSCRIPTION_API_URL", default_value_t = String::from("https://api.openai.com/v1"))]
+    pub api_url: String,
+
+    /// The whisper executable to run for local transcription
+    #[clap(long, env = "WHISPER_COMMAND", default_value_t = String::from("whisper-cli"))]
+    pub whisper_command: String,
+
+    /// Which command-line interface the whisper executable uses
+    #[clap(long, env = "WHISPER_FLAVOR", value_enum, default_value_t = WhisperFlavor::WhisperCpp)]
+    pub whisper_flavor: WhisperFlavor,
+}
+
+impl Default for TranscriptionConfig {
+    fn default() -> Self {
+        Self {
+            provider: TranscriptionProviderKind::Deepgram,
+            model: None,
+            api_url: "https://api.openai.com/v1".to_string(),
+            whisper_command: "whisper-cli".to_string(),
+            whisper_flavor: WhisperFlavor::WhisperCpp,
+        }
+    }
+}
+
+/// Create the transcription provider described by the configuration.
+pub fn create_provider(
+    config: &TranscriptionConfig,
+    secrets: &Secrets,
+    http_client: reqwest::Client,
+) -> Result<Box<dyn TranscriptionProvider>, Report<Error>> {
+    let provider: Box<dyn TranscriptionProvider> = match config.provider {
+        TranscriptionProviderKind::Deepgram => {
+            let api_key = secrets
+                .deepgram
+                .clone()
+                .ok_or(Error::Config)
+                .attach_printable("DEEPGRAM_API_KEY is required for the deepgram provider")?;
+            Box::new(Deepgram::new(
+                http_client,
+                api_key,
+                config.model.clone().unwrap_or_else(|| "nova-2".to_string()),
+            ))
+        }
+        TranscriptionProviderKind::OpenAi => Box::new(OpenAiCompatible::new(
+            http_client,
+            config.api_url.clone(),
+            secrets.transcription_api_key.clone(),
+            config
+                .model
+                .clone()
+                .unwrap_or_else(|| "whisper-1".to_string()),
+        )),
+        TranscriptionProviderKind::WhisperLocal => {
+            if config.whisper_flavor == WhisperFlavor::WhisperCpp && config.model.is_none() {
+                return Err(Report::new(Error::Config)).attach_printable(
+                    "TRANSCRIPTION_MODEL must be set to the path of a ggml model for whisper.cpp",
+                );
+            }
+
+            Box::new(WhisperLocal::new(
+                config.whisper_command.clone(),
+                config.whisper_flavor,
+                config.model.clone(),
+            ))
+        }
+    };
+
+    Ok(provider)
+}
+
+/// Return an error if the response did not have a successful status code, with the response body
+/// attached for context.
+async fn check_response_status(
+    response: reqwest::Response,
+) -> Result<reqwest::Response, Report<TranscriptionError>> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let body = response.text().await.unwrap_or_default();
+    Err(Report::new(TranscriptionError::Status(status))).attach_printable(body)
+}
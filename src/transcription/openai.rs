@@ -0,0 +1,167 @@
+use async_trait::async_trait;
+use error_stack::{Report, ResultExt};
+
+use super::{
+    check_response_status, TranscriptionError, TranscriptionProvider, TranscriptionRequest,
+    TranscriptionUsage,
+};
+
+/// Transcription using a server that implements the OpenAI `/audio/transcriptions` endpoint,
+/// such as OpenAI itself or a self-hosted faster-whisper server.
+///
+/// Note that OpenAI limits uploads to 25MB, which is a bit under half an hour of audio at the
+/// bitrate used by the extract job. Longer audio fails without being sent. Self-hosted servers
+/// generally don't have this limit.
+pub struct OpenAiCompatible {
+    http_client: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+    model: String,
+}
+
+/// The largest file that the OpenAI API accepts
+const OPENAI_MAX_UPLOAD: u64 = 25 * 1024 * 1024;
+
+impl OpenAiCompatible {
+    pub fn new(
+        http_client: reqwest::Client,
+        base_url: String,
+        api_key: Option<String>,
+        model: String,
+    ) -> Self {
+        Self {
+            http_client,
+            base_url,
+            api_key,
+            model,
+        }
+    }
+
+    /// The largest audio file that the server accepts, if it has a limit
+    fn max_upload(&self) -> Option<u64> {
+        let host = url::Url::parse(&self.base_url)
+            .ok()?
+            .host_str()?
+            .to_string();
+        (host == "api.openai.com").then_some(OPENAI_MAX_UPLOAD)
+    }
+}
+
+#[async_trait]
+impl TranscriptionProvider for OpenAiCompatible {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    /// OpenAI bills by the duration of the audio. Self-hosted servers don't need an API key, so
+    /// requests without one are assumed to be free.
+    fn usage(&self, response: &serde_json::Value) -> TranscriptionUsage {
+        TranscriptionUsage {
+            billed: self.api_key.is_some(),
+            audio_seconds: response["duration"].as_f64(),
+            model: Some(self.model.clone()),
+            request_id: None,
+        }
+    }
+
+    async fn transcribe(
+        &self,
+        request: TranscriptionRequest<'_>,
+    ) -> Result<serde_json::Value, Report<TranscriptionError>> {
+        let audio = request
+            .storage
+            .get(request.audio_path)
+            .await
+            .change_context(TranscriptionError::ReadAudio)?;
+
+        let size = audio.meta.size as u64;
+        if let Some(limit) = self.max_upload().filter(|limit| size > *limit) {
+            return Err(Report::new(TranscriptionError::AudioTooLarge {
+                size,
+                limit,
+            }));
+        }
+
+        let body = reqwest::Body::wrap_stream(audio.into_stream());
+        let file = reqwest::multipart::Part::stream_with_length(body, size)
+            .file_name("audio.mp4")
+            .mime_str("audio/mp4")
+            .change_context(TranscriptionError::Request)?;
+        let form = reqwest::multipart::Form::new()
+            .part("file", file)
+            .text("model", self.model.clone())
+            .text("response_format", "verbose_json")
+            .text("timestamp_granularities[]", "segment")
+            .text("timestamp_granularities[]", "word");
+
+        let url = format!(
+            "{}/audio/transcriptions",
+            self.base_url.trim_end_matches('/')
+        );
+        let mut builder = self
+            .http_client
+            .post(url)
+            .multipart(form)
+            .timeout(std::time::Duration::from_secs(600));
+        if let Some(api_key) = &self.api_key {
+            builder = builder.bearer_auth(api_key);
+        }
+
+        let response = builder
+            .send()
+            .await
+            .change_context(TranscriptionError::Request)?;
+
+        let mut result = check_response_status(response)
+            .await?
+            .json::<serde_json::Value>()
+            .await
+            .change_context(TranscriptionError::Response)?;
+
+        result["_provider_format"] = "openai_verbose_v1".into();
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use filigree::storage::Storage;
+
+    use super::*;
+    use crate::models::video::VideoId;
+
+    fn provider(base_url: &str) -> OpenAiCompatible {
+        OpenAiCompatible::new(
+            reqwest::Client::new(),
+            base_url.to_string(),
+            None,
+            "whisper-1".to_string(),
+        )
+    }
+
+    #[tokio::test]
+    async fn reject_audio_over_openai_limit() {
+        let storage = Storage::new_memory();
+        let audio = vec![0; OPENAI_MAX_UPLOAD as usize + 1];
+        storage
+            .put("vid/audio.mp4", bytes::Bytes::from(audio))
+            .await
+            .unwrap();
+
+        let err = provider("https://api.openai.com/v1")
+            .transcribe(TranscriptionRequest {
+                id: VideoId::new(),
+                storage: &storage,
+                audio_path: "vid/audio.mp4",
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.current_context(),
+            TranscriptionError::AudioTooLarge { .. }
+        ));
+        assert!(!err.current_context().is_retryable());
+
+        assert_eq!(provider("http://localhost:8000/v1").max_upload(), None);
+    }
+}
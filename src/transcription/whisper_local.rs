@@ -0,0 +1,119 @@
+use async_trait::async_trait;
+use error_stack::{Report, ResultExt};
+use temp_dir::TempDir;
+
+use super::{TranscriptionError, TranscriptionProvider, TranscriptionRequest, WhisperFlavor};
+use crate::jobs::check_command_result;
+
+/// Transcription by running whisper on this machine, so that the audio never leaves it.
+pub struct WhisperLocal {
+    command: String,
+    flavor: WhisperFlavor,
+    model: Option<String>,
+}
+
+impl WhisperLocal {
+    pub fn new(command: String, flavor: WhisperFlavor, model: Option<String>) -> Self {
+        Self {
+            command,
+            flavor,
+            model,
+        }
+    }
+}
+
+#[async_trait]
+impl TranscriptionProvider for WhisperLocal {
+    fn name(&self) -> &'static str {
+        "whisper_local"
+    }
+
+    async fn transcribe(
+        &self,
+        request: TranscriptionRequest<'_>,
+    ) -> Result<serde_json::Value, Report<TranscriptionError>> {
+        let temp_dir = TempDir::new().change_context(TranscriptionError::TempDir)?;
+        let dir = temp_dir.path();
+
+        let audio_path = dir.join("audio.mp4");
+        request
+            .storage
+            .stream_to_disk(request.audio_path, &audio_path)
+            .await
+            .change_context(TranscriptionError::ReadAudio)?;
+
+        // whisper.cpp only reads 16kHz WAV, and the Python implementations handle it fine too.
+        let wav_path = dir.join("audio.wav");
+        let result = tokio::process::Command::new("ffmpeg")
+            .args([
+                "-y",
+                "-i",
+                audio_path.to_string_lossy().as_ref(),
+                "-ar",
+                "16000",
+                "-ac",
+                "1",
+                "-c:a",
+                "pcm_s16le",
+                wav_path.to_string_lossy().as_ref(),
+            ])
+            .kill_on_drop(true)
+            .output()
+            .await
+            .change_context(TranscriptionError::Ffmpeg)?;
+        check_command_result(result, TranscriptionError::Ffmpeg)?;
+
+        let mut command = tokio::process::Command::new(&self.command);
+        let (output_path, format) = match self.flavor {
+            WhisperFlavor::WhisperCpp => {
+                let output_base = dir.join("transcript");
+                command.args([
+                    "--output-json",
+                    "--output-file",
+                    output_base.to_string_lossy().as_ref(),
+                    "--file",
+                    wav_path.to_string_lossy().as_ref(),
+                ]);
+                if let Some(model) = &self.model {
+                    command.args(["--model", model]);
+                }
+                (dir.join("transcript.json"), "whisper_cpp_v1")
+            }
+            WhisperFlavor::OpenaiWhisper => {
+                command.args([
+                    wav_path.to_string_lossy().as_ref(),
+                    "--output_format",
+                    "json",
+                    "--word_timestamps",
+                    "True",
+                    "--output_dir",
+                    dir.to_string_lossy().as_ref(),
+                ]);
+                if let Some(model) = &self.model {
+                    command.args(["--model", model]);
+                }
+                // The Python CLI's output matches the verbose JSON format from the OpenAI API.
+                (dir.join("audio.json"), "openai_verbose_v1")
+            }
+        };
+
+        // Whisper can run for a long time, so make sure it stops if the job is cancelled.
+        let result = command
+            .kill_on_drop(true)
+            .output()
+            .await
+            .change_context(TranscriptionError::Whisper)
+            .attach_printable_lazy(|| self.command.clone())?;
+        check_command_result(result, TranscriptionError::Whisper)?;
+
+        let output = tokio::fs::read(&output_path)
+            .await
+            .change_context(TranscriptionError::Response)
+            .attach_printable_lazy(|| output_path.display().to_string())?;
+        let mut result: serde_json::Value =
+            serde_json::from_slice(&output).change_context(TranscriptionError::Response)?;
+
+        result["_provider_format"] = format.into();
+        Ok(result)
+    }
+}